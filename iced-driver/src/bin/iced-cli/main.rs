@@ -0,0 +1,472 @@
+//! Command line client for the board, meant for shell scripts and test rigs.
+//!
+//! Exit codes: 0 success, 1 the device refused the command or answered
+//! something unexpected, or a sequence or script failed, 2 bad usage, 3 the
+//! port couldn't be used, 4 the device didn't answer, 5 the device doesn't
+//! speak our protocol or lacks the command.
+
+mod output;
+mod repl;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use futures::{future, StreamExt};
+use iced_driver::bridge::Bridge;
+use iced_driver::capture::{self, Annotator, CaptureLog, Replay};
+use iced_driver::runner::Sequence;
+use iced_driver::script::{Script, DEFAULT_MAX_OPERATIONS};
+use iced_driver::{
+    open_port, DeviceCommands, DeviceDriver, DeviceResponses, Discovery, DriverError, Port,
+    SerialPortParams,
+};
+use output::Output;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::time;
+use tokio_serial::{DataBits, Parity, SerialPort, SerialStream, StopBits};
+
+pub const EXIT_REJECTED: u8 = 1;
+pub const EXIT_USAGE: u8 = 2;
+pub const EXIT_PORT: u8 = 3;
+pub const EXIT_TIMEOUT: u8 = 4;
+pub const EXIT_INCOMPATIBLE: u8 = 5;
+
+#[derive(Parser)]
+#[command(
+    name = "iced-cli",
+    version,
+    about = "Drive the iced-mcu board from the command line"
+)]
+struct Cli {
+    #[command(flatten)]
+    port: PortArgs,
+    /// Print results as JSON, one object per line.
+    #[arg(long, global = true)]
+    json: bool,
+    #[command(subcommand)]
+    command: Cmd,
+}
+
+#[derive(Args)]
+struct PortArgs {
+    /// Serial port of the board. Found by discovery when a single ST-Link board is plugged in.
+    #[arg(short, long, global = true)]
+    port: Option<String>,
+    #[arg(short, long, default_value_t = 115200, global = true)]
+    baud: u32,
+    #[arg(long, value_enum, default_value_t = ParityArg::None, global = true)]
+    parity: ParityArg,
+    #[arg(long, value_parser = clap::value_parser!(u8).range(5..=8), default_value_t = 8, global = true)]
+    data_bits: u8,
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=2), default_value_t = 1, global = true)]
+    stop_bits: u8,
+    /// Reply timeout in milliseconds.
+    #[arg(long, default_value_t = 1000, global = true)]
+    timeout: u64,
+    /// Talk Modbus RTU to this slave address, for firmware built as a Modbus slave.
+    #[arg(long, value_name = "UNIT", value_parser = clap::value_parser!(u8).range(1..=247), global = true)]
+    modbus: Option<u8>,
+    /// Talk SCPI instead of the single-letter commands.
+    #[arg(long, conflicts_with = "modbus", global = true)]
+    scpi: bool,
+    /// Record every byte sent and received to this file, see the dump command.
+    #[arg(long, value_name = "FILE", global = true)]
+    capture: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ParityArg {
+    None,
+    Odd,
+    Even,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum OnOff {
+    On,
+    Off,
+}
+
+#[derive(Subcommand)]
+enum Cmd {
+    /// List boards, ST-Link virtual COM ports unless --all is given.
+    List {
+        /// List every serial port.
+        #[arg(long)]
+        all: bool,
+        /// Only list ports where a board answers the handshake.
+        #[arg(long)]
+        probe: bool,
+    },
+    /// Switch the user LED.
+    Led { state: OnOff },
+    /// Control the PWM output.
+    Pwm {
+        #[command(subcommand)]
+        action: PwmCmd,
+    },
+    /// Print the device uptime in milliseconds.
+    Time,
+    /// Print the full device state.
+    State,
+    /// Write a frame such as "0:D50" to the port as given, with a newline if it
+    /// has none, and print the line that comes back. No handshake is done.
+    Raw { frame: String },
+    /// Open an interactive console on the board.
+    Repl,
+    /// Run a TOML sequence file and report which steps passed.
+    Run { file: PathBuf },
+    /// Share the board over TCP until Ctrl-C, clients open it as socket://host:port.
+    Bridge {
+        /// Address to serve the raw protocol on.
+        #[arg(long, default_value = "0.0.0.0:5000")]
+        listen: String,
+        /// Also serve RFC 2217 telnet on this address.
+        #[arg(long)]
+        rfc2217: Option<String>,
+    },
+    /// Run a Rhai script, Ctrl-C stops it.
+    Script {
+        file: PathBuf,
+        /// Stop the script after this many operations, 0 for no limit.
+        #[arg(long, default_value_t = DEFAULT_MAX_OPERATIONS)]
+        max_operations: u64,
+    },
+    /// Print a capture file made with --capture, decoded into frames.
+    Dump {
+        file: PathBuf,
+        /// Also print the bytes of each record.
+        #[arg(long)]
+        hex: bool,
+    },
+    /// Play the device's side of a capture file on a pseudo-terminal.
+    Replay { file: PathBuf },
+}
+
+#[derive(Subcommand)]
+enum PwmCmd {
+    On,
+    Off,
+    /// Set the duty cycle in percent.
+    Duty {
+        percent: u8,
+    },
+    /// Set the frequency in Hz.
+    Freq {
+        hz: u32,
+    },
+}
+
+impl PortArgs {
+    fn params(&self) -> SerialPortParams {
+        SerialPortParams {
+            baudrate: self.baud,
+            parity: match self.parity {
+                ParityArg::None => Parity::None,
+                ParityArg::Odd => Parity::Odd,
+                ParityArg::Even => Parity::Even,
+            },
+            data_bits: match self.data_bits {
+                5 => DataBits::Five,
+                6 => DataBits::Six,
+                7 => DataBits::Seven,
+                _ => DataBits::Eight,
+            },
+            stop_bits: match self.stop_bits {
+                2 => StopBits::Two,
+                _ => StopBits::One,
+            },
+            timeout: Duration::from_millis(self.timeout),
+        }
+    }
+
+    /// The port given on the command line, or the only board discovery finds.
+    async fn resolve(&self) -> Result<String, Failure> {
+        if let Some(port) = &self.port {
+            return Ok(port.clone());
+        }
+        let found = Discovery::new().run().await?;
+        match found.as_slice() {
+            [device] => Ok(device.port_name.clone()),
+            [] => Err(Failure::new(EXIT_PORT, "no board found, pass --port")),
+            _ => {
+                let names: Vec<_> = found.iter().map(|d| d.port_name.as_str()).collect();
+                Err(Failure::new(
+                    EXIT_USAGE,
+                    format!(
+                        "several boards found, pick one with --port: {}",
+                        names.join(", ")
+                    ),
+                ))
+            }
+        }
+    }
+}
+
+/// Why the command failed and the exit code that says so.
+pub struct Failure {
+    pub code: u8,
+    pub message: String,
+}
+
+impl Failure {
+    pub fn new(code: u8, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<DriverError> for Failure {
+    fn from(e: DriverError) -> Self {
+        let code = match e {
+            DriverError::Io(_) | DriverError::PortClosed => EXIT_PORT,
+            DriverError::Timeout => EXIT_TIMEOUT,
+            DriverError::Incompatible(_) | DriverError::Unsupported(_) => EXIT_INCOMPATIBLE,
+            DriverError::OutOfRange(_) => EXIT_USAGE,
+            _ => EXIT_REJECTED,
+        };
+        Failure::new(code, e.to_string())
+    }
+}
+
+fn device_command(cmd: &Cmd) -> Result<Option<DeviceCommands>, Failure> {
+    let command = match cmd {
+        Cmd::List { .. }
+        | Cmd::Raw { .. }
+        | Cmd::Repl
+        | Cmd::Run { .. }
+        | Cmd::Bridge { .. }
+        | Cmd::Script { .. }
+        | Cmd::Dump { .. }
+        | Cmd::Replay { .. } => return Ok(None),
+        Cmd::Led { state: OnOff::On } => DeviceCommands::SetGpioPin,
+        Cmd::Led { state: OnOff::Off } => DeviceCommands::ClearGpioPin,
+        Cmd::Pwm { action } => match action {
+            PwmCmd::On => DeviceCommands::PwmOn,
+            PwmCmd::Off => DeviceCommands::PwmOff,
+            PwmCmd::Duty { percent } => DeviceCommands::PwmDuty(*percent),
+            PwmCmd::Freq { hz } => DeviceCommands::PwmSetFreq(*hz),
+        },
+        Cmd::Time => DeviceCommands::GetTime,
+        Cmd::State => DeviceCommands::GetState,
+    };
+    Ok(Some(command))
+}
+
+/// Opens the port, capturing the traffic if asked to.
+async fn open_link(port: &PortArgs) -> Result<Port, Failure> {
+    let path = port.resolve().await?;
+    let mut link = open_port(&path, &port.params())
+        .await
+        .map_err(DriverError::from)?;
+    if let Some(file) = &port.capture {
+        let log = CaptureLog::create(file).map_err(|e| {
+            Failure::new(
+                EXIT_USAGE,
+                format!("can't create {}: {}", file.display(), e),
+            )
+        })?;
+        link = link.capture(log);
+    }
+    Ok(link)
+}
+
+/// Opens the board and checks it speaks our protocol.
+async fn connect(port: &PortArgs) -> Result<DeviceDriver, Failure> {
+    let params = port.params();
+    let mut driver = DeviceDriver::new(open_link(port).await?).with_timeout(params.timeout);
+    if let Some(unit) = port.modbus {
+        driver = driver.with_modbus(unit);
+    }
+    if port.scpi {
+        driver = driver.with_scpi();
+    }
+    driver.handshake().await?;
+    Ok(driver)
+}
+
+/// Writes `frame` unchanged apart from the newline and prints the reply line.
+async fn raw(port: &PortArgs, frame: &str, out: &Output) -> Result<(), Failure> {
+    let mut link = BufReader::new(open_link(port).await?);
+    let mut bytes = frame.as_bytes().to_vec();
+    if !bytes.ends_with(b"\n") {
+        bytes.push(b'\n');
+    }
+    link.write_all(&bytes).await.map_err(DriverError::from)?;
+    let mut reply = Vec::new();
+    match time::timeout(port.params().timeout, link.read_until(b'\n', &mut reply)).await {
+        Ok(Ok(0)) => Err(DriverError::PortClosed.into()),
+        Ok(Ok(_)) => {
+            out.raw(&reply);
+            Ok(())
+        }
+        Ok(Err(e)) => Err(DriverError::from(e).into()),
+        Err(_elapsed) => Err(DriverError::Timeout.into()),
+    }
+}
+
+fn load_capture(file: &Path) -> Result<Vec<capture::Record>, Failure> {
+    capture::read_capture(file)
+        .map_err(|e| Failure::new(EXIT_USAGE, format!("can't load {}: {}", file.display(), e)))
+}
+
+/// Answers like the captured device on a new pty until Ctrl-C.
+async fn replay(file: &Path) -> Result<(), Failure> {
+    let replay = Replay::new(load_capture(file)?);
+    let (mut master, slave) =
+        SerialStream::pair().map_err(|e| Failure::new(EXIT_PORT, e.to_string()))?;
+    eprintln!(
+        "Replaying {} on {}",
+        file.display(),
+        slave.name().unwrap_or_default()
+    );
+    let diverged = |e: capture::ReplayError| Failure::new(EXIT_REJECTED, e.to_string());
+    tokio::select! {
+        played = replay.run(&mut master) => played.map_err(diverged)?,
+        _ = tokio::signal::ctrl_c() => return Ok(()),
+    }
+    // The pty goes away with us, so stay until the host has read the last reply.
+    eprintln!("End of the capture");
+    let mut byte = [0u8; 1];
+    tokio::select! {
+        _ = master.read(&mut byte) => {
+            Err(Failure::new(EXIT_REJECTED, "the host sent more than the capture holds"))
+        }
+        _ = tokio::signal::ctrl_c() => Ok(()),
+    }
+}
+
+async fn listen(address: &str) -> Result<TcpListener, Failure> {
+    TcpListener::bind(address)
+        .await
+        .map_err(|e| Failure::new(EXIT_PORT, format!("can't listen on {}: {}", address, e)))
+}
+
+/// Serves the board until Ctrl-C or until it goes away.
+async fn bridge(port: &PortArgs, address: &str, rfc2217: Option<&str>) -> Result<(), Failure> {
+    let (device, _) = connect(port).await?.spawn();
+    let raw = listen(address).await?;
+    eprintln!("Serving the raw protocol on {}", address);
+    let telnet = match rfc2217 {
+        Some(address) => {
+            let listener = listen(address).await?;
+            eprintln!("Serving RFC 2217 on {}", address);
+            Some(listener)
+        }
+        None => None,
+    };
+    let bridge = Bridge::new(device.clone());
+    let telnet_bridge = bridge.clone().with_rfc2217(port.baud);
+    let serve_telnet = async {
+        match telnet {
+            Some(listener) => telnet_bridge.serve(listener).await,
+            None => future::pending().await,
+        }
+    };
+    // The event stream ends when the driver task stops.
+    let mut events = device.events();
+    let closed = async { while events.next().await.is_some() {} };
+    let served = tokio::select! {
+        served = bridge.serve(raw) => served,
+        served = serve_telnet => served,
+        _ = closed => return Err(Failure::new(EXIT_PORT, "the device went away")),
+        _ = tokio::signal::ctrl_c() => Ok(()),
+    };
+    served.map_err(|e| Failure::new(EXIT_PORT, e.to_string()))
+}
+
+async fn run(cli: &Cli, out: &Output) -> Result<(), Failure> {
+    let command = match (&cli.command, device_command(&cli.command)?) {
+        (_, Some(command)) => command,
+        (Cmd::List { all, probe }, None) => {
+            let mut discovery = if *all {
+                Discovery::all_ports()
+            } else {
+                Discovery::new()
+            };
+            if *probe {
+                discovery = discovery.with_probe(cli.port.params());
+            }
+            out.devices(&discovery.run().await?);
+            return Ok(());
+        }
+        (Cmd::Run { file }, None) => {
+            let sequence = Sequence::load(file).map_err(|e| {
+                Failure::new(EXIT_USAGE, format!("can't load {}: {}", file.display(), e))
+            })?;
+            let (device, _) = connect(&cli.port).await?.spawn();
+            let report = sequence.run(&device).await;
+            out.report(&report);
+            return match report.passed() {
+                true => Ok(()),
+                false => Err(Failure::new(EXIT_REJECTED, "sequence failed")),
+            };
+        }
+        (
+            Cmd::Script {
+                file,
+                max_operations,
+            },
+            None,
+        ) => {
+            let source = fs::read_to_string(file).map_err(|e| {
+                Failure::new(EXIT_USAGE, format!("can't read {}: {}", file.display(), e))
+            })?;
+            let script = Script::new(source).with_max_operations(*max_operations);
+            script
+                .check()
+                .map_err(|e| Failure::new(EXIT_USAGE, e.to_string()))?;
+            let cancel = script.cancel_token();
+            tokio::spawn(async move {
+                if tokio::signal::ctrl_c().await.is_ok() {
+                    cancel.cancel();
+                }
+            });
+            let (device, _) = connect(&cli.port).await?.spawn();
+            let out = *out;
+            return script
+                .run(device, move |line| out.log(line))
+                .await
+                .map_err(|e| Failure::new(EXIT_REJECTED, e.to_string()));
+        }
+        (Cmd::Dump { file, hex }, None) => {
+            let mut annotator = Annotator::new();
+            for record in load_capture(file)? {
+                let frames = annotator.feed(&record);
+                out.record(&record, &frames, *hex);
+            }
+            return Ok(());
+        }
+        (Cmd::Replay { file }, None) => return replay(file).await,
+        (Cmd::Raw { frame }, None) => return raw(&cli.port, frame, out).await,
+        (Cmd::Bridge { listen, rfc2217 }, None) => {
+            return bridge(&cli.port, listen, rfc2217.as_deref()).await;
+        }
+        (_, None) => {
+            let (device, _) = connect(&cli.port).await?.spawn();
+            return repl::run(device, out).await;
+        }
+    };
+    let mut driver = connect(&cli.port).await?;
+    let response: DeviceResponses = driver.handle_command(command).await?;
+    out.response(&response);
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let out = Output::new(cli.json);
+    match run(&cli, &out).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(failure) => {
+            out.failure(&failure);
+            ExitCode::from(failure.code)
+        }
+    }
+}
@@ -0,0 +1,281 @@
+//! Printing results as text for people or as JSON for scripts.
+
+use crate::Failure;
+use iced_driver::capture::{self, Direction, Record};
+use iced_driver::runner::{Outcome, Report};
+use iced_driver::{DeviceEvent, DeviceInfo, DeviceResponses, DeviceState, Fault, FoundDevice};
+use serde_json::{json, Value};
+use std::time::Duration;
+
+#[derive(Clone, Copy)]
+pub struct Output {
+    json: bool,
+}
+
+pub fn state_json(s: &DeviceState) -> Value {
+    json!({
+        "led_on": s.led_on,
+        "pwm_enabled": s.pwm_enabled,
+        "pwm_duty": s.pwm_duty,
+        "pwm_frequency": s.pwm_frequency,
+        "uptime_ms": s.uptime_ms,
+    })
+}
+
+pub fn info_json(i: &DeviceInfo) -> Value {
+    json!({
+        "protocol_version": i.protocol_version,
+        "firmware_version": i.firmware_version.as_str(),
+        "board": i.board.as_str(),
+        "capabilities": i.capabilities.to_string(),
+    })
+}
+
+pub fn response_json(response: &DeviceResponses) -> Value {
+    match response {
+        DeviceResponses::Success => json!({ "ok": true }),
+        DeviceResponses::Time(ms) => json!({ "ok": true, "time_ms": ms }),
+        DeviceResponses::State(s) => json!({ "ok": true, "state": state_json(s) }),
+        DeviceResponses::Identity(i) => json!({ "ok": true, "identity": info_json(i) }),
+    }
+}
+
+fn state_text(s: &DeviceState) -> String {
+    let on_off = |b: bool| if b { "on" } else { "off" };
+    format!(
+        "led {}, pwm {} at {} % and {} Hz, up {:.3} s",
+        on_off(s.led_on),
+        on_off(s.pwm_enabled),
+        s.pwm_duty,
+        s.pwm_frequency,
+        s.uptime().as_secs_f64()
+    )
+}
+
+pub fn response_text(response: &DeviceResponses) -> String {
+    match response {
+        DeviceResponses::Success => "ok".into(),
+        DeviceResponses::Time(ms) => format!("{} ms", ms),
+        DeviceResponses::State(s) => state_text(s),
+        DeviceResponses::Identity(i) => format!(
+            "{} firmware {}, protocol {}, commands {}",
+            i.board, i.firmware_version, i.protocol_version, i.capabilities
+        ),
+    }
+}
+
+fn fault_text(fault: Fault) -> String {
+    match fault {
+        Fault::Overrun => "receive overrun, a command was dropped".into(),
+        Fault::Other(code) => format!("unknown fault {}", code),
+    }
+}
+
+pub fn event_json(event: &DeviceEvent) -> Value {
+    match event {
+        DeviceEvent::Button => json!({ "event": "button" }),
+        DeviceEvent::Telemetry(s) => json!({ "event": "telemetry", "state": state_json(s) }),
+        DeviceEvent::Fault(f) => {
+            json!({ "event": "fault", "code": f.to_u8(), "message": fault_text(*f) })
+        }
+    }
+}
+
+pub fn event_text(event: &DeviceEvent) -> String {
+    match event {
+        DeviceEvent::Button => "! button pressed".into(),
+        DeviceEvent::Telemetry(s) => format!("! {}", state_text(s)),
+        DeviceEvent::Fault(f) => format!("! fault: {}", fault_text(*f)),
+    }
+}
+
+pub fn report_json(report: &Report) -> Value {
+    let steps: Vec<Value> = report
+        .steps
+        .iter()
+        .map(|step| {
+            let mut value = json!({
+                "step": step.description,
+                "at_ms": step.at.as_millis() as u64,
+            });
+            match &step.outcome {
+                Outcome::Passed => value["result"] = json!("pass"),
+                Outcome::Read(response) => {
+                    value["result"] = json!("read");
+                    value["value"] = response_json(response);
+                }
+                Outcome::Failed(message) => {
+                    value["result"] = json!("fail");
+                    value["message"] = json!(message);
+                }
+            }
+            value
+        })
+        .collect();
+    json!({
+        "name": report.name,
+        "passed": report.passed(),
+        "failures": report.failures(),
+        "aborted": report.aborted,
+        "elapsed_ms": report.elapsed.as_millis() as u64,
+        "steps": steps,
+    })
+}
+
+pub fn report_text(report: &Report) -> String {
+    let mut lines: Vec<String> = report
+        .steps
+        .iter()
+        .map(|step| {
+            let at = step.at.as_secs_f64();
+            match &step.outcome {
+                Outcome::Passed => format!("{:8.3} s  pass  {}", at, step.description),
+                Outcome::Read(response) => format!(
+                    "{:8.3} s  read  {}: {}",
+                    at,
+                    step.description,
+                    response_text(response)
+                ),
+                Outcome::Failed(message) => {
+                    format!("{:8.3} s  FAIL  {}: {}", at, step.description, message)
+                }
+            }
+        })
+        .collect();
+    lines.push(format!(
+        "{}: {}, {} steps, {} failed, {:.3} s",
+        if report.name.is_empty() {
+            "sequence"
+        } else {
+            &report.name
+        },
+        if report.passed() { "PASSED" } else { "FAILED" },
+        report.steps.len(),
+        report.failures(),
+        report.elapsed.as_secs_f64()
+    ));
+    lines.join("\n")
+}
+
+impl Output {
+    pub fn new(json: bool) -> Self {
+        Self { json }
+    }
+
+    pub fn response(&self, response: &DeviceResponses) {
+        if self.json {
+            println!("{}", response_json(response));
+        } else {
+            println!("{}", response_text(response));
+        }
+    }
+
+    /// A reply line as the device sent it.
+    pub fn raw(&self, reply: &[u8]) {
+        let reply = String::from_utf8_lossy(reply);
+        let reply = reply.trim_end();
+        if self.json {
+            println!("{}", json!({ "ok": true, "reply": reply }));
+        } else {
+            println!("{}", reply);
+        }
+    }
+
+    /// A reply that took `latency` to come back, as one line.
+    pub fn timed_response(&self, response: &DeviceResponses, latency: Duration) -> String {
+        let ms = latency.as_secs_f64() * 1000.0;
+        if self.json {
+            let mut value = response_json(response);
+            value["latency_ms"] = json!(ms);
+            value.to_string()
+        } else {
+            format!("{} ({:.1} ms)", response_text(response), ms)
+        }
+    }
+
+    /// An unsolicited event, as one line.
+    pub fn event(&self, event: &DeviceEvent) -> String {
+        if self.json {
+            event_json(event).to_string()
+        } else {
+            event_text(event)
+        }
+    }
+
+    pub fn report(&self, report: &Report) {
+        if self.json {
+            println!("{}", report_json(report));
+        } else {
+            println!("{}", report_text(report));
+        }
+    }
+
+    /// A captured record and the frames it completed, with its bytes if `hex`.
+    pub fn record(&self, record: &Record, frames: &[String], hex: bool) {
+        if self.json {
+            let mut value = json!(record);
+            value["frames"] = json!(frames);
+            println!("{}", value);
+            return;
+        }
+        let arrow = match record.direction {
+            Direction::Sent => "->",
+            Direction::Received => "<-",
+        };
+        let time = record.time_us as f64 / 1_000_000.0;
+        if hex {
+            println!("{:>12.6} {} {} bytes", time, arrow, record.data.len());
+            for line in capture::hex_dump(&record.data) {
+                println!("    {}", line);
+            }
+            for frame in frames {
+                println!("    = {}", frame);
+            }
+        } else {
+            for frame in frames {
+                println!("{:>12.6} {} {}", time, arrow, frame);
+            }
+        }
+    }
+
+    /// A line a script logged.
+    pub fn log(&self, line: &str) {
+        if self.json {
+            println!("{}", json!({ "log": line }));
+        } else {
+            println!("{}", line);
+        }
+    }
+
+    pub fn devices(&self, devices: &[FoundDevice]) {
+        for d in devices {
+            if self.json {
+                let info = d.info.as_ref().map(info_json);
+                println!(
+                    "{}",
+                    json!({ "port": d.port_name, "serial_number": d.serial_number, "info": info })
+                );
+            } else {
+                let mut line = d.port_name.clone();
+                if let Some(serial) = &d.serial_number {
+                    line.push_str(&format!("  {}", serial));
+                }
+                if let Some(i) = &d.info {
+                    line.push_str(&format!("  {} firmware {}", i.board, i.firmware_version));
+                }
+                println!("{}", line);
+            }
+        }
+    }
+
+    pub fn failure(&self, failure: &Failure) {
+        if self.json {
+            println!(
+                "{}",
+                json!({ "ok": false, "error": failure.message, "exit_code": failure.code })
+            );
+        } else {
+            eprintln!("error: {}", failure.message);
+        }
+    }
+}
@@ -0,0 +1,196 @@
+//! Interactive console on a connected board.
+//!
+//! Lines use the same subcommands as the command line, `led on` or
+//! `pwm duty 40`, and replies are shown with their round-trip time. Events
+//! the board sends on its own are printed above the prompt as they arrive.
+
+use crate::output::Output;
+use crate::{device_command, Cmd, Failure, EXIT_USAGE};
+use clap::{CommandFactory, Parser};
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use iced_driver::{DeviceEvent, DeviceHandle};
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::history::DefaultHistory;
+use rustyline::{Context, Editor, ExternalPrinter, Helper, Highlighter, Hinter, Validator};
+use std::path::PathBuf;
+use std::time::Instant;
+use tokio::runtime::Handle;
+
+const PROMPT: &str = "iced> ";
+
+/// Kept in the home directory, shared by every console session.
+const HISTORY_FILE: &str = ".iced_cli_history";
+
+/// Top level commands that make no sense inside the console.
+const HIDDEN: [&str; 5] = ["list", "repl", "run", "script", "bridge"];
+
+/// Leaves the console.
+const QUIT: [&str; 2] = ["quit", "exit"];
+
+/// One console line.
+#[derive(Parser)]
+#[command(
+    name = "",
+    no_binary_name = true,
+    about = "Console commands, quit or exit leaves"
+)]
+struct Line {
+    #[command(subcommand)]
+    command: Cmd,
+}
+
+/// Completes subcommand names and argument values from the clap definitions.
+#[derive(Helper, Highlighter, Hinter, Validator)]
+struct Words {
+    commands: clap::Command,
+}
+
+impl Completer for Words {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let line = &line[..pos];
+        let start = line.rfind(' ').map_or(0, |i| i + 1);
+        let mut command = &self.commands;
+        for word in line[..start].split_whitespace() {
+            match command.find_subcommand(word) {
+                Some(sub) => command = sub,
+                None if command.has_subcommands() => return Ok((start, Vec::new())),
+                None => break,
+            }
+        }
+        let mut words: Vec<String> = command
+            .get_subcommands()
+            .map(|sub| sub.get_name().to_string())
+            .chain(
+                command
+                    .get_positionals()
+                    .flat_map(|arg| arg.get_possible_values())
+                    .map(|value| value.get_name().to_string()),
+            )
+            .collect();
+        if start == 0 {
+            words.retain(|w| !HIDDEN.contains(&w.as_str()));
+            words.extend(QUIT.iter().map(|w| w.to_string()));
+        }
+        words.retain(|w| w.starts_with(&line[start..]));
+        words.sort();
+        Ok((start, words))
+    }
+}
+
+fn history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE))
+}
+
+fn console_error(e: ReadlineError) -> Failure {
+    Failure::new(EXIT_USAGE, format!("console: {}", e))
+}
+
+/// Prints events above the prompt, or straight to stdout when input isn't a terminal.
+async fn print_events(
+    mut events: BoxStream<'static, DeviceEvent>,
+    mut printer: Option<impl ExternalPrinter>,
+    out: Output,
+) {
+    while let Some(event) = events.next().await {
+        let line = out.event(&event);
+        match &mut printer {
+            Some(printer) => {
+                if printer.print(line + "\n").is_err() {
+                    break;
+                }
+            }
+            None => println!("{}", line),
+        }
+    }
+}
+
+/// Runs one console line against the device.
+fn execute(runtime: &Handle, device: &DeviceHandle, line: &str, out: Output) {
+    let command = match Line::try_parse_from(line.split_whitespace()) {
+        Ok(line) => line.command,
+        Err(e) => {
+            let _ = e.print();
+            return;
+        }
+    };
+    let command = match device_command(&command) {
+        Ok(Some(command)) => command,
+        Ok(None) => {
+            out.failure(&Failure::new(EXIT_USAGE, "not available in the console"));
+            return;
+        }
+        Err(failure) => {
+            out.failure(&failure);
+            return;
+        }
+    };
+    let sent = Instant::now();
+    match runtime.block_on(device.handle_command(command)) {
+        Ok(response) => println!("{}", out.timed_response(&response, sent.elapsed())),
+        Err(e) => out.failure(&e.into()),
+    }
+}
+
+/// Reads commands from the terminal until `quit` or end of input.
+fn console(runtime: Handle, device: DeviceHandle, out: Output) -> Result<(), Failure> {
+    let mut editor = Editor::<Words, DefaultHistory>::new().map_err(console_error)?;
+    editor.set_helper(Some(Words {
+        commands: Line::command(),
+    }));
+    let history = history_path();
+    if let Some(path) = &history {
+        // There's no history file before the first session.
+        let _ = editor.load_history(path);
+    }
+    let printer = editor.create_external_printer().ok();
+    let events = runtime.spawn(print_events(device.events(), printer, out));
+
+    if let Some(info) = device.info() {
+        println!(
+            "Connected to {} firmware {}, type help for the commands",
+            info.board, info.firmware_version
+        );
+    }
+    let result = loop {
+        match editor.readline(PROMPT) {
+            Ok(line) => {
+                let line = line.trim();
+                if line.is_empty() {
+                    continue;
+                }
+                let _ = editor.add_history_entry(line);
+                if QUIT.contains(&line) {
+                    break Ok(());
+                }
+                execute(&runtime, &device, line, out);
+            }
+            // Ctrl-C drops the current line like a shell does.
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break Ok(()),
+            Err(e) => break Err(console_error(e)),
+        }
+    };
+    events.abort();
+    if let Some(path) = &history {
+        let _ = editor.save_history(path);
+    }
+    result
+}
+
+/// Runs the console on `device`, on a blocking thread since line editing is synchronous.
+pub async fn run(device: DeviceHandle, out: &Output) -> Result<(), Failure> {
+    let runtime = Handle::current();
+    let out = *out;
+    tokio::task::spawn_blocking(move || console(runtime, device, out))
+        .await
+        .map_err(|e| Failure::new(EXIT_USAGE, format!("console: {}", e)))?
+}
@@ -0,0 +1,72 @@
+//! Serves the board over HTTP/JSON, see the `http` module for the routes.
+
+use clap::Parser;
+use iced_driver::{DeviceDriver, Discovery, SerialPortParams};
+use std::process::ExitCode;
+use std::time::Duration;
+use tokio::net::TcpListener;
+
+#[derive(Parser)]
+#[command(name = "iced-http", version, about = "Serve the board over HTTP/JSON")]
+struct Args {
+    /// Serial port or socket://host:port of the board. Found by discovery when a single
+    /// ST-Link board is plugged in.
+    #[arg(short, long)]
+    port: Option<String>,
+    #[arg(short, long, default_value_t = 115200)]
+    baud: u32,
+    /// Reply timeout in milliseconds.
+    #[arg(long, default_value_t = 1000)]
+    timeout: u64,
+    /// Address to serve HTTP on.
+    #[arg(long, default_value = "127.0.0.1:8080")]
+    listen: String,
+}
+
+async fn run(args: Args) -> Result<(), String> {
+    let port = match args.port {
+        Some(port) => port,
+        None => match Discovery::new()
+            .run()
+            .await
+            .map_err(|e| e.to_string())?
+            .as_slice()
+        {
+            [device] => device.port_name.clone(),
+            _ => return Err("no single board found, pass --port".into()),
+        },
+    };
+    let params = SerialPortParams {
+        baudrate: args.baud,
+        timeout: Duration::from_millis(args.timeout),
+        ..SerialPortParams::new()
+    };
+    let mut driver = DeviceDriver::open(&port, &params)
+        .await
+        .map_err(|e| e.to_string())?;
+    let info = driver.handshake().await.map_err(|e| e.to_string())?;
+
+    let (device, _) = driver.spawn();
+    let app = iced_driver::http::router(device);
+    let listener = TcpListener::bind(&args.listen)
+        .await
+        .map_err(|e| format!("can't listen on {}: {}", args.listen, e))?;
+    eprintln!("Serving {} on http://{}", info.board, args.listen);
+    axum::serve(listener, app)
+        .with_graceful_shutdown(async {
+            let _ = tokio::signal::ctrl_c().await;
+        })
+        .await
+        .map_err(|e| e.to_string())
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    match run(Args::parse()).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,109 @@
+//! Bridges the board to an MQTT broker, see the `mqtt` module for the topics.
+
+use clap::Parser;
+use iced_driver::hotplug::serial_number_of;
+use iced_driver::mqtt::{MqttBridge, MqttOptions};
+use iced_driver::{DeviceDriver, Discovery, SerialPortParams};
+use std::process::ExitCode;
+use std::time::Duration;
+
+#[derive(Parser)]
+#[command(
+    name = "iced-mqtt",
+    version,
+    about = "Publish the board on an MQTT broker"
+)]
+struct Args {
+    /// Serial port or socket://host:port of the board. Found by discovery when a single
+    /// ST-Link board is plugged in.
+    #[arg(short, long)]
+    port: Option<String>,
+    #[arg(short, long, default_value_t = 115200)]
+    baud: u32,
+    /// Reply timeout in milliseconds.
+    #[arg(long, default_value_t = 1000)]
+    timeout: u64,
+    /// Broker as host or host:port.
+    #[arg(long, default_value = "localhost:1883")]
+    broker: String,
+    /// Name in the topics, iced/<serial>/... Defaults to the board's USB serial
+    /// number, or its name when there is none. `/`, `+` and `#` become `_`.
+    #[arg(long)]
+    serial: Option<String>,
+    /// MQTT client id, iced-<serial> by default.
+    #[arg(long)]
+    client_id: Option<String>,
+    /// How often to read the state, in milliseconds.
+    #[arg(long, default_value_t = 1000)]
+    poll: u64,
+}
+
+async fn run(args: Args) -> Result<(), String> {
+    let (port, usb_serial) = match args.port {
+        Some(port) => {
+            let usb_serial = serial_number_of(&port).unwrap_or(None);
+            (port, usb_serial)
+        }
+        None => match Discovery::new()
+            .run()
+            .await
+            .map_err(|e| e.to_string())?
+            .as_slice()
+        {
+            [device] => (device.port_name.clone(), device.serial_number.clone()),
+            _ => return Err("no single board found, pass --port".into()),
+        },
+    };
+    let params = SerialPortParams {
+        baudrate: args.baud,
+        timeout: Duration::from_millis(args.timeout),
+        ..SerialPortParams::new()
+    };
+    let mut driver = DeviceDriver::open(&port, &params)
+        .await
+        .map_err(|e| e.to_string())?;
+    let info = driver.handshake().await.map_err(|e| e.to_string())?;
+    let serial = args
+        .serial
+        .or(usb_serial)
+        .unwrap_or_else(|| info.board.as_str().to_string());
+
+    let (host, broker_port) = match args.broker.rsplit_once(':') {
+        Some((host, port)) => (
+            host.to_string(),
+            port.parse()
+                .map_err(|_| format!("bad broker port in {}", args.broker))?,
+        ),
+        None => (args.broker.clone(), 1883),
+    };
+    let client_id = args.client_id.unwrap_or_else(|| format!("iced-{}", serial));
+    let mut options = MqttOptions::new(client_id, host, broker_port);
+    options.set_keep_alive(Duration::from_secs(10));
+
+    let (device, _) = driver.spawn();
+    let bridge =
+        MqttBridge::new(device, &serial).with_poll_interval(Duration::from_millis(args.poll));
+    let cancel = bridge.cancel_token();
+    tokio::spawn(async move {
+        let _ = tokio::signal::ctrl_c().await;
+        cancel.cancel();
+    });
+    eprintln!(
+        "Publishing {} as {} on {}",
+        info.board,
+        bridge.topic("#"),
+        args.broker
+    );
+    bridge.run(options).await.map_err(|e| e.to_string())
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    match run(Args::parse()).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,286 @@
+//! Serves a device to TCP clients, so a board on a bench PC can be driven from another machine.
+//!
+//! The bridge owns the port through a [`DeviceHandle`] and speaks the device's
+//! own protocol to every client, which opens it as `socket://host:port`.
+//! Commands from all clients go through the one handle, events go to all of
+//! them, and each client negotiates its own framing. With
+//! [`Bridge::with_rfc2217`] the protocol runs inside a telnet session with the
+//! RFC 2217 COM-PORT-OPTION, for tools that expect a remote serial port.
+
+pub mod telnet;
+
+use crate::{DeviceCommands, DeviceHandle, DeviceResponses, DriverError};
+use futures::StreamExt;
+use iced_protocol::sequence::split_seq;
+use iced_protocol::{Error as ProtocolError, ErrorCode, Event, Framing, Response, FRAME_SIZE};
+use std::{io, mem};
+use telnet::Telnet;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+#[derive(Clone)]
+pub struct Bridge {
+    device: DeviceHandle,
+    /// Baud rate reported to RFC 2217 clients, `None` for the raw protocol.
+    rfc2217: Option<u32>,
+}
+
+/// Takes the next whole frame off `pending`.
+fn next_frame(pending: &mut Vec<u8>, framing: Framing) -> Option<Vec<u8>> {
+    let delimiter = framing.delimiter();
+    match pending.iter().position(|b| *b == delimiter) {
+        Some(n) => Some(pending.drain(..=n).collect()),
+        // Too long to be a frame, it's answered as malformed.
+        None if pending.len() > FRAME_SIZE => Some(mem::take(pending)),
+        None => None,
+    }
+}
+
+fn wrap(line: &[u8], framing: Framing) -> Vec<u8> {
+    let mut out = [0u8; FRAME_SIZE];
+    let n = framing.wrap(line, &mut out).unwrap_or(0);
+    out[..n].to_vec()
+}
+
+fn event_frame(event: &Event, framing: Framing) -> Vec<u8> {
+    let mut line = [0u8; FRAME_SIZE];
+    event
+        .encode(&mut line)
+        .map(|n| wrap(&line[..n], framing))
+        .unwrap_or_default()
+}
+
+impl Bridge {
+    pub fn new(device: DeviceHandle) -> Self {
+        Self {
+            device,
+            rfc2217: None,
+        }
+    }
+
+    /// Speaks RFC 2217 telnet instead of the raw protocol, telling clients
+    /// that ask the port runs at `baudrate`.
+    pub fn with_rfc2217(mut self, baudrate: u32) -> Self {
+        self.rfc2217 = Some(baudrate);
+        self
+    }
+
+    /// Accepts clients until `listener` fails, serving each on its own task.
+    pub async fn serve(&self, listener: TcpListener) -> io::Result<()> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let bridge = self.clone();
+            tokio::spawn(async move {
+                // A client going away only ends its own session.
+                let _ = bridge.serve_client(stream).await;
+            });
+        }
+    }
+
+    /// Runs one client session until either side closes.
+    pub async fn serve_client(&self, stream: TcpStream) -> io::Result<()> {
+        stream.set_nodelay(true)?;
+        let (mut reader, mut writer) = stream.into_split();
+        let mut telnet = self.rfc2217.map(Telnet::new);
+        let mut events = self.device.events();
+        let mut framing = Framing::Line;
+        let mut pending = Vec::new();
+        let mut buf = [0u8; 256];
+        loop {
+            let mut out = Vec::new();
+            let mut payload = Vec::new();
+            tokio::select! {
+                n = reader.read(&mut buf) => {
+                    let n = n?;
+                    if n == 0 {
+                        return Ok(());
+                    }
+                    match &mut telnet {
+                        Some(t) => t.feed(&buf[..n], &mut pending, &mut out),
+                        None => pending.extend_from_slice(&buf[..n]),
+                    }
+                    while let Some(frame) = next_frame(&mut pending, framing) {
+                        let (reply, next) = self.handle_frame(&frame, framing).await?;
+                        payload.extend(reply);
+                        framing = next;
+                    }
+                }
+                event = events.next() => match event {
+                    Some(event) => payload = event_frame(&event, framing),
+                    // The driver task stopped, the port is gone.
+                    None => return Ok(()),
+                },
+            }
+            match telnet {
+                Some(_) => out.extend(telnet::escape(&payload)),
+                None => out.extend(payload),
+            }
+            if !out.is_empty() {
+                writer.write_all(&out).await?;
+            }
+        }
+    }
+
+    /// Answers one frame in `framing`, returning the reply and the framing that
+    /// applies from then on.
+    async fn handle_frame(&self, frame: &[u8], framing: Framing) -> io::Result<(Vec<u8>, Framing)> {
+        let mut message = [0u8; FRAME_SIZE];
+        let (seq, response) = match framing.unwrap(frame, &mut message) {
+            _ if frame.len() > FRAME_SIZE => (None, Some(Response::Error(ErrorCode::Malformed))),
+            Err(ProtocolError::Checksum) => (None, Some(Response::Error(ErrorCode::Checksum))),
+            Err(_) => (None, Some(Response::Error(ErrorCode::Malformed))),
+            Ok(message) => match split_seq(message) {
+                Ok((seq, message)) => (seq, self.respond(message).await?),
+                Err(_) => (None, Some(Response::Error(ErrorCode::Malformed))),
+            },
+        };
+        let Some(response) = response else {
+            return Ok((Vec::new(), framing));
+        };
+        let mut line = [0u8; FRAME_SIZE];
+        let reply = response
+            .encode_seq(seq, &mut line)
+            .map(|n| wrap(&line[..n], framing))
+            .unwrap_or_default();
+        match response {
+            Response::Ack(DeviceCommands::SetFraming(next)) => Ok((reply, next)),
+            _ => Ok((reply, framing)),
+        }
+    }
+
+    /// Passes a command on to the device. Gives `None` when the device didn't
+    /// answer properly, the client then times out as it would on the port.
+    async fn respond(&self, message: &[u8]) -> io::Result<Option<Response>> {
+        let command = match DeviceCommands::decode(message) {
+            Ok(command) => command,
+            Err(_) => return Ok(Some(Response::Error(ErrorCode::Malformed))),
+        };
+        // Framing is per client, the bridge keeps its own with the device.
+        if let DeviceCommands::SetFraming(_) = command {
+            return Ok(Some(Response::Ack(command)));
+        }
+        let response = match self.device.handle_command(command).await {
+            Ok(DeviceResponses::Success) => Response::Ack(command),
+            Ok(DeviceResponses::Time(ms)) => Response::Time(ms),
+            Ok(DeviceResponses::State(s)) => Response::State(s),
+            Ok(DeviceResponses::Identity(i)) => Response::Identity(i),
+            Err(DriverError::Rejected(code)) => Response::Error(code),
+            Err(DriverError::OutOfRange(_)) => Response::Error(ErrorCode::OutOfRange),
+            // What firmware without the command answers.
+            Err(DriverError::Unsupported(_)) => Response::Error(ErrorCode::Malformed),
+            Err(DriverError::PortClosed) | Err(DriverError::Io(_)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "the device is gone",
+                ))
+            }
+            Err(_) => return Ok(None),
+        };
+        Ok(Some(response))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::telnet::{COM_PORT_OPTION, DO, IAC, SB, SE, SERVER_OFFSET, SET_BAUDRATE, WILL};
+    use super::*;
+    use crate::{DeviceDriver, DeviceEvent, Framing, SerialPortParams};
+    use iced_sim::Simulator;
+    use std::time::Duration;
+    use tokio::sync::mpsc::UnboundedSender;
+    use tokio::time::timeout;
+
+    /// A bridge on localhost in front of a simulator, returning its address
+    /// and the simulator's event sender.
+    async fn start(rfc2217: bool) -> (String, UnboundedSender<DeviceEvent>) {
+        let mut sim = Simulator::new();
+        let sim_events = sim.event_sender();
+        let (client, device) = tokio::io::duplex(256);
+        tokio::spawn(async move { sim.run(device).await });
+        let mut driver = DeviceDriver::new(client);
+        driver.handshake().await.unwrap();
+        let (handle, _) = driver.spawn();
+
+        let mut bridge = Bridge::new(handle);
+        if rfc2217 {
+            bridge = bridge.with_rfc2217(115200);
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move { bridge.serve(listener).await });
+        (address, sim_events)
+    }
+
+    #[tokio::test]
+    async fn drives_the_device_through_a_socket() {
+        let (address, _) = start(false).await;
+        let path = format!("socket://{}", address);
+        let mut driver = DeviceDriver::open(&path, &SerialPortParams::new())
+            .await
+            .unwrap();
+        let info = driver.handshake().await.unwrap();
+        assert_eq!(info.board.as_str(), "iced-sim");
+
+        driver.set_pwm_duty(42).await.unwrap();
+        assert_eq!(
+            driver.negotiate_framing(Framing::Binary).await.unwrap(),
+            Framing::Binary
+        );
+        match driver.get_state().await.unwrap() {
+            DeviceResponses::State(s) => assert_eq!(s.pwm_duty, 42),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn forwards_events_to_every_client() {
+        let (address, sim_events) = start(false).await;
+        let path = format!("socket://{}", address);
+        let params = SerialPortParams::new();
+        let mut first = DeviceDriver::open(&path, &params).await.unwrap();
+        let mut second = DeviceDriver::open(&path, &params).await.unwrap();
+        first.handshake().await.unwrap();
+        second.handshake().await.unwrap();
+        let (first, _) = first.spawn();
+        let (second, _) = second.spawn();
+        let (mut a, mut b) = (first.events(), second.events());
+
+        sim_events.send(DeviceEvent::Button).unwrap();
+        let wait = Duration::from_secs(2);
+        assert_eq!(
+            timeout(wait, a.next()).await.unwrap(),
+            Some(DeviceEvent::Button)
+        );
+        assert_eq!(
+            timeout(wait, b.next()).await.unwrap(),
+            Some(DeviceEvent::Button)
+        );
+    }
+
+    #[tokio::test]
+    async fn speaks_rfc2217() {
+        let (address, _) = start(true).await;
+        let mut stream = TcpStream::connect(&address).await.unwrap();
+        let mut request = vec![IAC, WILL, COM_PORT_OPTION];
+        request.extend_from_slice(&[IAC, SB, COM_PORT_OPTION, SET_BAUDRATE, 0, 0, 0, 0, IAC, SE]);
+        request.extend_from_slice(b"7:T\n");
+        stream.write_all(&request).await.unwrap();
+
+        let mut expected = vec![IAC, DO, COM_PORT_OPTION];
+        expected.extend_from_slice(&[IAC, SB, COM_PORT_OPTION, SET_BAUDRATE + SERVER_OFFSET]);
+        expected.extend_from_slice(&115200u32.to_be_bytes());
+        expected.extend_from_slice(&[IAC, SE]);
+        let mut received = Vec::new();
+        let mut buf = [0u8; 64];
+        while !received.ends_with(b"\n") {
+            let n = timeout(Duration::from_secs(2), stream.read(&mut buf))
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(n > 0, "bridge closed the connection");
+            received.extend_from_slice(&buf[..n]);
+        }
+        assert!(received.starts_with(&expected), "{:?}", received);
+        assert!(received[expected.len()..].starts_with(b"7:T"));
+    }
+}
@@ -0,0 +1,222 @@
+//! The telnet side of RFC 2217: option negotiation and COM-PORT-OPTION replies.
+//!
+//! The bridge owns the serial port, so line settings a client asks for are
+//! acknowledged with the values it asked for and otherwise ignored. Only a
+//! baud rate query gets the real speed.
+
+pub const IAC: u8 = 255;
+pub const DONT: u8 = 254;
+pub const DO: u8 = 253;
+pub const WONT: u8 = 252;
+pub const WILL: u8 = 251;
+pub const SB: u8 = 250;
+pub const SE: u8 = 240;
+
+pub const BINARY: u8 = 0;
+pub const SUPPRESS_GO_AHEAD: u8 = 3;
+pub const COM_PORT_OPTION: u8 = 44;
+
+/// COM-PORT-OPTION commands from the client, the server answers each with the code plus 100.
+pub const SIGNATURE: u8 = 0;
+pub const SET_BAUDRATE: u8 = 1;
+pub const PURGE_DATA: u8 = 12;
+pub const SERVER_OFFSET: u8 = 100;
+
+/// Sent in reply to a signature query.
+const SIGNATURE_TEXT: &[u8] = b"iced-bridge";
+
+/// Options the bridge agrees to on both sides of the connection.
+fn supported(option: u8) -> bool {
+    matches!(option, BINARY | SUPPRESS_GO_AHEAD | COM_PORT_OPTION)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Data,
+    Iac,
+    Option(u8),
+    Sub,
+    SubIac,
+}
+
+/// Splits a telnet byte stream into data and the negotiation the server has to answer.
+#[derive(Debug)]
+pub struct Telnet {
+    state: State,
+    sub: Vec<u8>,
+    baudrate: u32,
+    /// Options enabled on our side (WILL) and on the client's (DO).
+    local: [bool; 256],
+    remote: [bool; 256],
+}
+
+/// Doubles every IAC so data can't be mistaken for a telnet command.
+pub fn escape(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len());
+    for &b in data {
+        out.push(b);
+        if b == IAC {
+            out.push(IAC);
+        }
+    }
+    out
+}
+
+impl Telnet {
+    /// `baudrate` is what a client asking for the port's speed is told.
+    pub fn new(baudrate: u32) -> Self {
+        Self {
+            state: State::Data,
+            sub: Vec::new(),
+            baudrate,
+            local: [false; 256],
+            remote: [false; 256],
+        }
+    }
+
+    /// Consumes `input`, appending the data it carries to `data` and anything
+    /// the server has to send back to `reply`.
+    pub fn feed(&mut self, input: &[u8], data: &mut Vec<u8>, reply: &mut Vec<u8>) {
+        for &b in input {
+            self.state = match (self.state, b) {
+                (State::Data, IAC) => State::Iac,
+                (State::Data, b) => {
+                    data.push(b);
+                    State::Data
+                }
+                (State::Iac, IAC) => {
+                    data.push(IAC);
+                    State::Data
+                }
+                (State::Iac, WILL | WONT | DO | DONT) => State::Option(b),
+                (State::Iac, SB) => {
+                    self.sub.clear();
+                    State::Sub
+                }
+                // NOP, break and the like mean nothing to the device.
+                (State::Iac, _) => State::Data,
+                (State::Option(command), option) => {
+                    self.negotiate(command, option, reply);
+                    State::Data
+                }
+                (State::Sub, IAC) => State::SubIac,
+                (State::Sub, b) => {
+                    self.sub.push(b);
+                    State::Sub
+                }
+                (State::SubIac, IAC) => {
+                    self.sub.push(IAC);
+                    State::Sub
+                }
+                (State::SubIac, SE) => {
+                    self.subnegotiate(reply);
+                    State::Data
+                }
+                (State::SubIac, _) => State::Data,
+            };
+        }
+    }
+
+    /// Answers a request only when it changes an option's state, so both
+    /// sides agreeing never loops.
+    fn negotiate(&mut self, command: u8, option: u8, reply: &mut Vec<u8>) {
+        let index = usize::from(option);
+        let answer = match command {
+            WILL if supported(option) && !self.remote[index] => {
+                self.remote[index] = true;
+                DO
+            }
+            WILL if !supported(option) => DONT,
+            WONT if self.remote[index] => {
+                self.remote[index] = false;
+                DONT
+            }
+            DO if supported(option) && !self.local[index] => {
+                self.local[index] = true;
+                WILL
+            }
+            DO if !supported(option) => WONT,
+            DONT if self.local[index] => {
+                self.local[index] = false;
+                WONT
+            }
+            _ => return,
+        };
+        reply.extend_from_slice(&[IAC, answer, option]);
+    }
+
+    fn subnegotiate(&mut self, reply: &mut Vec<u8>) {
+        let (command, value) = match self.sub.as_slice() {
+            [COM_PORT_OPTION, command, value @ ..] if *command <= PURGE_DATA => (*command, value),
+            _ => return,
+        };
+        let value = match command {
+            SIGNATURE if value.is_empty() => SIGNATURE_TEXT.to_vec(),
+            SET_BAUDRATE if value.iter().all(|b| *b == 0) => self.baudrate.to_be_bytes().to_vec(),
+            _ => value.to_vec(),
+        };
+        reply.extend_from_slice(&[IAC, SB, COM_PORT_OPTION, command + SERVER_OFFSET]);
+        reply.extend(escape(&value));
+        reply.extend_from_slice(&[IAC, SE]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed(telnet: &mut Telnet, input: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let (mut data, mut reply) = (Vec::new(), Vec::new());
+        telnet.feed(input, &mut data, &mut reply);
+        (data, reply)
+    }
+
+    #[test]
+    fn passes_data_and_unescapes_iac() {
+        let mut t = Telnet::new(115200);
+        assert_eq!(feed(&mut t, b"D50\n"), (b"D50\n".to_vec(), vec![]));
+        assert_eq!(feed(&mut t, &[1, IAC, IAC, 2]), (vec![1, IAC, 2], vec![]));
+        assert_eq!(escape(&[1, IAC, 2]), vec![1, IAC, IAC, 2]);
+    }
+
+    #[test]
+    fn negotiates_once() {
+        let mut t = Telnet::new(115200);
+        let (_, reply) = feed(&mut t, &[IAC, WILL, COM_PORT_OPTION]);
+        assert_eq!(reply, vec![IAC, DO, COM_PORT_OPTION]);
+        let (_, reply) = feed(&mut t, &[IAC, WILL, COM_PORT_OPTION]);
+        assert!(reply.is_empty());
+        let (_, reply) = feed(&mut t, &[IAC, DO, 1]);
+        assert_eq!(reply, vec![IAC, WONT, 1]);
+    }
+
+    #[test]
+    fn answers_com_port_commands() {
+        let mut t = Telnet::new(115200);
+        let (data, reply) = feed(
+            &mut t,
+            &[
+                IAC,
+                SB,
+                COM_PORT_OPTION,
+                SET_BAUDRATE,
+                0,
+                0,
+                0,
+                0,
+                IAC,
+                SE,
+                b'T',
+            ],
+        );
+        assert_eq!(data, b"T".to_vec());
+        let mut expected = vec![IAC, SB, COM_PORT_OPTION, SET_BAUDRATE + SERVER_OFFSET];
+        expected.extend_from_slice(&115200u32.to_be_bytes());
+        expected.extend_from_slice(&[IAC, SE]);
+        assert_eq!(reply, expected);
+
+        // Data size 8, echoed back as set.
+        let (_, reply) = feed(&mut t, &[IAC, SB, COM_PORT_OPTION, 2, 8, IAC, SE]);
+        assert_eq!(reply, vec![IAC, SB, COM_PORT_OPTION, 102, 8, IAC, SE]);
+    }
+}
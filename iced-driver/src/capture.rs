@@ -0,0 +1,590 @@
+//! Recording what crosses the wire, reading recordings back and replaying them.
+//!
+//! A capture file holds one JSON object per line in the order the bytes went
+//! by, such as `{"time_us":1520,"direction":"tx","data":"303a490a"}`: the
+//! time since the capture started, whether the host sent or received the
+//! bytes and the bytes in hex. [`Capture`] wraps a transport and records as it
+//! goes, [`Port::capture`] does the same for an opened port. [`Annotator`]
+//! splits recorded bytes back into frames and decodes them, [`Replay`] plays
+//! the device's side of a capture to a driver as a fake device.
+
+use crate::Port;
+use futures::ready;
+use iced_protocol::sequence::split_seq;
+use iced_protocol::{scpi, Command, Event, Framing, Response, Seq, FRAME_SIZE};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{mpsc, Arc};
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::Instant;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf};
+use tokio::task::JoinHandle;
+
+/// Which way bytes went, seen from the host.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Direction {
+    #[serde(rename = "tx")]
+    Sent,
+    #[serde(rename = "rx")]
+    Received,
+}
+
+/// Bytes that went by in one read or write.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Record {
+    /// Microseconds since the capture started.
+    pub time_us: u64,
+    pub direction: Direction,
+    #[serde(serialize_with = "to_hex", deserialize_with = "from_hex")]
+    pub data: Vec<u8>,
+}
+
+fn to_hex<S: Serializer>(data: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    let hex: String = data.iter().map(|b| format!("{:02x}", b)).collect();
+    serializer.serialize_str(&hex)
+}
+
+fn from_hex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    let hex = String::deserialize(deserializer)?;
+    let invalid = || serde::de::Error::custom(format!("invalid hex {:?}", hex));
+    if hex.len() % 2 != 0 {
+        return Err(invalid());
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            hex.get(i..i + 2)
+                .and_then(|b| u8::from_str_radix(b, 16).ok())
+                .ok_or_else(invalid)
+        })
+        .collect()
+}
+
+/// Reads a capture file, failing on the first line that isn't a record.
+pub fn read_capture(path: impl AsRef<Path>) -> io::Result<Vec<Record>> {
+    let mut records = Vec::new();
+    for (n, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record = serde_json::from_str(&line).map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidData, format!("line {}: {}", n + 1, e))
+        })?;
+        records.push(record);
+    }
+    Ok(records)
+}
+
+/// Where a [`Capture`] writes its records. Clones write to the same place.
+///
+/// Records are written by a thread of their own so reads and writes on the
+/// port never wait for the file. Once the last clone is dropped, everything
+/// recorded has been written.
+#[derive(Clone)]
+pub struct CaptureLog {
+    writer: Arc<Writer>,
+    started: Instant,
+}
+
+struct Writer {
+    records: Option<mpsc::Sender<Record>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Writer {
+    /// Writes records as they come and flushes whenever none are waiting, so
+    /// a crash loses little. A capture that can't be written doesn't get in
+    /// the way of the session.
+    fn spawn(out: impl Write + Send + 'static) -> Self {
+        let (records, queue) = mpsc::channel::<Record>();
+        let thread = thread::spawn(move || {
+            let mut out = BufWriter::new(out);
+            while let Ok(record) = queue.recv() {
+                for record in std::iter::once(record).chain(queue.try_iter()) {
+                    if let Ok(line) = serde_json::to_string(&record) {
+                        let _ = writeln!(out, "{}", line);
+                    }
+                }
+                let _ = out.flush();
+            }
+        });
+        Self {
+            records: Some(records),
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        // Closing the channel lets the thread finish what's queued.
+        self.records.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl CaptureLog {
+    /// Starts a capture file at `path`, replacing any file already there.
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::new(File::create(path)?))
+    }
+
+    pub fn new(out: impl Write + Send + 'static) -> Self {
+        Self {
+            writer: Arc::new(Writer::spawn(out)),
+            started: Instant::now(),
+        }
+    }
+
+    /// Queues a record for the writer thread.
+    fn record(&self, direction: Direction, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let record = Record {
+            time_us: self.started.elapsed().as_micros() as u64,
+            direction,
+            data: data.to_vec(),
+        };
+        if let Some(records) = &self.writer.records {
+            let _ = records.send(record);
+        }
+    }
+}
+
+impl fmt::Debug for CaptureLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CaptureLog")
+            .field("started", &self.started)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A transport that records every byte read from or written to it.
+#[derive(Debug)]
+pub struct Capture<T> {
+    inner: T,
+    log: CaptureLog,
+}
+
+impl<T> Capture<T> {
+    pub fn new(inner: T, log: CaptureLog) -> Self {
+        Self { inner, log }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for Capture<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.log
+            .record(Direction::Received, &buf.filled()[before..]);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for Capture<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.log.record(Direction::Sent, &buf[..n]);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+impl Port {
+    /// Records everything that goes through the port to `log`.
+    pub fn capture(self, log: CaptureLog) -> Port {
+        Port::Captured(Box::new(Capture::new(self, log)))
+    }
+}
+
+/// Formats `data` as lines of 16 hex bytes with the printable ones alongside.
+pub fn hex_dump(data: &[u8]) -> Vec<String> {
+    data.chunks(16)
+        .enumerate()
+        .map(|(i, chunk)| {
+            let hex: Vec<String> = chunk.iter().map(|b| format!("{:02x}", b)).collect();
+            let text: String = chunk
+                .iter()
+                .map(|&b| match b {
+                    0x20..=0x7e => b as char,
+                    _ => '.',
+                })
+                .collect();
+            format!("{:04x}  {:<47}  |{}|", i * 16, hex.join(" "), text)
+        })
+        .collect()
+}
+
+/// Splits the bytes of a capture back into frames and describes them,
+/// following the framing when the device acknowledges a change.
+#[derive(Debug)]
+pub struct Annotator {
+    framing: Framing,
+    sent: Vec<u8>,
+    received: Vec<u8>,
+}
+
+impl Annotator {
+    pub fn new() -> Self {
+        Self {
+            framing: Framing::Line,
+            sent: Vec::new(),
+            received: Vec::new(),
+        }
+    }
+
+    /// Describes the frames `record` completes, bytes of unfinished frames
+    /// wait for the next record in the same direction.
+    pub fn feed(&mut self, record: &Record) -> Vec<String> {
+        let pending = match record.direction {
+            Direction::Sent => &mut self.sent,
+            Direction::Received => &mut self.received,
+        };
+        pending.extend_from_slice(&record.data);
+        let mut frames = Vec::new();
+        while let Some(end) = pending.iter().position(|b| *b == self.framing.delimiter()) {
+            frames.push(pending.drain(..=end).collect::<Vec<u8>>());
+        }
+        let mut descriptions = Vec::with_capacity(frames.len());
+        for frame in frames {
+            let mut message = [0u8; FRAME_SIZE];
+            let description = match self.framing.unwrap(&frame, &mut message) {
+                Ok(message) => match record.direction {
+                    Direction::Sent => describe_sent(message),
+                    Direction::Received => {
+                        if let Ok((_, reply)) = split_seq(message) {
+                            if let Ok(Response::Ack(Command::SetFraming(f))) =
+                                Response::decode(reply)
+                            {
+                                self.framing = f;
+                            }
+                        }
+                        describe_received(message)
+                    }
+                },
+                Err(e) => format!("invalid frame: {}", e),
+            };
+            descriptions.push(description);
+        }
+        descriptions
+    }
+}
+
+impl Default for Annotator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn with_seq(seq: Option<Seq>, text: String) -> String {
+    match seq {
+        Some(seq) => format!("#{} {}", seq, text),
+        None => text,
+    }
+}
+
+fn describe_sent(message: &[u8]) -> String {
+    if scpi::is_scpi(message) {
+        return match scpi::Message::parse(message) {
+            Ok(m) => format!("SCPI {:?}", m),
+            Err(e) => format!("SCPI error {}: {}", e.code(), e.message()),
+        };
+    }
+    match split_seq(message) {
+        Ok((seq, command)) => match Command::decode(command) {
+            Ok(command) => with_seq(seq, format!("{:?}", command)),
+            Err(e) => with_seq(seq, format!("unknown command: {}", e)),
+        },
+        Err(e) => format!("unknown command: {}", e),
+    }
+}
+
+fn describe_received(message: &[u8]) -> String {
+    if Event::is_event(message) {
+        return match Event::decode(message) {
+            Ok(event) => format!("event {:?}", event),
+            Err(e) => format!("unknown event: {}", e),
+        };
+    }
+    if let Ok((seq, reply)) = split_seq(message) {
+        if let Ok(response) = Response::decode(reply) {
+            return with_seq(seq, format!("{:?}", response));
+        }
+    }
+    // SCPI answers are free text.
+    format!("{:?}", String::from_utf8_lossy(message))
+}
+
+#[derive(Debug)]
+pub enum ReplayError {
+    Io(io::Error),
+    /// The driver sent something else than the capture recorded.
+    Diverged {
+        /// Index of the record that didn't match.
+        record: usize,
+        expected: Vec<u8>,
+        received: Vec<u8>,
+    },
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayError::Io(e) => write!(f, "I/O error: {}", e),
+            ReplayError::Diverged {
+                record,
+                expected,
+                received,
+            } => write!(
+                f,
+                "record {} expected {:?}, got {:?}",
+                record,
+                String::from_utf8_lossy(expected),
+                String::from_utf8_lossy(received)
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ReplayError {}
+
+impl From<io::Error> for ReplayError {
+    fn from(e: io::Error) -> Self {
+        ReplayError::Io(e)
+    }
+}
+
+/// The device's side of a capture, played back as a fake device.
+#[derive(Debug, Clone)]
+pub struct Replay {
+    records: Vec<Record>,
+}
+
+impl Replay {
+    pub fn new(records: Vec<Record>) -> Self {
+        Self { records }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        read_capture(path).map(Self::new)
+    }
+
+    /// Goes through the records in order on `io`: waits for the bytes the
+    /// host sent and checks they match, sends the bytes it received. Ends
+    /// after the last record, the driver then finds the port closed.
+    pub async fn run<T>(&self, mut io: T) -> Result<(), ReplayError>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        for (i, record) in self.records.iter().enumerate() {
+            match record.direction {
+                Direction::Sent => {
+                    let mut received = vec![0u8; record.data.len()];
+                    let mut n = 0;
+                    while n < received.len() {
+                        match io.read(&mut received[n..]).await? {
+                            0 => break,
+                            read => n += read,
+                        }
+                    }
+                    if received[..n] != record.data[..] {
+                        received.truncate(n);
+                        return Err(ReplayError::Diverged {
+                            record: i,
+                            expected: record.data.clone(),
+                            received,
+                        });
+                    }
+                }
+                Direction::Received => {
+                    io.write_all(&record.data).await?;
+                    io.flush().await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Spawns the replay on one end of an in-memory pipe and returns the other end.
+    pub fn spawn(self, max_buf_size: usize) -> (DuplexStream, JoinHandle<Result<(), ReplayError>>) {
+        let (client, device) = tokio::io::duplex(max_buf_size);
+        let handle = tokio::spawn(async move { self.run(device).await });
+        (client, handle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DeviceDriver, DeviceResponses, DriverError};
+    use std::sync::Mutex;
+
+    /// A writer tests can read back.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Shared {
+        fn records(&self) -> Vec<Record> {
+            let text = String::from_utf8(self.0.lock().unwrap().clone()).unwrap();
+            text.lines()
+                .map(|l| serde_json::from_str(l).unwrap())
+                .collect()
+        }
+    }
+
+    /// Runs a short session against the simulator and returns what it recorded and got.
+    async fn session() -> (Vec<Record>, Vec<DeviceResponses>) {
+        let out = Shared::default();
+        let (client, _sim) = iced_sim::spawn(256);
+        let port = Capture::new(client, CaptureLog::new(out.clone()));
+        let mut driver = DeviceDriver::new(port);
+        driver.handshake().await.unwrap();
+        let responses = vec![
+            driver.set_pwm_duty(40).await.unwrap(),
+            driver.get_state().await.unwrap(),
+        ];
+        // Dropping the log waits for its records to be written.
+        drop(driver);
+        (out.records(), responses)
+    }
+
+    #[test]
+    fn stores_data_in_hex() {
+        let record = Record {
+            time_us: 1520,
+            direction: Direction::Sent,
+            data: b"0:I\n".to_vec(),
+        };
+        let line = serde_json::to_string(&record).unwrap();
+        assert_eq!(
+            line,
+            r#"{"time_us":1520,"direction":"tx","data":"303a490a"}"#
+        );
+        assert_eq!(serde_json::from_str::<Record>(&line).unwrap(), record);
+        assert!(
+            serde_json::from_str::<Record>(r#"{"time_us":0,"direction":"rx","data":"3"}"#).is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn records_and_decodes_a_session() {
+        let (records, _) = session().await;
+        assert_eq!(records[0].direction, Direction::Sent);
+        assert_eq!(records[0].data, b"0:I\n");
+        assert!(records.windows(2).all(|w| w[0].time_us <= w[1].time_us));
+
+        let mut annotator = Annotator::new();
+        let frames: Vec<String> = records.iter().flat_map(|r| annotator.feed(r)).collect();
+        assert_eq!(frames[0], "#0 Identify");
+        assert!(frames[1].starts_with("#0 Identity("));
+        assert_eq!(frames[2], "#1 PwmDuty(40)");
+        assert_eq!(frames[3], "#1 Ack(PwmDuty(40))");
+        assert_eq!(frames[4], "#2 GetState");
+        assert!(frames[5].starts_with("#2 State("));
+    }
+
+    #[tokio::test]
+    async fn follows_framing_changes() {
+        let out = Shared::default();
+        let (client, _sim) = iced_sim::spawn(256);
+        let mut driver = DeviceDriver::new(Capture::new(client, CaptureLog::new(out.clone())));
+        driver.set_framing(Framing::Binary).await.unwrap();
+        driver.get_time().await.unwrap();
+        drop(driver);
+
+        let mut annotator = Annotator::new();
+        let frames: Vec<String> = out
+            .records()
+            .iter()
+            .flat_map(|r| annotator.feed(r))
+            .collect();
+        assert_eq!(frames[0], "#0 SetFraming(Binary)");
+        assert_eq!(frames[2], "#1 GetTime");
+        assert!(frames[3].starts_with("#1 Time("));
+    }
+
+    #[tokio::test]
+    async fn replays_a_session() {
+        let (records, responses) = session().await;
+        let (client, replay) = Replay::new(records.clone()).spawn(256);
+        let mut driver = DeviceDriver::new(client);
+        driver.handshake().await.unwrap();
+        let again = vec![
+            driver.set_pwm_duty(40).await.unwrap(),
+            driver.get_state().await.unwrap(),
+        ];
+        assert_eq!(again, responses);
+        replay.await.unwrap().unwrap();
+
+        // A driver that strays from the capture is caught.
+        let (client, replay) = Replay::new(records).spawn(256);
+        let mut driver = DeviceDriver::new(client).with_retries(0);
+        driver.handshake().await.unwrap();
+        assert!(matches!(
+            driver.set_pwm_duty(50).await,
+            Err(DriverError::PortClosed) | Err(DriverError::Timeout)
+        ));
+        match replay.await.unwrap() {
+            Err(ReplayError::Diverged {
+                expected, received, ..
+            }) => {
+                assert_eq!(expected, b"1:D40\n");
+                assert_eq!(received, b"1:D50\n");
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn dumps_hex() {
+        let dump = hex_dump(b"0:S\n0123456789abcdef");
+        assert_eq!(dump.len(), 2);
+        assert_eq!(
+            dump[0],
+            "0000  30 3a 53 0a 30 31 32 33 34 35 36 37 38 39 61 62  |0:S.0123456789ab|"
+        );
+        assert_eq!(dump[1], format!("0010  {:<47}  |cdef|", "63 64 65 66"));
+    }
+}
@@ -0,0 +1,163 @@
+use crate::DeviceCommands;
+use bytes::BytesMut;
+use iced_protocol::framing::{decode_binary, BINARY_DELIMITER};
+use iced_protocol::{Error as ProtocolError, Framing, Seq, DELIMITER, FRAME_SIZE};
+use std::io;
+use tokio_util::codec::{Decoder, Encoder};
+
+/// A received message with its framing stripped, or why it couldn't be recovered.
+pub type Message = Result<Vec<u8>, ProtocolError>;
+
+/// A command and the sequence number its reply has to carry.
+pub type Request = (Seq, DeviceCommands);
+
+fn encode_command(
+    framing: Framing,
+    (seq, item): Request,
+    dst: &mut BytesMut,
+) -> Result<(), io::Error> {
+    let mut line = [0u8; FRAME_SIZE];
+    let mut frame = [0u8; FRAME_SIZE];
+    let n = item
+        .encode_seq(Some(seq), &mut line)
+        .and_then(|n| framing.wrap(&line[..n], &mut frame))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))?;
+    dst.extend_from_slice(&frame[..n]);
+    Ok(())
+}
+
+/// ASCII lines terminated by `\n`.
+#[derive(Debug, Default)]
+pub struct LineCodec;
+
+impl Decoder for LineCodec {
+    type Item = Message;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let newline = src.as_ref().iter().position(|b| *b == DELIMITER);
+        match newline {
+            Some(n) => {
+                let line = src.split_to(n + 1);
+                Ok(Some(Ok(line[..n].to_vec())))
+            }
+            // As with binary frames, noise without a newline isn't kept.
+            None if src.len() > FRAME_SIZE => {
+                src.clear();
+                Ok(Some(Err(ProtocolError::InvalidFrame)))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl Encoder<Request> for LineCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Request, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        encode_command(Framing::Line, item, dst)
+    }
+}
+
+/// COBS encoded, CRC-16 protected frames terminated by `0x00`.
+#[derive(Debug, Default)]
+pub struct CobsCodec;
+
+impl Decoder for CobsCodec {
+    type Item = Message;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let delimiter = src.as_ref().iter().position(|b| *b == BINARY_DELIMITER);
+        match delimiter {
+            Some(n) => {
+                let frame = src.split_to(n + 1);
+                let mut message = [0u8; FRAME_SIZE];
+                Ok(Some(
+                    decode_binary(&frame, &mut message).map(|m| message[..m].to_vec()),
+                ))
+            }
+            // No valid frame is this long, drop the noise instead of buffering it forever.
+            None if src.len() > FRAME_SIZE => {
+                src.clear();
+                Ok(Some(Err(ProtocolError::InvalidFrame)))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+impl Encoder<Request> for CobsCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Request, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        encode_command(Framing::Binary, item, dst)
+    }
+}
+
+/// Dispatches to the codec of the currently negotiated framing.
+#[derive(Debug)]
+pub enum FrameCodec {
+    Line(LineCodec),
+    Binary(CobsCodec),
+}
+
+impl FrameCodec {
+    pub fn new(framing: Framing) -> Self {
+        match framing {
+            Framing::Line => FrameCodec::Line(LineCodec),
+            Framing::Binary => FrameCodec::Binary(CobsCodec),
+        }
+    }
+
+    pub fn framing(&self) -> Framing {
+        match self {
+            FrameCodec::Line(_) => Framing::Line,
+            FrameCodec::Binary(_) => Framing::Binary,
+        }
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = Message;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        match self {
+            FrameCodec::Line(c) => c.decode(src),
+            FrameCodec::Binary(c) => c.decode(src),
+        }
+    }
+}
+
+impl Encoder<Request> for FrameCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, item: Request, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        match self {
+            FrameCodec::Line(c) => c.encode(item, dst),
+            FrameCodec::Binary(c) => c.encode(item, dst),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn drops_overlong_noise() {
+        for framing in [Framing::Line, Framing::Binary] {
+            let mut codec = FrameCodec::new(framing);
+            let mut src = BytesMut::from(&[b'x'; FRAME_SIZE + 1][..]);
+            let message = codec.decode(&mut src).unwrap();
+            assert_eq!(
+                message,
+                Some(Err(ProtocolError::InvalidFrame)),
+                "{:?}",
+                framing
+            );
+            assert!(src.is_empty());
+        }
+    }
+}
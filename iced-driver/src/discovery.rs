@@ -0,0 +1,156 @@
+//! Finding boards among the serial ports of the machine.
+//!
+//! The NUCLEO boards expose the MCU's USART2 through the ST-Link virtual COM
+//! port, so by default only ports with an ST-Link USB id are considered.
+//! Probing then opens each candidate and keeps those answering the handshake.
+
+use crate::{DeviceDriver, DeviceInfo, DriverError, SerialPortParams};
+use futures::future::join_all;
+use std::io;
+use tokio_serial::{SerialPortInfo, SerialPortType};
+
+/// STMicroelectronics' USB vendor id.
+pub const ST_LINK_VID: u16 = 0x0483;
+
+/// Product ids of the ST-Link V2-1 and V3 probes, all of which have a VCP.
+pub const ST_LINK_PIDS: &[u16] = &[0x374b, 0x3752, 0x374e, 0x374f, 0x3753, 0x3754];
+
+/// A port that passed discovery.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FoundDevice {
+    pub port_name: String,
+    /// The USB serial number, which stays the same whatever name the port gets.
+    pub serial_number: Option<String>,
+    /// What the device reported, only set when probing.
+    pub info: Option<DeviceInfo>,
+}
+
+pub struct Discovery {
+    usb_ids: Option<Vec<(u16, u16)>>,
+    probe: Option<SerialPortParams>,
+}
+
+impl Discovery {
+    /// Looks for ST-Link virtual COM ports, without probing them.
+    pub fn new() -> Self {
+        let ids = ST_LINK_PIDS.iter().map(|pid| (ST_LINK_VID, *pid)).collect();
+        Self {
+            usb_ids: Some(ids),
+            probe: None,
+        }
+    }
+
+    /// Considers every serial port, USB or not, such as the simulator's pseudo-terminal.
+    pub fn all_ports() -> Self {
+        Self {
+            usb_ids: None,
+            probe: None,
+        }
+    }
+
+    /// Also accepts ports with this USB vendor and product id. Changes nothing
+    /// after [`Discovery::all_ports`], which already accepts them.
+    pub fn with_usb_id(mut self, vid: u16, pid: u16) -> Self {
+        if let Some(ids) = &mut self.usb_ids {
+            ids.push((vid, pid));
+        }
+        self
+    }
+
+    /// Opens every candidate with `params` and only keeps the ports where a
+    /// device of a compatible protocol version answers within `params.timeout`.
+    pub fn with_probe(mut self, params: SerialPortParams) -> Self {
+        self.probe = Some(params);
+        self
+    }
+
+    pub fn matches(&self, port: &SerialPortInfo) -> bool {
+        match (&self.usb_ids, &port.port_type) {
+            (None, _) => true,
+            (Some(ids), SerialPortType::UsbPort(usb)) => ids.contains(&(usb.vid, usb.pid)),
+            (Some(_), _) => false,
+        }
+    }
+
+    pub async fn run(&self) -> Result<Vec<FoundDevice>, DriverError> {
+        let ports = tokio_serial::available_ports().map_err(io::Error::from)?;
+        let found = ports.into_iter().filter(|p| self.matches(p)).map(|p| {
+            let serial_number = match p.port_type {
+                SerialPortType::UsbPort(usb) => usb.serial_number,
+                _ => None,
+            };
+            FoundDevice {
+                port_name: p.port_name,
+                serial_number,
+                info: None,
+            }
+        });
+        let params = match &self.probe {
+            Some(params) => params,
+            None => return Ok(found.collect()),
+        };
+        let probed = join_all(found.map(|mut device| async move {
+            device.info = probe(&device.port_name, params).await.ok();
+            device
+        }))
+        .await;
+        Ok(probed.into_iter().filter(|d| d.info.is_some()).collect())
+    }
+}
+
+impl Default for Discovery {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Opens `path` and runs the handshake once, without retries.
+pub async fn probe(path: &str, params: &SerialPortParams) -> Result<DeviceInfo, DriverError> {
+    let mut driver = DeviceDriver::open(path, params).await?.with_retries(0);
+    driver.handshake().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_serial::UsbPortInfo;
+
+    fn port(port_type: SerialPortType) -> SerialPortInfo {
+        SerialPortInfo {
+            port_name: "/dev/ttyACM0".to_string(),
+            port_type,
+        }
+    }
+
+    fn usb(vid: u16, pid: u16) -> SerialPortInfo {
+        port(SerialPortType::UsbPort(UsbPortInfo {
+            vid,
+            pid,
+            serial_number: None,
+            manufacturer: None,
+            product: None,
+        }))
+    }
+
+    #[test]
+    fn matches_st_link_ports() {
+        let discovery = Discovery::new();
+        assert!(discovery.matches(&usb(ST_LINK_VID, 0x374b)));
+        assert!(!discovery.matches(&usb(ST_LINK_VID, 0x5740)));
+        assert!(!discovery.matches(&port(SerialPortType::Unknown)));
+
+        let discovery = discovery.with_usb_id(0x1a86, 0x7523);
+        assert!(discovery.matches(&usb(0x1a86, 0x7523)));
+
+        let all = Discovery::all_ports().with_usb_id(0x1a86, 0x7523);
+        assert!(all.matches(&port(SerialPortType::Unknown)));
+        assert!(all.matches(&usb(0x1a86, 0x7523)));
+        assert!(all.matches(&usb(ST_LINK_VID, 0x374b)));
+    }
+
+    #[tokio::test]
+    async fn probe_fails_on_a_missing_port() {
+        let params = SerialPortParams::new();
+        assert!(probe("/dev/does-not-exist", &params).await.is_err());
+    }
+}
@@ -0,0 +1,70 @@
+use crate::DeviceCommands;
+use iced_protocol::{DeviceInfo, Error as ProtocolError, ErrorCode, Response, PROTOCOL_VERSION};
+use std::{fmt, io};
+
+#[derive(Debug)]
+pub enum DriverError {
+    /// Reading from or writing to the transport failed.
+    Io(io::Error),
+    /// The device didn't answer in time.
+    Timeout,
+    /// The transport reached end of file.
+    PortClosed,
+    /// The device sent a line that isn't a valid reply to the command.
+    MalformedResponse(String),
+    /// A binary frame from the device failed its integrity check.
+    Corrupt(ProtocolError),
+    /// The device refused the command.
+    Rejected(ErrorCode),
+    /// The command's argument is outside what the device accepts, it wasn't sent.
+    OutOfRange(DeviceCommands),
+    /// The reply carries the command's sequence number but answers a different command.
+    Mismatch {
+        sent: DeviceCommands,
+        received: Response,
+    },
+    /// The device speaks a different version of the protocol.
+    Incompatible(DeviceInfo),
+    /// The device didn't list the command as supported during the handshake, it wasn't sent.
+    Unsupported(DeviceCommands),
+}
+
+impl fmt::Display for DriverError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DriverError::Io(e) => write!(f, "I/O error: {}", e),
+            DriverError::Timeout => write!(f, "timed out waiting for the device"),
+            DriverError::PortClosed => write!(f, "port closed"),
+            DriverError::MalformedResponse(line) => {
+                write!(f, "malformed response {:?}", line)
+            }
+            DriverError::Corrupt(e) => write!(f, "corrupt frame: {}", e),
+            DriverError::Rejected(code) => write!(f, "device rejected the command: {}", code),
+            DriverError::OutOfRange(cmd) => write!(f, "argument out of range in {:?}", cmd),
+            DriverError::Mismatch { sent, received } => {
+                write!(f, "sent {:?} but the reply is {:?}", sent, received)
+            }
+            DriverError::Incompatible(info) => write!(
+                f,
+                "{} firmware {} speaks protocol version {}, expected {}",
+                info.board, info.firmware_version, info.protocol_version, PROTOCOL_VERSION
+            ),
+            DriverError::Unsupported(cmd) => write!(f, "the device doesn't support {:?}", cmd),
+        }
+    }
+}
+
+impl std::error::Error for DriverError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DriverError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for DriverError {
+    fn from(e: io::Error) -> Self {
+        DriverError::Io(e)
+    }
+}
@@ -0,0 +1,215 @@
+//! A background task owning the driver, and cheap handles to talk to it.
+//!
+//! The task keeps reading the port between commands, so nothing the device
+//! sends on its own piles up in front of the next reply. Commands from all
+//! handles are queued and sent one at a time, the firmware only buffers a
+//! single frame.
+
+use crate::{
+    event_stream, telemetry_period, DeviceCommands, DeviceDriver, DeviceEvent, DeviceInfo,
+    DeviceResponse, DeviceResponses, DriverError, Framing, Transport,
+};
+use futures::stream::BoxStream;
+use std::time::Duration;
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+struct Request {
+    command: DeviceCommands,
+    reply: oneshot::Sender<DeviceResponse>,
+}
+
+/// Sends commands to a driver running in its own task. Clones share the task,
+/// which stops once every handle is dropped or the port closes.
+pub struct DeviceHandle {
+    requests: mpsc::UnboundedSender<Request>,
+    info: Option<DeviceInfo>,
+    // Only resubscribed, never read. Holding a sender instead would keep
+    // event streams open after the task stops.
+    events: broadcast::Receiver<DeviceEvent>,
+}
+
+impl Clone for DeviceHandle {
+    fn clone(&self) -> Self {
+        Self {
+            requests: self.requests.clone(),
+            info: self.info,
+            events: self.events.resubscribe(),
+        }
+    }
+}
+
+impl<T: Transport + 'static> DeviceDriver<T> {
+    /// Moves the driver into a background task. The join handle gives the
+    /// driver back once the task stops, in line framing if the last handle
+    /// was dropped.
+    pub fn spawn(self) -> (DeviceHandle, JoinHandle<DeviceDriver<T>>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let handle = DeviceHandle {
+            requests: tx,
+            info: self.info().copied(),
+            events: self.events.subscribe(),
+        };
+        (handle, tokio::spawn(run(self, rx)))
+    }
+}
+
+async fn run<T: Transport>(
+    mut driver: DeviceDriver<T>,
+    mut requests: mpsc::UnboundedReceiver<Request>,
+) -> DeviceDriver<T> {
+    loop {
+        tokio::select! {
+            request = requests.recv() => match request {
+                Some(Request { command, reply }) => {
+                    // The caller may have given up waiting, that's fine.
+                    let _ = reply.send(driver.handle_command(command).await);
+                }
+                None => {
+                    driver.restore_line_framing().await;
+                    break;
+                }
+            },
+            idle = driver.read_idle() => {
+                if idle.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+    driver
+}
+
+impl DeviceHandle {
+    /// What the device reported during the handshake, if the driver did one before spawning.
+    pub fn info(&self) -> Option<&DeviceInfo> {
+        self.info.as_ref()
+    }
+
+    /// Events the device sends on its own from now on, kept apart from the
+    /// replies. The stream ends when the driver task stops.
+    pub fn events(&self) -> BoxStream<'static, DeviceEvent> {
+        event_stream(self.events.resubscribe())
+    }
+
+    /// Whether the driver task has stopped, after which every command fails
+    /// with [`DriverError::PortClosed`].
+    pub fn is_closed(&self) -> bool {
+        self.requests.is_closed()
+    }
+
+    pub async fn handle_command(&self, command: DeviceCommands) -> DeviceResponse {
+        let (reply, response) = oneshot::channel();
+        self.requests
+            .send(Request { command, reply })
+            .map_err(|_| DriverError::PortClosed)?;
+        response.await.map_err(|_| DriverError::PortClosed)?
+    }
+
+    pub async fn set_gpio(&self) -> DeviceResponse {
+        self.handle_command(DeviceCommands::SetGpioPin).await
+    }
+
+    pub async fn clear_gpio(&self) -> DeviceResponse {
+        self.handle_command(DeviceCommands::ClearGpioPin).await
+    }
+
+    pub async fn set_pwm_hz(&self, hz: u32) -> DeviceResponse {
+        self.handle_command(DeviceCommands::PwmSetFreq(hz)).await
+    }
+
+    pub async fn set_pwm_duty(&self, percent: u8) -> DeviceResponse {
+        self.handle_command(DeviceCommands::PwmDuty(percent)).await
+    }
+
+    pub async fn get_time(&self) -> DeviceResponse {
+        self.handle_command(DeviceCommands::GetTime).await
+    }
+
+    /// Time since the device booted, read from its millisecond SysTick counter.
+    pub async fn get_uptime(&self) -> Result<Duration, DriverError> {
+        match self.get_time().await? {
+            DeviceResponses::Time(ms) => Ok(Duration::from_millis(ms.into())),
+            r => Err(DriverError::MalformedResponse(format!("{:?}", r))),
+        }
+    }
+
+    pub async fn get_state(&self) -> DeviceResponse {
+        self.handle_command(DeviceCommands::GetState).await
+    }
+
+    /// Asks for an event with the device state every `period`, zero stops them.
+    pub async fn set_telemetry(&self, period: Duration) -> DeviceResponse {
+        self.handle_command(DeviceCommands::SetTelemetry(telemetry_period(period)))
+            .await
+    }
+
+    pub async fn identify(&self) -> Result<DeviceInfo, DriverError> {
+        match self.handle_command(DeviceCommands::Identify).await? {
+            DeviceResponses::Identity(info) => Ok(info),
+            r => Err(DriverError::MalformedResponse(format!("{:?}", r))),
+        }
+    }
+
+    /// Switches both ends to `framing`.
+    pub async fn set_framing(&self, framing: Framing) -> DeviceResponse {
+        self.handle_command(DeviceCommands::SetFraming(framing))
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{DeviceCommands, DeviceDriver, DeviceResponses, DriverError, Framing};
+    use futures::future;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn serves_clones_concurrently() {
+        let (client, _sim) = iced_sim::spawn(256);
+        let (device, _task) = DeviceDriver::new(client).spawn();
+        let handles: Vec<_> = (0..4).map(|_| device.clone()).collect();
+        let replies = future::join_all(handles.iter().zip([10, 20, 30, 40]).map(
+            |(handle, duty)| async move {
+                handle.set_pwm_duty(duty).await.unwrap();
+                handle.get_state().await
+            },
+        ))
+        .await;
+        assert!(replies
+            .iter()
+            .all(|r| matches!(r, Ok(DeviceResponses::State(_)))));
+    }
+
+    #[tokio::test]
+    async fn closes_when_the_port_drops() {
+        let (client, sim) = iced_sim::spawn(256);
+        let (device, task) = DeviceDriver::new(client).spawn();
+        sim.abort();
+        let _ = sim.await;
+        task.await.unwrap();
+        assert!(device.is_closed());
+        assert!(matches!(
+            device.handle_command(DeviceCommands::GetState).await,
+            Err(DriverError::PortClosed)
+        ));
+    }
+
+    #[tokio::test]
+    async fn stops_with_the_last_handle() {
+        let (client, _sim) = iced_sim::spawn(256);
+        let (device, task) = DeviceDriver::new(client).spawn();
+        let other = device.clone();
+        device.set_framing(Framing::Binary).await.unwrap();
+        drop(device);
+        other.get_state().await.unwrap();
+        drop(other);
+        let mut driver = tokio::time::timeout(Duration::from_secs(1), task)
+            .await
+            .expect("the task kept running")
+            .unwrap();
+        // The driver comes back usable, in the framing the device booted in.
+        assert_eq!(driver.framing(), Framing::Line);
+        assert!(driver.get_state().await.is_ok());
+    }
+}
@@ -0,0 +1,155 @@
+//! Following a USB board across unplug and replug.
+//!
+//! The port name can change when the board comes back, its USB serial number
+//! doesn't. Ports are looked up through `tokio_serial::available_ports`,
+//! which enumerates udev on Linux.
+//!
+//! Nothing here is told when a port appears: [`wait_for_port`] lists the
+//! ports again every `poll` interval, so a board is noticed up to one
+//! interval after it's plugged in, and each listing walks udev.
+
+use crate::{DeviceCommands, DeviceDriver, DeviceState, DriverError, Transport};
+use std::io;
+use std::time::Duration;
+use tokio::time;
+use tokio_serial::{SerialPortInfo, SerialPortType};
+
+/// How often [`wait_for_port`] looks for the board by default.
+pub const DEFAULT_POLL: Duration = Duration::from_millis(500);
+
+/// Ports that appeared and went away between two listings.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PortChanges {
+    pub added: Vec<SerialPortInfo>,
+    pub removed: Vec<SerialPortInfo>,
+}
+
+/// Compares two listings. A port name that now belongs to another board
+/// counts as removed and added.
+pub fn diff_ports(before: &[SerialPortInfo], after: &[SerialPortInfo]) -> PortChanges {
+    PortChanges {
+        added: after
+            .iter()
+            .filter(|p| !before.contains(p))
+            .cloned()
+            .collect(),
+        removed: before
+            .iter()
+            .filter(|p| !after.contains(p))
+            .cloned()
+            .collect(),
+    }
+}
+
+fn usb_serial(port: &SerialPortInfo) -> Option<&str> {
+    match &port.port_type {
+        SerialPortType::UsbPort(usb) => usb.serial_number.as_deref(),
+        _ => None,
+    }
+}
+
+/// The port in `ports` of the USB device with `serial_number`.
+pub fn port_with_serial<'a>(ports: &'a [SerialPortInfo], serial_number: &str) -> Option<&'a str> {
+    ports
+        .iter()
+        .find(|p| usb_serial(p) == Some(serial_number))
+        .map(|p| p.port_name.as_str())
+}
+
+fn list_ports() -> Result<Vec<SerialPortInfo>, DriverError> {
+    Ok(tokio_serial::available_ports().map_err(io::Error::from)?)
+}
+
+/// USB serial number of the device behind `port_name`, if it's a USB port.
+pub fn serial_number_of(port_name: &str) -> Result<Option<String>, DriverError> {
+    Ok(list_ports()?
+        .iter()
+        .find(|p| p.port_name == port_name)
+        .and_then(usb_serial)
+        .map(str::to_string))
+}
+
+/// Name of the port the USB device with `serial_number` currently has.
+pub fn find_port(serial_number: &str) -> Result<Option<String>, DriverError> {
+    Ok(port_with_serial(&list_ports()?, serial_number).map(str::to_string))
+}
+
+/// Waits until the USB device with `serial_number` is plugged in and returns
+/// its port name, listing the ports every `poll`.
+pub async fn wait_for_port(serial_number: &str, poll: Duration) -> Result<String, DriverError> {
+    let mut ports = Vec::new();
+    loop {
+        let now = list_ports()?;
+        if let Some(port) = port_with_serial(&diff_ports(&ports, &now).added, serial_number) {
+            return Ok(port.to_string());
+        }
+        ports = now;
+        time::sleep(poll).await;
+    }
+}
+
+impl<T: Transport> DeviceDriver<T> {
+    /// Brings the outputs back to `state`, such as after the board was power cycled.
+    pub async fn restore(&mut self, state: &DeviceState) -> Result<(), DriverError> {
+        self.set_pwm_hz(state.pwm_frequency).await?;
+        self.set_pwm_duty(state.pwm_duty).await?;
+        let pwm = if state.pwm_enabled {
+            DeviceCommands::PwmOn
+        } else {
+            DeviceCommands::PwmOff
+        };
+        self.handle_command(pwm).await?;
+        if state.led_on {
+            self.set_gpio().await?;
+        } else {
+            self.clear_gpio().await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_serial::UsbPortInfo;
+
+    fn usb(name: &str, serial: &str) -> SerialPortInfo {
+        SerialPortInfo {
+            port_name: name.to_string(),
+            port_type: SerialPortType::UsbPort(UsbPortInfo {
+                vid: 0x0483,
+                pid: 0x374b,
+                serial_number: Some(serial.to_string()),
+                manufacturer: None,
+                product: None,
+            }),
+        }
+    }
+
+    fn pty(name: &str) -> SerialPortInfo {
+        SerialPortInfo {
+            port_name: name.to_string(),
+            port_type: SerialPortType::Unknown,
+        }
+    }
+
+    #[test]
+    fn diffs_listings() {
+        let before = [usb("/dev/ttyACM0", "A"), pty("/dev/pts/3")];
+        assert_eq!(diff_ports(&before, &before), PortChanges::default());
+
+        // Board A unplugged, B takes its name and A comes back under another.
+        let after = [
+            pty("/dev/pts/3"),
+            usb("/dev/ttyACM0", "B"),
+            usb("/dev/ttyACM1", "A"),
+        ];
+        let changes = diff_ports(&before, &after);
+        assert_eq!(changes.added, [after[1].clone(), after[2].clone()]);
+        assert_eq!(changes.removed, [before[0].clone()]);
+        assert_eq!(port_with_serial(&changes.added, "A"), Some("/dev/ttyACM1"));
+        assert_eq!(port_with_serial(&changes.added, "C"), None);
+
+        assert_eq!(diff_ports(&after, &[]).removed.len(), 3);
+    }
+}
@@ -0,0 +1,224 @@
+//! HTTP/JSON API for test automation written in languages that can't link the driver.
+//!
+//! - `GET /state`, `GET /time` and `GET /info` read the device.
+//! - `POST /pwm` with any of `{"duty": 40, "freq": 1000, "enabled": true}` changes the PWM output.
+//! - `POST /gpio` with `{"on": true}` switches the LED.
+//! - `POST /command` takes any command, such as `{"PwmDuty": 40}` or `"GetTime"`,
+//!   and returns the device's response.
+//!
+//! `/pwm` and `/gpio` answer with the state the device ended up in. A `/pwm`
+//! field out of range fails the request before anything is sent. Requests are
+//! handled one at a time, so a multi-command request is never interleaved with
+//! another. Errors come back as `{"error": "..."}` with a 4xx or 5xx status.
+
+use crate::{DeviceCommands, DeviceHandle, DeviceInfo, DeviceResponses, DeviceState, DriverError};
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::{get, post};
+use axum::{Json, Router};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+/// What every request goes through.
+#[derive(Clone)]
+struct Api {
+    device: DeviceHandle,
+    /// Held for the whole request.
+    busy: Arc<Mutex<()>>,
+}
+
+struct ApiError(DriverError);
+
+impl From<DriverError> for ApiError {
+    fn from(e: DriverError) -> Self {
+        ApiError(e)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = match self.0 {
+            DriverError::OutOfRange(_) | DriverError::Rejected(_) | DriverError::Unsupported(_) => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            DriverError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            DriverError::Io(_) | DriverError::PortClosed => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::BAD_GATEWAY,
+        };
+        (status, Json(json!({ "error": self.0.to_string() }))).into_response()
+    }
+}
+
+type ApiResult<T> = Result<Json<T>, ApiError>;
+
+#[derive(Deserialize)]
+struct Pwm {
+    duty: Option<u8>,
+    freq: Option<u32>,
+    enabled: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct Gpio {
+    on: bool,
+}
+
+/// The routes above, serving `device`.
+pub fn router(device: DeviceHandle) -> Router {
+    Router::new()
+        .route("/state", get(state))
+        .route("/time", get(time))
+        .route("/info", get(identity))
+        .route("/pwm", post(pwm))
+        .route("/gpio", post(gpio))
+        .route("/command", post(command))
+        .with_state(Api {
+            device,
+            busy: Arc::new(Mutex::new(())),
+        })
+}
+
+async fn read_state(device: &DeviceHandle) -> Result<DeviceState, DriverError> {
+    match device.get_state().await? {
+        DeviceResponses::State(s) => Ok(s),
+        other => Err(DriverError::MalformedResponse(format!("{:?}", other))),
+    }
+}
+
+async fn state(State(api): State<Api>) -> ApiResult<DeviceState> {
+    let _busy = api.busy.lock().await;
+    Ok(Json(read_state(&api.device).await?))
+}
+
+async fn time(State(api): State<Api>) -> ApiResult<Value> {
+    let _busy = api.busy.lock().await;
+    match api.device.get_time().await? {
+        DeviceResponses::Time(ms) => Ok(Json(json!({ "time_ms": ms }))),
+        other => Err(DriverError::MalformedResponse(format!("{:?}", other)).into()),
+    }
+}
+
+async fn identity(State(api): State<Api>) -> ApiResult<DeviceInfo> {
+    let _busy = api.busy.lock().await;
+    match api.device.info().copied() {
+        Some(info) => Ok(Json(info)),
+        None => Ok(Json(api.device.identify().await?)),
+    }
+}
+
+async fn pwm(State(api): State<Api>, Json(pwm): Json<Pwm>) -> ApiResult<DeviceState> {
+    let _busy = api.busy.lock().await;
+    let mut commands = Vec::new();
+    if let Some(hz) = pwm.freq {
+        commands.push(DeviceCommands::PwmSetFreq(hz));
+    }
+    if let Some(duty) = pwm.duty {
+        commands.push(DeviceCommands::PwmDuty(duty));
+    }
+    match pwm.enabled {
+        Some(true) => commands.push(DeviceCommands::PwmOn),
+        Some(false) => commands.push(DeviceCommands::PwmOff),
+        None => {}
+    }
+    // All checked before any is sent, so a bad field doesn't leave the others applied.
+    if let Some(bad) = commands.iter().find(|c| !c.in_range()) {
+        return Err(DriverError::OutOfRange(*bad).into());
+    }
+    for command in commands {
+        api.device.handle_command(command).await?;
+    }
+    Ok(Json(read_state(&api.device).await?))
+}
+
+async fn gpio(State(api): State<Api>, Json(gpio): Json<Gpio>) -> ApiResult<DeviceState> {
+    let _busy = api.busy.lock().await;
+    match gpio.on {
+        true => api.device.set_gpio().await?,
+        false => api.device.clear_gpio().await?,
+    };
+    Ok(Json(read_state(&api.device).await?))
+}
+
+async fn command(
+    State(api): State<Api>,
+    Json(command): Json<DeviceCommands>,
+) -> ApiResult<DeviceResponses> {
+    let _busy = api.busy.lock().await;
+    Ok(Json(api.device.handle_command(command).await?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DeviceDriver;
+    use axum::body::{to_bytes, Body};
+    use axum::http::{header, Method, Request};
+    use tower::ServiceExt;
+
+    async fn start() -> Router {
+        let (client, _sim) = iced_sim::spawn(256);
+        let mut driver = DeviceDriver::new(client);
+        driver.handshake().await.unwrap();
+        router(driver.spawn().0)
+    }
+
+    /// Sends one request and returns the status and the JSON it answered with.
+    async fn send(app: &Router, method: Method, uri: &str, body: &str) -> (StatusCode, Value) {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or(Value::Null))
+    }
+
+    #[tokio::test]
+    async fn reads_the_state() {
+        let app = start().await;
+        let (status, state) = send(&app, Method::GET, "/state", "").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(state["pwm_duty"], 25);
+        assert_eq!(state["led_on"], false);
+    }
+
+    #[tokio::test]
+    async fn sends_commands() {
+        let app = start().await;
+        let (status, reply) = send(&app, Method::POST, "/command", r#"{"PwmDuty": 40}"#).await;
+        assert_eq!(status, StatusCode::OK, "{}", reply);
+        let (_, state) = send(&app, Method::GET, "/state", "").await;
+        assert_eq!(state["pwm_duty"], 40);
+
+        let (status, state) = send(&app, Method::POST, "/gpio", r#"{"on": true}"#).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(state["led_on"], true);
+    }
+
+    #[tokio::test]
+    async fn refuses_bad_requests() {
+        let app = start().await;
+        for body in [r#"{"Nope": 1}"#, "{", r#"{"duty": "high"}"#] {
+            let (status, _) = send(&app, Method::POST, "/command", body).await;
+            assert!(status.is_client_error(), "{} gave {}", body, status);
+        }
+        let (status, error) = send(&app, Method::POST, "/pwm", r#"{"duty": 101}"#).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(error["error"].is_string());
+        let (_, state) = send(&app, Method::GET, "/state", "").await;
+        assert_eq!(state["pwm_duty"], 25);
+
+        // Nothing of a request with one bad field is applied.
+        let body = r#"{"freq": 2000, "duty": 101}"#;
+        let (status, _) = send(&app, Method::POST, "/pwm", body).await;
+        assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+        let (_, state) = send(&app, Method::GET, "/state", "").await;
+        assert_eq!(state["pwm_frequency"], 1000);
+    }
+}
@@ -1,140 +1,320 @@
-use bytes::BytesMut;
-use futures::stream::StreamExt;
-use std::fmt::Write;
-use std::{io, str};
+pub mod bridge;
+pub mod capture;
+pub mod codec;
+pub mod discovery;
+pub mod error;
+pub mod handle;
+pub mod hotplug;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod manager;
+mod modbus;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+pub mod runner;
+mod scpi;
+pub mod script;
+pub mod transport;
+
+use codec::FrameCodec;
+use futures::sink::SinkExt;
+use futures::stream::{self, BoxStream, StreamExt};
+use iced_protocol::framing::BINARY_DELIMITER;
+use iced_protocol::sequence::split_seq;
+use iced_protocol::{Event, Response};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::Duration;
 use tokio::io::AsyncWriteExt;
-use tokio_serial::SerialStream;
-use tokio_util::codec::{Decoder, Encoder};
-
-struct LineCodec;
-
-impl Decoder for LineCodec {
-    type Item = String;
-    type Error = io::Error;
-
-    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        let newline = src.as_ref().iter().position(|b| *b == b'\n');
-        if let Some(n) = newline {
-            let line = src.split_to(n + 1);
-            return match str::from_utf8(line.as_ref()) {
-                Ok(s) => Ok(Some(s.to_string())),
-                Err(_) => Err(io::Error::new(io::ErrorKind::Other, "Invalid String")),
-            };
-        }
-        Ok(None)
-    }
-}
+use tokio::sync::broadcast;
+use tokio::time::{self, Instant};
+use tokio_util::codec::{Decoder, Framed};
 
-impl Encoder<String> for LineCodec {
-    type Error = io::Error;
+pub use discovery::{Discovery, FoundDevice};
+pub use error::DriverError;
+pub use handle::DeviceHandle;
+pub use iced_protocol::{
+    Command as DeviceCommands, DeviceInfo, DeviceState, ErrorCode, Event as DeviceEvent, Fault,
+    Framing, Seq, PROTOCOL_VERSION,
+};
+pub use manager::DeviceManager;
+pub use transport::{open_port, open_serial, Port, SerialPortParams, Transport, SOCKET_SCHEME};
 
-    fn encode(&mut self, _item: String, _dst: &mut BytesMut) -> Result<(), Self::Error> {
-        Ok(())
-    }
+/// Default time to wait for a reply before giving up on an attempt.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(1);
+/// Default number of extra attempts for idempotent commands that time out.
+pub const DEFAULT_RETRIES: u32 = 2;
+/// Events buffered per subscriber before the oldest are dropped.
+pub const EVENT_CAPACITY: usize = 64;
+
+pub struct DeviceDriver<T = Port> {
+    port: Framed<T, FrameCodec>,
+    timeout: Duration,
+    command_timeouts: HashMap<u8, Duration>,
+    retries: u32,
+    next_seq: Seq,
+    discarded: u64,
+    info: Option<DeviceInfo>,
+    events: broadcast::Sender<DeviceEvent>,
+    dialect: Dialect,
 }
 
-pub struct DeviceDriver {
-    port: SerialStream,
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DeviceResponses {
+    Success,
+    Time(u32),
+    State(DeviceState),
+    Identity(DeviceInfo),
 }
 
-#[derive(Debug, Copy, Clone)]
-pub enum DeviceCommands {
-    PwmOn,
-    PwmOff,
-    PwmDuty(u8),
-    PwmSetFreq(u32),
-    SetGpioPin,
-    ClearGpioPin,
-    GetTime,
-    GetState,
+pub type DeviceResponse = Result<DeviceResponses, DriverError>;
+
+/// What the driver speaks to the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Dialect {
+    /// The single-letter commands of the line protocol, in the current framing.
+    Native,
+    /// Modbus RTU to the slave at this address.
+    Modbus(u8),
+    Scpi,
 }
 
-#[derive(Debug, Copy, Clone)]
-pub enum DeviceResponses {
-    Success,
-    Error,
-    Time(u32)
+impl DeviceDriver<Port> {
+    /// Opens the named serial port with `params` and wraps it in a driver.
+    /// A `socket://host:port` path connects to a bridge instead.
+    ///
+    /// `params.timeout` becomes the default reply timeout.
+    pub async fn open(path: &str, params: &SerialPortParams) -> Result<Self, DriverError> {
+        Ok(Self::new(open_port(path, params).await?).with_timeout(params.timeout))
+    }
 }
 
-// pub type DeviceResponse = Option<Result<String, std::io::Error>>;
-pub type DeviceResponse = Option<Result<DeviceResponses, std::io::Error>>;
+impl<T: Transport> DeviceDriver<T> {
+    pub fn new(port: T) -> Self {
+        Self {
+            port: FrameCodec::new(Framing::Line).framed(port),
+            timeout: DEFAULT_TIMEOUT,
+            command_timeouts: HashMap::new(),
+            retries: DEFAULT_RETRIES,
+            next_seq: 0,
+            discarded: 0,
+            info: None,
+            events: broadcast::channel(EVENT_CAPACITY).0,
+            dialect: Dialect::Native,
+        }
+    }
 
-impl DeviceDriver {
-    pub fn new(port: SerialStream) -> Self {
-        Self { port }
+    /// Sets how long to wait for a reply to commands without their own timeout.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
     }
 
-    pub async fn close(self) -> SerialStream {
-        self.port
+    /// Sets the reply timeout for every command of the same kind as `command`,
+    /// whatever its argument.
+    pub fn with_command_timeout(mut self, command: DeviceCommands, timeout: Duration) -> Self {
+        self.command_timeouts.insert(command.tag(), timeout);
+        self
     }
 
-    fn parse_response(&self, buffer: String) -> DeviceResponses {
-        if let Some(c) = buffer.chars().nth(0) {
-            match c {
-                'X' => DeviceResponses::Error,
-                'T' => {
-                    if let Ok(t) = buffer[1..buffer.len()-1].parse::<u32>() {
-                        DeviceResponses::Time(t)
-                    } else {
-                        DeviceResponses::Error
-                    }
-                },
-                _ => DeviceResponses::Success
+    /// Sets how many times an idempotent command is resent after a timeout.
+    pub fn with_retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Speaks Modbus RTU to slave `unit` instead of the device's own protocol,
+    /// for firmware built with the `modbus` feature.
+    pub fn with_modbus(mut self, unit: u8) -> Self {
+        self.dialect = Dialect::Modbus(unit);
+        self
+    }
+
+    /// Speaks SCPI instead of the single-letter commands. Settings are each
+    /// followed by `SYST:ERR?` to learn whether the device took them.
+    pub fn with_scpi(mut self) -> Self {
+        self.dialect = Dialect::Scpi;
+        self
+    }
+
+    /// The Modbus slave address, if the driver speaks Modbus.
+    pub fn modbus_unit(&self) -> Option<u8> {
+        match self.dialect {
+            Dialect::Modbus(unit) => Some(unit),
+            _ => None,
+        }
+    }
+
+    pub fn timeout_for(&self, command: &DeviceCommands) -> Duration {
+        self.command_timeouts
+            .get(&command.tag())
+            .copied()
+            .unwrap_or(self.timeout)
+    }
+
+    /// What the device reported during [`DeviceDriver::handshake`].
+    pub fn info(&self) -> Option<&DeviceInfo> {
+        self.info.as_ref()
+    }
+
+    pub fn framing(&self) -> Framing {
+        self.port.codec().framing()
+    }
+
+    /// Number of frames thrown away: replies to earlier commands that arrived
+    /// too late, replies left over from another session, frames that carry no
+    /// sequence number at all and anything received while idle in a spawned task.
+    pub fn discarded_replies(&self) -> u64 {
+        self.discarded
+    }
+
+    /// Events the device sends on its own from now on. They only arrive while
+    /// the driver reads the port, so use a spawned driver to get them between commands.
+    pub fn events(&self) -> BoxStream<'static, DeviceEvent> {
+        event_stream(self.events.subscribe())
+    }
+
+    /// Hands an event frame to the subscribers, if it's valid.
+    fn dispatch_event(&mut self, message: &[u8]) {
+        match Event::decode(message) {
+            // Nobody listening isn't an error.
+            Ok(event) => {
+                let _ = self.events.send(event);
             }
-        } else {
-            DeviceResponses::Error
+            Err(_) => self.discarded += 1,
         }
     }
 
-    async fn write_command(&mut self, buffer: &[u8]) -> Result<usize, std::io::Error> {
-        self.port.write(&buffer).await
+    /// Puts the device back in line framing, so the next session finds it the
+    /// way it booted, and gives the port back.
+    pub async fn close(mut self) -> T {
+        self.restore_line_framing().await;
+        self.port.into_inner()
     }
 
-    async fn read_response(&mut self) -> Option<Result<String, std::io::Error>> {
-        let mut reader = LineCodec.framed(&mut self.port);
-        let res = reader.next().await;
-        res
+    /// Best effort, the port may already be gone.
+    pub(crate) async fn restore_line_framing(&mut self) {
+        if self.dialect == Dialect::Native && self.framing() != Framing::Line {
+            let _ = self.set_framing(Framing::Line).await;
+        }
+    }
+
+    fn parse_response(
+        &self,
+        command: DeviceCommands,
+        message: &[u8],
+    ) -> Result<DeviceResponses, DriverError> {
+        let malformed = || DriverError::MalformedResponse(String::from_utf8_lossy(message).into());
+        let response = Response::decode(message).map_err(|_| malformed())?;
+        match (command, response) {
+            (_, Response::Error(code)) => Err(DriverError::Rejected(code)),
+            (DeviceCommands::GetTime, Response::Time(t)) => Ok(DeviceResponses::Time(t)),
+            (DeviceCommands::GetState, Response::State(s)) => Ok(DeviceResponses::State(s)),
+            (DeviceCommands::Identify, Response::Identity(i)) => Ok(DeviceResponses::Identity(i)),
+            (cmd, Response::Ack(echo)) if cmd == echo => Ok(DeviceResponses::Success),
+            (sent, received) => Err(DriverError::Mismatch { sent, received }),
+        }
+    }
+
+    /// Waits up to `timeout` for the frame carrying `seq`, discarding stale ones.
+    async fn read_reply(
+        &mut self,
+        seq: Seq,
+        command: DeviceCommands,
+        timeout: Duration,
+    ) -> DeviceResponse {
+        let deadline = Instant::now() + timeout;
+        loop {
+            let message = match time::timeout_at(deadline, self.port.next()).await {
+                Ok(Some(Ok(Ok(message)))) => message,
+                Ok(Some(Ok(Err(e)))) => return Err(DriverError::Corrupt(e)),
+                Ok(Some(Err(e))) => return Err(e.into()),
+                Ok(None) => return Err(DriverError::PortClosed),
+                Err(_elapsed) => return Err(DriverError::Timeout),
+            };
+            if Event::is_event(&message) {
+                self.dispatch_event(&message);
+                continue;
+            }
+            match split_seq(&message) {
+                Ok((Some(s), reply)) if s == seq => return self.parse_response(command, reply),
+                // A corrupt frame's number can't be read, so the device can't echo it.
+                Ok((None, reply))
+                    if Response::decode(reply) == Ok(Response::Error(ErrorCode::Checksum)) =>
+                {
+                    return Err(DriverError::Rejected(ErrorCode::Checksum))
+                }
+                _ => self.discarded += 1,
+            }
+        }
+    }
+
+    /// Waits for a frame while no command is in flight. Only events are
+    /// expected, anything else is discarded. Fails once the port is gone.
+    pub(crate) async fn read_idle(&mut self) -> Result<(), DriverError> {
+        if let Dialect::Modbus(_) = self.dialect {
+            return self.modbus_read_idle().await;
+        }
+        match self.port.next().await {
+            Some(Ok(Ok(message))) if Event::is_event(&message) => {
+                self.dispatch_event(&message);
+                Ok(())
+            }
+            Some(Ok(_)) => {
+                self.discarded += 1;
+                Ok(())
+            }
+            Some(Err(e)) => Err(e.into()),
+            None => Err(DriverError::PortClosed),
+        }
     }
 
     pub async fn handle_command(&mut self, command: DeviceCommands) -> DeviceResponse {
-        let mut buff_out = String::new();
-        match command {
-            DeviceCommands::SetGpioPin => {
-                let _ = write!(buff_out, "P\n");
-            },
-            DeviceCommands::ClearGpioPin => {
-                let _ = write!(buff_out, "C\n");
-            },
-            DeviceCommands::PwmOn => {
-                let _ = write!(buff_out, "E\n");
-            },
-            DeviceCommands::PwmOff => {
-                let _ = write!(buff_out, "O\n");
-            },
-            DeviceCommands::PwmDuty(duty) => {
-                let _ = write!(buff_out, "D{}\n", duty);
-            },
-            DeviceCommands::PwmSetFreq(hz) => {
-                let _ = write!(buff_out, "F{}\n", hz);
-            },
-            DeviceCommands::GetTime => {
-                let _ = write!(buff_out, "T\n");
-            },
-            _ => (),
-        }
-        let wresult = self.write_command(&buff_out.as_bytes()).await;
-        match wresult {
-            Ok(_bytesout) => (),
-            Err(e) => return Some(Err(e)),
-        };
-        if let Some(resp) = self.read_response().await {
-            match resp {
-                Ok(s) => Some(Ok(self.parse_response(s))),
-                Err(e) => Some(Err(e))
+        if !command.in_range() {
+            return Err(DriverError::OutOfRange(command));
+        }
+        if let Some(info) = &self.info {
+            if !info.capabilities.supports(&command) {
+                return Err(DriverError::Unsupported(command));
             }
+        }
+        let timeout = self.timeout_for(&command);
+        let attempts = if command.is_idempotent() {
+            self.retries + 1
         } else {
-            None
+            1
+        };
+        match self.dialect {
+            Dialect::Native => (),
+            Dialect::Modbus(unit) => {
+                return self.modbus_command(unit, command, timeout, attempts).await
+            }
+            Dialect::Scpi => return self.scpi_command(command, timeout, attempts).await,
         }
+        // Retries reuse the number, a late reply to an earlier attempt is just as good.
+        let seq = self.next_seq;
+        self.next_seq = self.next_seq.wrapping_add(1);
+        let mut failure = DriverError::Timeout;
+        for _ in 0..attempts {
+            self.port.send((seq, command)).await?;
+            failure = match self.read_reply(seq, command, timeout).await {
+                Ok(resp) => {
+                    if let DeviceCommands::SetFraming(framing) = command {
+                        *self.port.codec_mut() = FrameCodec::new(framing);
+                    }
+                    return Ok(resp);
+                }
+                // A checksum rejection means the device never ran the command.
+                // After a timeout or a corrupt reply it may well have, sending
+                // again is only safe because `attempts` is 1 unless the
+                // command is idempotent.
+                Err(e @ DriverError::Rejected(ErrorCode::Checksum))
+                | Err(e @ DriverError::Corrupt(_))
+                | Err(e @ DriverError::Timeout) => e,
+                Err(e) => return Err(e),
+            };
+        }
+        Err(failure)
     }
 
     pub async fn set_gpio(&mut self) -> DeviceResponse {
@@ -156,4 +336,274 @@ impl DeviceDriver {
     pub async fn get_time(&mut self) -> DeviceResponse {
         self.handle_command(DeviceCommands::GetTime).await
     }
+
+    /// Time since the device booted, read from its millisecond SysTick counter.
+    pub async fn get_uptime(&mut self) -> Result<Duration, DriverError> {
+        match self.get_time().await? {
+            DeviceResponses::Time(ms) => Ok(Duration::from_millis(ms.into())),
+            r => Err(DriverError::MalformedResponse(format!("{:?}", r))),
+        }
+    }
+
+    pub async fn get_state(&mut self) -> DeviceResponse {
+        self.handle_command(DeviceCommands::GetState).await
+    }
+
+    /// Asks for an event with the device state every `period`, zero stops them.
+    pub async fn set_telemetry(&mut self, period: Duration) -> DeviceResponse {
+        self.handle_command(DeviceCommands::SetTelemetry(telemetry_period(period)))
+            .await
+    }
+
+    pub async fn identify(&mut self) -> Result<DeviceInfo, DriverError> {
+        match self.handle_command(DeviceCommands::Identify).await? {
+            DeviceResponses::Identity(info) => Ok(info),
+            r => Err(DriverError::MalformedResponse(format!("{:?}", r))),
+        }
+    }
+
+    /// Checks the other end speaks a compatible version of the protocol.
+    ///
+    /// Afterwards commands the device didn't list as supported fail with
+    /// [`DriverError::Unsupported`] without being sent.
+    ///
+    /// A device that doesn't answer in lines may still be in binary framing
+    /// from an earlier session, so binary is tried before giving up.
+    pub async fn handshake(&mut self) -> Result<DeviceInfo, DriverError> {
+        let info = match self.identify().await {
+            Err(DriverError::Timeout)
+                if self.dialect == Dialect::Native && self.framing() == Framing::Line =>
+            {
+                self.identify_in_binary()
+                    .await
+                    .map_err(|_| DriverError::Timeout)?
+            }
+            result => result?,
+        };
+        if !info.is_compatible() {
+            return Err(DriverError::Incompatible(info));
+        }
+        self.info = Some(info);
+        Ok(info)
+    }
+
+    /// Identifies a device left in binary framing, the driver is back in
+    /// lines if it doesn't answer there either.
+    async fn identify_in_binary(&mut self) -> Result<DeviceInfo, DriverError> {
+        // Ends the unanswered lines as one bad frame, its error reply has no number and is discarded.
+        self.port.get_mut().write_all(&[BINARY_DELIMITER]).await?;
+        *self.port.codec_mut() = FrameCodec::new(Framing::Binary);
+        let info = self.identify().await;
+        if info.is_err() {
+            *self.port.codec_mut() = FrameCodec::new(Framing::Line);
+        }
+        info
+    }
+
+    /// Switches both ends to `framing`.
+    pub async fn set_framing(&mut self, framing: Framing) -> DeviceResponse {
+        self.handle_command(DeviceCommands::SetFraming(framing))
+            .await
+    }
+
+    /// Switches to `preferred` framing if the device supports it, firmware that
+    /// doesn't know the command rejects it and the current framing is kept.
+    pub async fn negotiate_framing(&mut self, preferred: Framing) -> Result<Framing, DriverError> {
+        match self.set_framing(preferred).await {
+            Ok(_) | Err(DriverError::Rejected(_)) | Err(DriverError::Unsupported(_)) => {
+                Ok(self.framing())
+            }
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// Milliseconds to ask for in `SetTelemetry`, saturating at the longest period the device supports.
+pub(crate) fn telemetry_period(period: Duration) -> u16 {
+    period.as_millis().try_into().unwrap_or(u16::MAX)
+}
+
+/// Turns a subscription into a stream, skipping over events a slow reader missed.
+pub(crate) fn event_stream(
+    rx: broadcast::Receiver<DeviceEvent>,
+) -> BoxStream<'static, DeviceEvent> {
+    stream::unfold(rx, |mut rx| async move {
+        loop {
+            match rx.recv().await {
+                Ok(event) => return Some((event, rx)),
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    })
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    /// A simulator behind a link that loses its first `lost` replies, and
+    /// the count of lines the driver sent over it.
+    fn lossy_sim(lost: usize) -> (DuplexStream, Arc<AtomicUsize>) {
+        let (client, mut link) = tokio::io::duplex(256);
+        let (mut device, _sim) = iced_sim::spawn(256);
+        let sent = Arc::new(AtomicUsize::new(0));
+        let counter = sent.clone();
+        tokio::spawn(async move {
+            let mut dropped = 0;
+            let mut up = [0u8; 256];
+            let mut down = [0u8; 256];
+            loop {
+                tokio::select! {
+                    n = link.read(&mut up) => {
+                        let n = match n {
+                            Ok(0) | Err(_) => break,
+                            Ok(n) => n,
+                        };
+                        let lines = up[..n].iter().filter(|b| **b == b'\n').count();
+                        counter.fetch_add(lines, Ordering::SeqCst);
+                        if device.write_all(&up[..n]).await.is_err() {
+                            break;
+                        }
+                    }
+                    n = device.read(&mut down) => {
+                        let n = match n {
+                            Ok(0) | Err(_) => break,
+                            Ok(n) => n,
+                        };
+                        if dropped < lost {
+                            dropped += 1;
+                        } else if link.write_all(&down[..n]).await.is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+        (client, sent)
+    }
+
+    #[tokio::test]
+    async fn retries_after_a_timeout() {
+        let (link, sent) = lossy_sim(1);
+        let mut driver = DeviceDriver::new(link).with_timeout(Duration::from_millis(100));
+        assert!(matches!(
+            driver.get_state().await,
+            Ok(DeviceResponses::State(_))
+        ));
+        assert_eq!(sent.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn times_out_after_every_attempt() {
+        let (link, sent) = lossy_sim(usize::MAX);
+        let mut driver = DeviceDriver::new(link)
+            .with_timeout(Duration::from_millis(50))
+            .with_retries(2);
+        assert!(matches!(
+            driver.get_state().await,
+            Err(DriverError::Timeout)
+        ));
+        assert_eq!(sent.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn uses_the_command_timeout() {
+        let (link, _) = lossy_sim(usize::MAX);
+        let mut driver = DeviceDriver::new(link)
+            .with_timeout(Duration::from_secs(10))
+            .with_command_timeout(DeviceCommands::GetTime, Duration::from_millis(50))
+            .with_retries(0);
+        let started = Instant::now();
+        assert!(matches!(driver.get_time().await, Err(DriverError::Timeout)));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn discards_stale_replies() {
+        let (client, mut device) = tokio::io::duplex(256);
+        let mut driver = DeviceDriver::new(client);
+        let fake = tokio::spawn(async move {
+            let mut line = [0u8; 16];
+            let n = device.read(&mut line).await.unwrap();
+            assert_eq!(&line[..n], b"0:T\n");
+            // A late reply to an earlier command, a line with no number, then the reply.
+            device.write_all(b"7:T5\nT6\n0:T42\n").await.unwrap();
+            device
+        });
+        assert!(matches!(
+            driver.get_time().await,
+            Ok(DeviceResponses::Time(42))
+        ));
+        assert_eq!(driver.discarded_replies(), 2);
+        fake.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn routes_events_that_arrive_before_a_reply() {
+        let (client, _sim) = iced_sim::spawn(256);
+        let mut driver = DeviceDriver::new(client);
+        let mut events = driver.events();
+        driver
+            .set_telemetry(Duration::from_millis(5))
+            .await
+            .unwrap();
+        // Telemetry piles up in the pipe while nothing reads, the next
+        // command has to read past it to its reply.
+        time::sleep(Duration::from_millis(30)).await;
+        assert!(matches!(
+            driver.get_state().await,
+            Ok(DeviceResponses::State(_))
+        ));
+        assert!(matches!(
+            time::timeout(Duration::from_secs(1), events.next()).await,
+            Ok(Some(DeviceEvent::Telemetry(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn does_not_resend_set_framing() {
+        let (link, sent) = lossy_sim(1);
+        let mut driver = DeviceDriver::new(link).with_timeout(Duration::from_millis(100));
+        assert!(matches!(
+            driver.set_framing(Framing::Binary).await,
+            Err(DriverError::Timeout)
+        ));
+        // The device switched, a repeat in lines would have gone unanswered.
+        assert_eq!(sent.load(Ordering::SeqCst), 1);
+        assert_eq!(driver.framing(), Framing::Line);
+        // The next handshake finds it there.
+        driver.handshake().await.unwrap();
+        assert_eq!(driver.framing(), Framing::Binary);
+    }
+
+    #[tokio::test]
+    async fn finds_a_device_left_in_binary() {
+        let (client, _sim) = iced_sim::spawn(256);
+        let mut driver = DeviceDriver::new(client);
+        driver.set_framing(Framing::Binary).await.unwrap();
+        // Gone without closing, as when the program is killed.
+        let port = driver.port.into_inner();
+
+        let mut driver = DeviceDriver::new(port).with_timeout(Duration::from_millis(100));
+        driver.handshake().await.unwrap();
+        assert_eq!(driver.framing(), Framing::Binary);
+        assert!(driver.get_state().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn close_returns_to_line_framing() {
+        let (client, _sim) = iced_sim::spawn(256);
+        let mut driver = DeviceDriver::new(client);
+        driver.set_framing(Framing::Binary).await.unwrap();
+        let port = driver.close().await;
+
+        let mut driver = DeviceDriver::new(port).with_timeout(Duration::from_millis(100));
+        driver.handshake().await.unwrap();
+        assert_eq!(driver.framing(), Framing::Line);
+    }
 }
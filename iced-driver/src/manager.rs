@@ -0,0 +1,231 @@
+//! Several boards at once, each under a name, for test racks.
+//!
+//! Every board runs in its own driver task behind a [`DeviceHandle`], so a
+//! command sent to many of them goes out to all at the same time and one slow
+//! or lost board doesn't hold up the others. Boards can be put in named groups
+//! to address part of the rack.
+
+use crate::{
+    DeviceCommands, DeviceDriver, DeviceHandle, DeviceInfo, DeviceResponse, DeviceResponses,
+    DeviceState, DriverError, SerialPortParams,
+};
+use futures::future;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// How one board is doing.
+#[derive(Debug, Clone)]
+pub struct DeviceStatus {
+    pub name: String,
+    /// What it reported during the handshake.
+    pub info: Option<DeviceInfo>,
+    /// False once its driver task stopped, the port is gone.
+    pub connected: bool,
+    /// Its state, or why it couldn't be read.
+    pub state: Result<DeviceState, String>,
+}
+
+/// Named handles to boards, in name order.
+#[derive(Clone, Default)]
+pub struct DeviceManager {
+    devices: BTreeMap<String, DeviceHandle>,
+    groups: BTreeMap<String, BTreeSet<String>>,
+}
+
+impl DeviceManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a board under `name`, returning the one it replaces.
+    pub fn add(&mut self, name: impl Into<String>, device: DeviceHandle) -> Option<DeviceHandle> {
+        self.devices.insert(name.into(), device)
+    }
+
+    /// Opens the port at `path`, checks the board speaks our protocol and
+    /// adds it under `name`.
+    pub async fn open(
+        &mut self,
+        name: impl Into<String>,
+        path: &str,
+        params: &SerialPortParams,
+    ) -> Result<&DeviceHandle, DriverError> {
+        let mut driver = DeviceDriver::open(path, params).await?;
+        driver.handshake().await?;
+        let (device, _) = driver.spawn();
+        let name = name.into();
+        self.devices.insert(name.clone(), device);
+        Ok(&self.devices[&name])
+    }
+
+    /// Drops the board from the manager and its groups. Its driver task stops
+    /// once no other handle to it is left.
+    pub fn remove(&mut self, name: &str) -> Option<DeviceHandle> {
+        for members in self.groups.values_mut() {
+            members.remove(name);
+        }
+        self.devices.remove(name)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&DeviceHandle> {
+        self.devices.get(name)
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.devices.keys().map(String::as_str)
+    }
+
+    pub fn len(&self) -> usize {
+        self.devices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+
+    /// Puts the board `name` in `group`, false if there's no such board.
+    pub fn add_to_group(&mut self, group: &str, name: &str) -> bool {
+        if !self.devices.contains_key(name) {
+            return false;
+        }
+        self.groups
+            .entry(group.to_string())
+            .or_default()
+            .insert(name.to_string());
+        true
+    }
+
+    pub fn remove_from_group(&mut self, group: &str, name: &str) {
+        if let Some(members) = self.groups.get_mut(group) {
+            members.remove(name);
+        }
+    }
+
+    /// The boards in `group`, none if there's no such group.
+    pub fn group(&self, group: &str) -> impl Iterator<Item = &str> {
+        self.groups
+            .get(group)
+            .into_iter()
+            .flatten()
+            .map(String::as_str)
+    }
+
+    /// Sends `command` to every board at once, each reply under its board's name.
+    pub async fn broadcast(&self, command: DeviceCommands) -> Vec<(String, DeviceResponse)> {
+        self.send_to(self.devices.keys(), command).await
+    }
+
+    /// Sends `command` to every board in `group` at once.
+    pub async fn send_to_group(
+        &self,
+        group: &str,
+        command: DeviceCommands,
+    ) -> Vec<(String, DeviceResponse)> {
+        self.send_to(self.groups.get(group).into_iter().flatten(), command)
+            .await
+    }
+
+    async fn send_to<'a>(
+        &self,
+        names: impl Iterator<Item = &'a String>,
+        command: DeviceCommands,
+    ) -> Vec<(String, DeviceResponse)> {
+        let sends = names.filter_map(|name| {
+            let device = self.devices.get(name)?;
+            Some(async move { (name.clone(), device.handle_command(command).await) })
+        });
+        future::join_all(sends).await
+    }
+
+    /// Reads the state of every board at once.
+    pub async fn status(&self) -> Vec<DeviceStatus> {
+        let reads = self.devices.iter().map(|(name, device)| async move {
+            let state = match device.get_state().await {
+                Ok(DeviceResponses::State(s)) => Ok(s),
+                Ok(r) => Err(DriverError::MalformedResponse(format!("{:?}", r)).to_string()),
+                Err(e) => Err(e.to_string()),
+            };
+            DeviceStatus {
+                name: name.clone(),
+                info: device.info().copied(),
+                connected: !device.is_closed(),
+                state,
+            }
+        });
+        future::join_all(reads).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn rack(names: &[&str]) -> DeviceManager {
+        let mut manager = DeviceManager::new();
+        for name in names {
+            let (client, _sim) = iced_sim::spawn(256);
+            let mut driver = DeviceDriver::new(client);
+            driver.handshake().await.unwrap();
+            manager.add(*name, driver.spawn().0);
+        }
+        manager
+    }
+
+    fn duties(status: &[DeviceStatus]) -> Vec<(&str, u8)> {
+        status
+            .iter()
+            .map(|s| (s.name.as_str(), s.state.as_ref().unwrap().pwm_duty))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn broadcasts_to_every_board() {
+        let manager = rack(&["b", "a"]).await;
+        let replies = manager.broadcast(DeviceCommands::PwmDuty(40)).await;
+        let names: Vec<_> = replies.iter().map(|(n, _)| n.as_str()).collect();
+        assert_eq!(names, ["a", "b"]);
+        assert!(replies.iter().all(|(_, r)| r.is_ok()));
+
+        let status = manager.status().await;
+        assert_eq!(duties(&status), [("a", 40), ("b", 40)]);
+        assert!(status.iter().all(|s| s.connected && s.info.is_some()));
+    }
+
+    #[tokio::test]
+    async fn sends_to_a_group() {
+        let mut manager = rack(&["a", "b", "c"]).await;
+        assert!(manager.add_to_group("left", "a"));
+        assert!(manager.add_to_group("left", "b"));
+        assert!(!manager.add_to_group("left", "nope"));
+        manager
+            .send_to_group("left", DeviceCommands::PwmDuty(10))
+            .await;
+        assert_eq!(
+            duties(&manager.status().await),
+            [("a", 10), ("b", 10), ("c", 25)]
+        );
+
+        manager.remove("b");
+        assert_eq!(manager.group("left").collect::<Vec<_>>(), ["a"]);
+        assert!(manager
+            .send_to_group("right", DeviceCommands::PwmOn)
+            .await
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn reports_a_lost_board() {
+        let mut manager = rack(&["a"]).await;
+        let (client, sim) = iced_sim::spawn(256);
+        manager.add("b", DeviceDriver::new(client).spawn().0);
+        sim.abort();
+        let _ = sim.await;
+        while !manager.get("b").unwrap().is_closed() {
+            tokio::task::yield_now().await;
+        }
+
+        let status = manager.status().await;
+        assert!(status[0].state.is_ok());
+        assert!(!status[1].connected);
+        assert!(status[1].state.is_err());
+    }
+}
@@ -0,0 +1,304 @@
+//! The driver as a Modbus RTU master, for firmware built as a Modbus slave.
+//!
+//! [`DeviceDriver::with_modbus`] keeps the whole API, commands are carried out
+//! through the register map in [`iced_protocol::modbus`]. `GetState` takes
+//! three reads, of the coils, holding and input registers. Framing and
+//! telemetry have no Modbus equivalent and are reported as unsupported, and
+//! as a slave never speaks unasked there are no events.
+
+use crate::{DeviceCommands, DeviceDriver, DeviceResponses, DeviceState, DriverError, Transport};
+use iced_protocol::modbus::{
+    self, Reply, Request, COILS, COIL_LED, COIL_PWM, HOLDING_DUTY, HOLDING_FREQUENCY,
+    HOLDING_REGISTERS, INPUT_REGISTERS, INPUT_UPTIME, MAX_ADU,
+};
+use iced_protocol::{DeviceInfo, PROTOCOL_VERSION};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::time::{self, Instant};
+
+/// The requests that carry out `command`, `None` if Modbus can't express it.
+fn requests(command: DeviceCommands) -> Option<&'static [Request]> {
+    const STATE: &[Request] = &[
+        Request::ReadCoils {
+            start: 0,
+            count: COILS,
+        },
+        Request::ReadHoldingRegisters {
+            start: 0,
+            count: HOLDING_REGISTERS,
+        },
+        Request::ReadInputRegisters {
+            start: 0,
+            count: INPUT_REGISTERS,
+        },
+    ];
+    const TIME: &[Request] = &[Request::ReadInputRegisters {
+        start: INPUT_UPTIME,
+        count: 2,
+    }];
+    match command {
+        DeviceCommands::GetState => Some(STATE),
+        DeviceCommands::GetTime => Some(TIME),
+        DeviceCommands::Identify => Some(&[Request::ReadDeviceIdentification]),
+        DeviceCommands::SetGpioPin => Some(&[Request::WriteCoil {
+            address: COIL_LED,
+            on: true,
+        }]),
+        DeviceCommands::ClearGpioPin => Some(&[Request::WriteCoil {
+            address: COIL_LED,
+            on: false,
+        }]),
+        DeviceCommands::PwmOn => Some(&[Request::WriteCoil {
+            address: COIL_PWM,
+            on: true,
+        }]),
+        DeviceCommands::PwmOff => Some(&[Request::WriteCoil {
+            address: COIL_PWM,
+            on: false,
+        }]),
+        // Writes carry their argument, see `write`.
+        DeviceCommands::PwmDuty(_) | DeviceCommands::PwmSetFreq(_) => Some(&[]),
+        DeviceCommands::SetFraming(_) | DeviceCommands::SetTelemetry(_) => None,
+    }
+}
+
+/// The request for a command with an argument.
+fn write(command: DeviceCommands) -> Option<Request> {
+    match command {
+        DeviceCommands::PwmDuty(duty) => Some(Request::WriteRegister {
+            address: HOLDING_DUTY,
+            value: duty.into(),
+        }),
+        DeviceCommands::PwmSetFreq(hz) => Some(Request::WriteRegisters {
+            start: HOLDING_FREQUENCY,
+            count: 2,
+            values: [(hz >> 16) as u16, hz as u16, 0],
+        }),
+        _ => None,
+    }
+}
+
+fn double_word(values: &[u16]) -> u32 {
+    u32::from(values[0]) << 16 | u32::from(values[1])
+}
+
+/// Builds the driver's response to `command` from the replies to its requests.
+fn response(command: DeviceCommands, replies: &[Reply]) -> Result<DeviceResponses, DriverError> {
+    let unexpected = || DriverError::MalformedResponse(format!("{:?}", replies));
+    match (command, replies) {
+        (
+            DeviceCommands::GetState,
+            [Reply::Coils(coils), Reply::Registers {
+                values: holding, ..
+            }, Reply::Registers { values: input, .. }],
+        ) => Ok(DeviceResponses::State(DeviceState {
+            led_on: coils & 1 << COIL_LED != 0,
+            pwm_enabled: coils & 1 << COIL_PWM != 0,
+            pwm_duty: holding[usize::from(HOLDING_DUTY)] as u8,
+            pwm_frequency: double_word(&holding[usize::from(HOLDING_FREQUENCY)..]),
+            uptime_ms: double_word(input),
+        })),
+        (DeviceCommands::GetTime, [Reply::Registers { values, .. }]) => {
+            Ok(DeviceResponses::Time(double_word(values)))
+        }
+        (
+            DeviceCommands::Identify,
+            [Reply::Identification {
+                product, revision, ..
+            }],
+        ) => Ok(DeviceResponses::Identity(DeviceInfo {
+            // The register map is versioned with the line protocol.
+            protocol_version: PROTOCOL_VERSION,
+            firmware_version: *revision,
+            board: *product,
+            capabilities: modbus::capabilities(),
+        })),
+        (_, replies) if replies.iter().all(|r| *r == Reply::Written) => {
+            Ok(DeviceResponses::Success)
+        }
+        _ => Err(unexpected()),
+    }
+}
+
+impl<T: Transport> DeviceDriver<T> {
+    /// Carries out `command` on slave `unit`, each request tried `attempts` times.
+    pub(crate) async fn modbus_command(
+        &mut self,
+        unit: u8,
+        command: DeviceCommands,
+        timeout: Duration,
+        attempts: u32,
+    ) -> Result<DeviceResponses, DriverError> {
+        let requests = requests(command).ok_or(DriverError::Unsupported(command))?;
+        let mut replies = Vec::with_capacity(requests.len());
+        for request in requests.iter().copied().chain(write(command)) {
+            let mut failure = DriverError::Timeout;
+            let mut reply = None;
+            for _ in 0..attempts {
+                match self.modbus_transaction(unit, &request, timeout).await {
+                    Ok(r) => {
+                        reply = Some(r);
+                        break;
+                    }
+                    // Every request in the map is safe to repeat.
+                    Err(e @ DriverError::Timeout) | Err(e @ DriverError::Corrupt(_)) => failure = e,
+                    Err(e) => return Err(e),
+                }
+            }
+            match reply {
+                Some(Reply::Exception(e)) => return Err(DriverError::Rejected(e.into())),
+                Some(r) => replies.push(r),
+                None => return Err(failure),
+            }
+        }
+        response(command, &replies)
+    }
+
+    /// Sends one request and waits up to `timeout` for the whole reply.
+    async fn modbus_transaction(
+        &mut self,
+        unit: u8,
+        request: &Request,
+        timeout: Duration,
+    ) -> Result<Reply, DriverError> {
+        let mut frame = [0u8; MAX_ADU];
+        let n = request
+            .encode(unit, &mut frame)
+            .map_err(DriverError::Corrupt)?;
+        self.discard_pending().await?;
+        let port = self.port.get_mut();
+        port.write_all(&frame[..n]).await?;
+        port.flush().await?;
+
+        let deadline = Instant::now() + timeout;
+        let mut received = Vec::with_capacity(MAX_ADU);
+        let mut buf = [0u8; MAX_ADU];
+        let len = loop {
+            match modbus::reply_len(&received) {
+                Some(len) if len <= received.len() => break len,
+                _ if received.len() > MAX_ADU => {
+                    return Err(DriverError::Corrupt(iced_protocol::Error::InvalidFrame))
+                }
+                _ => {}
+            }
+            match time::timeout_at(deadline, port.read(&mut buf)).await {
+                Ok(Ok(0)) => return Err(DriverError::PortClosed),
+                Ok(Ok(n)) => received.extend_from_slice(&buf[..n]),
+                Ok(Err(e)) => return Err(e.into()),
+                Err(_elapsed) => return Err(DriverError::Timeout),
+            }
+        };
+        match Reply::decode(&received[..len], request) {
+            Ok((from, reply)) if from == unit => Ok(reply),
+            Ok((from, _)) => Err(DriverError::MalformedResponse(format!(
+                "reply from unit {} instead of {}",
+                from, unit
+            ))),
+            Err(e) => Err(DriverError::Corrupt(e)),
+        }
+    }
+
+    /// Throws away anything already received, such as a reply to an attempt
+    /// that timed out, so it can't be taken for the next one.
+    async fn discard_pending(&mut self) -> Result<(), DriverError> {
+        let mut buf = [0u8; MAX_ADU];
+        while let Ok(n) = time::timeout(Duration::ZERO, self.port.get_mut().read(&mut buf)).await {
+            match n? {
+                0 => return Err(DriverError::PortClosed),
+                _ => self.discarded += 1,
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads while idle, a slave has nothing to say unasked so it's all discarded.
+    pub(crate) async fn modbus_read_idle(&mut self) -> Result<(), DriverError> {
+        let mut buf = [0u8; MAX_ADU];
+        match self.port.get_mut().read(&mut buf).await? {
+            0 => Err(DriverError::PortClosed),
+            _ => {
+                self.discarded += 1;
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{DeviceCommands, DeviceDriver, DeviceResponses, DriverError};
+    use iced_protocol::modbus::{Exception, Reply, Request, DEFAULT_UNIT, HOLDING_DUTY};
+    use iced_sim::Simulator;
+    use std::time::Duration;
+
+    async fn connect() -> DeviceDriver<tokio::io::DuplexStream> {
+        let mut sim = Simulator::new().with_modbus(DEFAULT_UNIT);
+        let (client, device) = tokio::io::duplex(256);
+        tokio::spawn(async move { sim.run(device).await });
+        DeviceDriver::new(client).with_modbus(DEFAULT_UNIT)
+    }
+
+    #[tokio::test]
+    async fn drives_a_modbus_slave() {
+        let mut driver = connect().await;
+        let info = driver.handshake().await.unwrap();
+        assert_eq!(info.board.as_str(), "iced-sim");
+
+        driver.set_gpio().await.unwrap();
+        driver.set_pwm_duty(40).await.unwrap();
+        driver.set_pwm_hz(100_000).await.unwrap();
+        driver.handle_command(DeviceCommands::PwmOff).await.unwrap();
+        match driver.get_state().await.unwrap() {
+            DeviceResponses::State(s) => {
+                assert!(s.led_on);
+                assert!(!s.pwm_enabled);
+                assert_eq!(s.pwm_duty, 40);
+                assert_eq!(s.pwm_frequency, 100_000);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(driver.get_uptime().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn reports_what_modbus_cant_do() {
+        let mut driver = connect().await;
+        driver.handshake().await.unwrap();
+        assert!(matches!(
+            driver.set_telemetry(Duration::from_secs(1)).await,
+            Err(DriverError::Unsupported(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn reads_exceptions() {
+        // The driver checks ranges before sending, so write the register directly.
+        let mut driver = connect().await;
+        let write = Request::WriteRegister {
+            address: HOLDING_DUTY,
+            value: 300,
+        };
+        let reply = driver
+            .modbus_transaction(DEFAULT_UNIT, &write, Duration::from_secs(1))
+            .await;
+        assert_eq!(
+            reply.unwrap(),
+            Reply::Exception(Exception::IllegalDataValue)
+        );
+    }
+
+    #[tokio::test]
+    async fn times_out_on_another_unit() {
+        let mut sim = Simulator::new().with_modbus(DEFAULT_UNIT);
+        let (client, device) = tokio::io::duplex(256);
+        tokio::spawn(async move { sim.run(device).await });
+        let mut driver = DeviceDriver::new(client)
+            .with_modbus(DEFAULT_UNIT + 1)
+            .with_timeout(Duration::from_millis(100))
+            .with_retries(0);
+        assert!(matches!(
+            driver.get_state().await,
+            Err(DriverError::Timeout)
+        ));
+    }
+}
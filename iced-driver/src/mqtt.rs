@@ -0,0 +1,590 @@
+//! Connects a device to an MQTT broker, for dashboards and home automation.
+//!
+//! Everything lives under `iced/<serial>/`:
+//!
+//! - `status` is `online`, or `offline` once the bridge stops or, through the
+//!   last will, loses the broker.
+//! - `info` holds the device's identity as JSON.
+//! - `led`, `pwm/enabled` (`on` or `off`), `pwm/duty`, `pwm/freq` and
+//!   `uptime_ms` hold the last known state.
+//! - `led/set`, `pwm/enabled/set`, `pwm/duty/set`, `pwm/freq/set` and
+//!   `telemetry/set` (period in ms, 0 for off) change it.
+//! - `button` and `fault` carry events, `error` says why a set failed.
+//!
+//! All but the events and errors are retained, so a dashboard that connects
+//! later sees the state at once. The state is published as it changes, from
+//! telemetry when the device sends it and from a poll otherwise.
+
+use crate::{DeviceCommands, DeviceHandle, DeviceResponses, DeviceState, DriverError, Fault};
+use futures::StreamExt;
+use iced_protocol::Event as DeviceEvent;
+use rumqttc::{AsyncClient, Event, LastWill, Outgoing, Packet, QoS};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+pub use rumqttc::MqttOptions;
+
+/// How often the state is read when the device doesn't send telemetry.
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Wait between attempts to reach the broker again.
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+const ONLINE: &str = "online";
+const OFFLINE: &str = "offline";
+
+#[derive(Debug)]
+pub enum MqttError {
+    /// The broker couldn't be reached.
+    Broker(String),
+    /// The device went away.
+    Device(DriverError),
+}
+
+impl fmt::Display for MqttError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MqttError::Broker(e) => write!(f, "MQTT broker: {}", e),
+            MqttError::Device(e) => write!(f, "device: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for MqttError {}
+
+impl From<rumqttc::ClientError> for MqttError {
+    fn from(e: rumqttc::ClientError) -> Self {
+        MqttError::Broker(e.to_string())
+    }
+}
+
+/// What the connection task passes on from the broker.
+enum Incoming {
+    Connected,
+    Publish(String, Vec<u8>),
+    Error(String),
+    Disconnected,
+}
+
+/// Stops the connection task with the bridge, even when [`MqttBridge::run`]
+/// is dropped rather than cancelled. The broker then sends the last will.
+struct Connection(JoinHandle<()>);
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+pub struct MqttBridge {
+    device: DeviceHandle,
+    prefix: String,
+    poll_interval: Duration,
+    cancel: CancellationToken,
+}
+
+fn on_off(on: bool) -> &'static str {
+    if on {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+fn parse_on_off(payload: &str) -> Option<bool> {
+    match payload.to_ascii_lowercase().as_str() {
+        "on" | "true" | "1" => Some(true),
+        "off" | "false" | "0" => Some(false),
+        _ => None,
+    }
+}
+
+fn number<T: FromStr>(name: &str, payload: &str) -> Result<T, String> {
+    payload
+        .parse()
+        .map_err(|_| format!("{} must be a number, not {:?}", name, payload))
+}
+
+/// The command a `.../set` topic asks for, `field` being the part between the
+/// serial number and `/set`.
+fn set_command(field: &str, payload: &str) -> Result<DeviceCommands, String> {
+    let switch =
+        || parse_on_off(payload).ok_or_else(|| format!("expected on or off, not {:?}", payload));
+    match field {
+        "led" if switch()? => Ok(DeviceCommands::SetGpioPin),
+        "led" => Ok(DeviceCommands::ClearGpioPin),
+        "pwm/enabled" if switch()? => Ok(DeviceCommands::PwmOn),
+        "pwm/enabled" => Ok(DeviceCommands::PwmOff),
+        "pwm/duty" => Ok(DeviceCommands::PwmDuty(number("duty", payload)?)),
+        "pwm/freq" => Ok(DeviceCommands::PwmSetFreq(number("frequency", payload)?)),
+        "telemetry" => Ok(DeviceCommands::SetTelemetry(number(
+            "telemetry period",
+            payload,
+        )?)),
+        other => Err(format!("{} can't be set", other)),
+    }
+}
+
+/// Topic and payload of every field of `state` that differs from `last`.
+fn state_changes(state: &DeviceState, last: Option<&DeviceState>) -> Vec<(&'static str, String)> {
+    let fields = [
+        (
+            "led",
+            on_off(state.led_on).to_string(),
+            last.map(|l| l.led_on != state.led_on),
+        ),
+        (
+            "pwm/enabled",
+            on_off(state.pwm_enabled).to_string(),
+            last.map(|l| l.pwm_enabled != state.pwm_enabled),
+        ),
+        (
+            "pwm/duty",
+            state.pwm_duty.to_string(),
+            last.map(|l| l.pwm_duty != state.pwm_duty),
+        ),
+        (
+            "pwm/freq",
+            state.pwm_frequency.to_string(),
+            last.map(|l| l.pwm_frequency != state.pwm_frequency),
+        ),
+        ("uptime_ms", state.uptime_ms.to_string(), Some(true)),
+    ];
+    fields
+        .into_iter()
+        .filter(|(_, _, changed)| changed.unwrap_or(true))
+        .map(|(topic, payload, _)| (topic, payload))
+        .collect()
+}
+
+/// `name` as a single topic level: `/` would split it, `+` and `#` are
+/// wildcards, so each becomes `_`.
+pub fn topic_level(name: &str) -> String {
+    name.replace(['/', '+', '#'], "_")
+}
+
+impl MqttBridge {
+    /// Publishes `device` under `iced/<serial>/`, with `serial` passed
+    /// through [`topic_level`].
+    pub fn new(device: DeviceHandle, serial: &str) -> Self {
+        Self {
+            device,
+            prefix: format!("iced/{}", topic_level(serial)),
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            cancel: CancellationToken::new(),
+        }
+    }
+
+    /// Reads the state every `interval` to catch changes that don't come as telemetry.
+    pub fn with_poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// A token that, when cancelled, makes [`MqttBridge::run`] mark the device
+    /// offline and return.
+    pub fn cancel_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    pub fn topic(&self, field: &str) -> String {
+        format!("{}/{}", self.prefix, field)
+    }
+
+    /// Runs until cancelled, the device goes away or the broker can't be
+    /// reached for the first connection. Later losses of the broker are
+    /// retried.
+    pub async fn run(&self, mut options: MqttOptions) -> Result<(), MqttError> {
+        options.set_last_will(LastWill::new(
+            self.topic("status"),
+            OFFLINE,
+            QoS::AtLeastOnce,
+            true,
+        ));
+        let (client, mut eventloop) = AsyncClient::new(options, 64);
+        let (tx, mut incoming) = mpsc::unbounded_channel();
+        let _connection = Connection(tokio::spawn(async move {
+            loop {
+                let message = match eventloop.poll().await {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => Incoming::Connected,
+                    Ok(Event::Incoming(Packet::Publish(p))) => {
+                        Incoming::Publish(p.topic, p.payload.to_vec())
+                    }
+                    Ok(Event::Outgoing(Outgoing::Disconnect)) => Incoming::Disconnected,
+                    Ok(_) => continue,
+                    Err(e) => {
+                        if tx.send(Incoming::Error(e.to_string())).is_err() {
+                            return;
+                        }
+                        tokio::time::sleep(RECONNECT_DELAY).await;
+                        continue;
+                    }
+                };
+                if tx.send(message).is_err() {
+                    return;
+                }
+            }
+        }));
+
+        let result = self.serve(&client, &mut incoming).await;
+        if !matches!(result, Err(MqttError::Broker(_))) {
+            // The will only goes out when the connection drops, not on a clean disconnect.
+            let goodbye = async {
+                client
+                    .publish(self.topic("status"), QoS::AtLeastOnce, true, OFFLINE)
+                    .await?;
+                client.disconnect().await?;
+                while let Some(message) = incoming.recv().await {
+                    if let Incoming::Disconnected | Incoming::Error(_) = message {
+                        break;
+                    }
+                }
+                Ok::<_, MqttError>(())
+            };
+            let _ = tokio::time::timeout(Duration::from_secs(2), goodbye).await;
+        }
+        result
+    }
+
+    async fn serve(
+        &self,
+        client: &AsyncClient,
+        incoming: &mut mpsc::UnboundedReceiver<Incoming>,
+    ) -> Result<(), MqttError> {
+        let mut events = self.device.events();
+        let mut poll = tokio::time::interval(self.poll_interval);
+        let mut connected = false;
+        // Whether the broker was ever reached, errors before that end the run.
+        let mut reached = false;
+        let mut last = None;
+        loop {
+            tokio::select! {
+                message = incoming.recv() => match message {
+                    Some(Incoming::Connected) => {
+                        connected = true;
+                        reached = true;
+                        last = None;
+                        self.announce(client).await?;
+                    }
+                    Some(Incoming::Publish(topic, payload)) => {
+                        self.handle_set(client, &topic, &payload).await?;
+                        // Publish what the command changed right away.
+                        self.refresh(client, &mut last).await?;
+                    }
+                    Some(Incoming::Error(e)) if !reached => return Err(MqttError::Broker(e)),
+                    // Publishing now would only fill the client's queue until it blocks.
+                    Some(Incoming::Error(_)) | Some(Incoming::Disconnected) => connected = false,
+                    None => return Err(MqttError::Broker("connection task stopped".into())),
+                },
+                event = events.next() => match event {
+                    Some(_) if !connected => {}
+                    Some(DeviceEvent::Telemetry(s)) => self.publish_state(client, &s, &mut last).await?,
+                    Some(DeviceEvent::Button) => self.publish(client, "button", "pressed", false).await?,
+                    Some(DeviceEvent::Fault(f)) => {
+                        let fault = match f {
+                            Fault::Overrun => "overrun".to_string(),
+                            Fault::Other(code) => code.to_string(),
+                        };
+                        self.publish(client, "fault", &fault, false).await?
+                    }
+                    None => return Err(MqttError::Device(DriverError::PortClosed)),
+                },
+                _ = poll.tick(), if connected => self.refresh(client, &mut last).await?,
+                _ = self.cancel.cancelled() => return Ok(()),
+            }
+        }
+    }
+
+    async fn publish(
+        &self,
+        client: &AsyncClient,
+        field: &str,
+        payload: &str,
+        retain: bool,
+    ) -> Result<(), MqttError> {
+        client
+            .publish(self.topic(field), QoS::AtLeastOnce, retain, payload)
+            .await?;
+        Ok(())
+    }
+
+    /// Subscribes and publishes everything retained, on every (re)connection.
+    async fn announce(&self, client: &AsyncClient) -> Result<(), MqttError> {
+        for filter in ["+/set", "+/+/set"] {
+            client
+                .subscribe(self.topic(filter), QoS::AtLeastOnce)
+                .await?;
+        }
+        self.publish(client, "status", ONLINE, true).await?;
+        if let Some(info) = self.device.info() {
+            let info = serde_json::to_string(info).unwrap_or_default();
+            self.publish(client, "info", &info, true).await?;
+        }
+        Ok(())
+    }
+
+    /// Reads the state and publishes what changed.
+    async fn refresh(
+        &self,
+        client: &AsyncClient,
+        last: &mut Option<DeviceState>,
+    ) -> Result<(), MqttError> {
+        match self.device.get_state().await {
+            Ok(DeviceResponses::State(s)) => self.publish_state(client, &s, last).await,
+            Err(e @ (DriverError::PortClosed | DriverError::Io(_))) => Err(MqttError::Device(e)),
+            // A missed reply is caught up on the next poll.
+            _ => Ok(()),
+        }
+    }
+
+    async fn publish_state(
+        &self,
+        client: &AsyncClient,
+        state: &DeviceState,
+        last: &mut Option<DeviceState>,
+    ) -> Result<(), MqttError> {
+        for (field, payload) in state_changes(state, last.as_ref()) {
+            self.publish(client, field, &payload, true).await?;
+        }
+        *last = Some(*state);
+        Ok(())
+    }
+
+    async fn handle_set(
+        &self,
+        client: &AsyncClient,
+        topic: &str,
+        payload: &[u8],
+    ) -> Result<(), MqttError> {
+        let Some(field) = topic
+            .strip_prefix(self.prefix.as_str())
+            .and_then(|t| t.strip_prefix('/'))
+            .and_then(|t| t.strip_suffix("/set"))
+        else {
+            return Ok(());
+        };
+        let payload = String::from_utf8_lossy(payload);
+        let outcome = match set_command(field, payload.trim()) {
+            Ok(command) => match self.device.handle_command(command).await {
+                Ok(_) => Ok(()),
+                Err(e @ (DriverError::PortClosed | DriverError::Io(_))) => {
+                    return Err(MqttError::Device(e))
+                }
+                Err(e) => Err(e.to_string()),
+            },
+            Err(e) => Err(e),
+        };
+        match outcome {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                let message = format!("{}: {}", topic, e);
+                self.publish(client, "error", &message, false).await
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DeviceDriver;
+    use iced_sim::Simulator;
+    use rumqttd::{Broker, Config, ConnectionSettings, RouterConfig, ServerSettings};
+    use std::collections::HashMap;
+    use tokio::time::timeout;
+
+    /// Starts an embedded broker on a free localhost port and returns the port.
+    fn start_broker() -> u16 {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let server = ServerSettings {
+            name: "v4".into(),
+            listen: ([127, 0, 0, 1], port).into(),
+            tls: None,
+            next_connection_delay_ms: 1,
+            connections: ConnectionSettings {
+                connection_timeout_ms: 5000,
+                max_payload_size: 4096,
+                max_inflight_count: 100,
+                auth: None,
+                external_auth: None,
+                dynamic_filters: false,
+            },
+        };
+        let config = Config {
+            router: RouterConfig {
+                max_connections: 10,
+                max_outgoing_packet_count: 200,
+                max_segment_size: 1024 * 1024,
+                max_segment_count: 10,
+                custom_segment: None,
+                initialized_filters: None,
+                shared_subscriptions_strategy: Default::default(),
+            },
+            v4: Some(HashMap::from([("v4".to_string(), server)])),
+            ..Default::default()
+        };
+        std::thread::spawn(move || {
+            let _ = Broker::new(config).start();
+        });
+        while std::net::TcpStream::connect(("127.0.0.1", port)).is_err() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        port
+    }
+
+    async fn start_device() -> DeviceHandle {
+        let mut sim = Simulator::new();
+        let (client, device) = tokio::io::duplex(256);
+        tokio::spawn(async move { sim.run(device).await });
+        let mut driver = DeviceDriver::new(client);
+        driver.handshake().await.unwrap();
+        driver.spawn().0
+    }
+
+    /// A dashboard-like client subscribed to everything under `iced/`.
+    async fn watch(
+        port: u16,
+        id: &str,
+    ) -> (AsyncClient, mpsc::UnboundedReceiver<(String, String)>) {
+        let (client, mut eventloop) = AsyncClient::new(MqttOptions::new(id, "127.0.0.1", port), 64);
+        client.subscribe("iced/#", QoS::AtLeastOnce).await.unwrap();
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok(event) = eventloop.poll().await {
+                if let Event::Incoming(Packet::Publish(p)) = event {
+                    let payload = String::from_utf8_lossy(&p.payload).to_string();
+                    if tx.send((p.topic, payload)).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+        (client, rx)
+    }
+
+    /// Waits until every topic in `wanted` has been published with its payload, in any order.
+    async fn expect(rx: &mut mpsc::UnboundedReceiver<(String, String)>, wanted: &[(&str, &str)]) {
+        let mut missing = wanted.to_vec();
+        let found = timeout(Duration::from_secs(5), async {
+            while let Some((t, p)) = rx.recv().await {
+                missing.retain(|(topic, payload)| (*topic, *payload) != (t.as_str(), p.as_str()));
+                if missing.is_empty() {
+                    return;
+                }
+            }
+        })
+        .await;
+        assert!(found.is_ok(), "never saw {:?}", missing);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn publishes_state_and_accepts_sets() {
+        let port = start_broker();
+        let bridge = MqttBridge::new(start_device().await, "sim1")
+            .with_poll_interval(Duration::from_millis(100));
+        let cancel = bridge.cancel_token();
+        let running = tokio::spawn(async move {
+            bridge
+                .run(MqttOptions::new("bridge", "127.0.0.1", port))
+                .await
+        });
+
+        let (client, mut rx) = watch(port, "dashboard").await;
+        expect(&mut rx, &[("iced/sim1/status", "online")]).await;
+        client
+            .publish("iced/sim1/pwm/duty/set", QoS::AtLeastOnce, false, "42")
+            .await
+            .unwrap();
+        expect(&mut rx, &[("iced/sim1/pwm/duty", "42")]).await;
+        client
+            .publish("iced/sim1/led/set", QoS::AtLeastOnce, false, "on")
+            .await
+            .unwrap();
+        expect(&mut rx, &[("iced/sim1/led", "on")]).await;
+        client
+            .publish("iced/sim1/pwm/duty/set", QoS::AtLeastOnce, false, "140")
+            .await
+            .unwrap();
+        let error = timeout(Duration::from_secs(5), async {
+            loop {
+                match rx.recv().await {
+                    Some((t, p)) if t == "iced/sim1/error" => return p,
+                    Some(_) => {}
+                    None => panic!("watcher stopped"),
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert!(error.starts_with("iced/sim1/pwm/duty/set"), "{}", error);
+
+        cancel.cancel();
+        running.await.unwrap().unwrap();
+        expect(&mut rx, &[("iced/sim1/status", "offline")]).await;
+
+        // A client connecting now still sees the last known state.
+        let (_late, mut rx) = watch(port, "late").await;
+        let retained = [
+            ("iced/sim1/pwm/duty", "42"),
+            ("iced/sim1/status", "offline"),
+        ];
+        expect(&mut rx, &retained).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn leaves_a_last_will() {
+        let port = start_broker();
+        let bridge = MqttBridge::new(start_device().await, "sim2");
+        let running = tokio::spawn(async move {
+            bridge
+                .run(MqttOptions::new("bridge", "127.0.0.1", port))
+                .await
+        });
+        let (_client, mut rx) = watch(port, "dashboard").await;
+        expect(&mut rx, &[("iced/sim2/status", "online")]).await;
+
+        // Dropping the bridge closes the connection without a word to the broker.
+        running.abort();
+        expect(&mut rx, &[("iced/sim2/status", "offline")]).await;
+    }
+
+    #[tokio::test]
+    async fn fails_without_a_broker() {
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let bridge = MqttBridge::new(start_device().await, "sim1");
+        let result = bridge
+            .run(MqttOptions::new("bridge", "127.0.0.1", port))
+            .await;
+        assert!(matches!(result, Err(MqttError::Broker(_))), "{:?}", result);
+    }
+
+    #[test]
+    fn parses_set_payloads() {
+        assert_eq!(set_command("led", "ON"), Ok(DeviceCommands::SetGpioPin));
+        assert_eq!(set_command("pwm/enabled", "0"), Ok(DeviceCommands::PwmOff));
+        assert_eq!(
+            set_command("pwm/freq", "2000"),
+            Ok(DeviceCommands::PwmSetFreq(2000))
+        );
+        assert!(set_command("pwm/duty", "lots").is_err());
+        assert!(set_command("uptime_ms", "0").is_err());
+    }
+
+    #[test]
+    fn escapes_topic_levels() {
+        assert_eq!(topic_level("066DFF545057"), "066DFF545057");
+        assert_eq!(topic_level("rack/1+#"), "rack_1__");
+    }
+}
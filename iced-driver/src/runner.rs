@@ -0,0 +1,559 @@
+//! Declarative test sequences, read from TOML and run against a device.
+//!
+//! ```toml
+//! name = "duty sweep"
+//!
+//! [[step]]
+//! set = { led = true, pwm = true, freq = 1000 }
+//!
+//! [[step]]
+//! [step.loop]
+//! sweep = { var = "d", from = 10, to = 100, by = 10 }
+//!
+//! [[step.loop.step]]
+//! set = { duty = "d" }
+//!
+//! [[step.loop.step]]
+//! wait = 200
+//!
+//! [[step.loop.step]]
+//! expect = { duty = "d", pwm = true }
+//! ```
+//!
+//! A failed `expect` is recorded and the run goes on, a command the device
+//! doesn't accept stops it.
+
+use crate::{DeviceCommands, DeviceHandle, DeviceResponses, DeviceState, DriverError};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::Duration;
+use std::{fmt, fs, io};
+use tokio::time::{self, Instant};
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Sequence {
+    #[serde(default)]
+    pub name: String,
+    #[serde(rename = "step", default)]
+    pub steps: Vec<Step>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Step {
+    /// Changes the outputs that are given, in the order LED, frequency, duty, PWM.
+    Set(Outputs),
+    /// Waits this many milliseconds.
+    Wait(u64),
+    /// Reads a value from the device into the report.
+    Read(Reading),
+    /// Reads the device state and fails the step if a given output differs.
+    Expect(Outputs),
+    /// Runs steps repeatedly.
+    Loop(Loop),
+}
+
+/// Output settings, fields left out aren't touched or checked.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Outputs {
+    pub led: Option<bool>,
+    pub pwm: Option<bool>,
+    pub duty: Option<Value>,
+    pub freq: Option<Value>,
+}
+
+/// A number, or the name of a loop variable.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Value {
+    Number(u32),
+    Variable(String),
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Reading {
+    Time,
+    State,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Loop {
+    /// Number of passes when there's no sweep, once if neither is given.
+    pub times: Option<u32>,
+    pub sweep: Option<Sweep>,
+    #[serde(rename = "step", default)]
+    pub steps: Vec<Step>,
+}
+
+/// Runs the loop once for every value of `var` from `from` to `to` inclusive.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Sweep {
+    pub var: String,
+    pub from: u32,
+    pub to: u32,
+    #[serde(default = "default_by")]
+    pub by: u32,
+}
+
+fn default_by() -> u32 {
+    1
+}
+
+impl Sweep {
+    fn values(&self) -> impl Iterator<Item = u32> {
+        let by = self.by.max(1) as usize;
+        let (from, to) = (self.from, self.to);
+        let up = (from..=to).step_by(by);
+        let down = (to..=from).rev().step_by(by);
+        // Only one of the two ranges is non-empty, unless `from == to`.
+        up.chain(down.skip(usize::from(from == to)))
+    }
+}
+
+#[derive(Debug)]
+pub enum SequenceError {
+    /// The file couldn't be read.
+    Io(io::Error),
+    /// The file isn't a valid sequence.
+    Parse(toml::de::Error),
+}
+
+impl fmt::Display for SequenceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SequenceError::Io(e) => write!(f, "I/O error: {}", e),
+            SequenceError::Parse(e) => write!(f, "invalid sequence: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SequenceError {}
+
+impl From<io::Error> for SequenceError {
+    fn from(e: io::Error) -> Self {
+        SequenceError::Io(e)
+    }
+}
+
+impl From<toml::de::Error> for SequenceError {
+    fn from(e: toml::de::Error) -> Self {
+        SequenceError::Parse(e)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum Outcome {
+    Passed,
+    /// The value a `read` step got.
+    Read(DeviceResponses),
+    Failed(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct StepReport {
+    /// The step with loop variables filled in, such as `set duty 40`.
+    pub description: String,
+    pub outcome: Outcome,
+    /// Time since the start of the run.
+    pub at: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct Report {
+    pub name: String,
+    pub steps: Vec<StepReport>,
+    /// The run stopped early because the device didn't accept a command.
+    pub aborted: bool,
+    pub elapsed: Duration,
+}
+
+impl Report {
+    pub fn passed(&self) -> bool {
+        !self.aborted && self.failures() == 0
+    }
+
+    pub fn failures(&self) -> usize {
+        self.steps
+            .iter()
+            .filter(|s| matches!(s.outcome, Outcome::Failed(_)))
+            .count()
+    }
+}
+
+/// Why a run stopped early.
+enum Abort {
+    Driver(DriverError),
+    UnknownVariable(String),
+}
+
+impl fmt::Display for Abort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Abort::Driver(e) => e.fmt(f),
+            Abort::UnknownVariable(name) => write!(f, "unknown variable {:?}", name),
+        }
+    }
+}
+
+impl From<DriverError> for Abort {
+    fn from(e: DriverError) -> Self {
+        Abort::Driver(e)
+    }
+}
+
+type Variables = HashMap<String, u32>;
+
+impl Value {
+    fn resolve(&self, vars: &Variables) -> Result<u32, Abort> {
+        match self {
+            Value::Number(n) => Ok(*n),
+            Value::Variable(name) => vars
+                .get(name)
+                .copied()
+                .ok_or_else(|| Abort::UnknownVariable(name.clone())),
+        }
+    }
+}
+
+/// `Outputs` with the loop variables filled in.
+#[derive(Default)]
+struct Resolved {
+    led: Option<bool>,
+    pwm: Option<bool>,
+    duty: Option<u32>,
+    freq: Option<u32>,
+}
+
+impl Outputs {
+    fn resolve(&self, vars: &Variables) -> Result<Resolved, Abort> {
+        Ok(Resolved {
+            led: self.led,
+            pwm: self.pwm,
+            duty: self.duty.as_ref().map(|v| v.resolve(vars)).transpose()?,
+            freq: self.freq.as_ref().map(|v| v.resolve(vars)).transpose()?,
+        })
+    }
+}
+
+impl fmt::Display for Resolved {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let on_off = |b: bool| if b { "on" } else { "off" };
+        if let Some(led) = self.led {
+            write!(f, " led {}", on_off(led))?;
+        }
+        if let Some(freq) = self.freq {
+            write!(f, " freq {}", freq)?;
+        }
+        if let Some(duty) = self.duty {
+            write!(f, " duty {}", duty)?;
+        }
+        if let Some(pwm) = self.pwm {
+            write!(f, " pwm {}", on_off(pwm))?;
+        }
+        Ok(())
+    }
+}
+
+impl Resolved {
+    fn commands(&self) -> Vec<DeviceCommands> {
+        let mut commands = Vec::new();
+        match self.led {
+            Some(true) => commands.push(DeviceCommands::SetGpioPin),
+            Some(false) => commands.push(DeviceCommands::ClearGpioPin),
+            None => (),
+        }
+        if let Some(hz) = self.freq {
+            commands.push(DeviceCommands::PwmSetFreq(hz));
+        }
+        if let Some(duty) = self.duty {
+            // Anything past a u8 is out of range anyway, the driver refuses it.
+            commands.push(DeviceCommands::PwmDuty(duty.min(u8::MAX.into()) as u8));
+        }
+        match self.pwm {
+            Some(true) => commands.push(DeviceCommands::PwmOn),
+            Some(false) => commands.push(DeviceCommands::PwmOff),
+            None => (),
+        }
+        commands
+    }
+
+    /// What differs between the expected outputs and `state`.
+    fn mismatches(&self, state: &DeviceState) -> Vec<String> {
+        let mut wrong = Vec::new();
+        if let Some(led) = self.led.filter(|led| *led != state.led_on) {
+            wrong.push(format!("led is {}, expected {}", state.led_on, led));
+        }
+        if let Some(pwm) = self.pwm.filter(|pwm| *pwm != state.pwm_enabled) {
+            wrong.push(format!("pwm is {}, expected {}", state.pwm_enabled, pwm));
+        }
+        if let Some(duty) = self.duty.filter(|d| *d != u32::from(state.pwm_duty)) {
+            wrong.push(format!("duty is {}, expected {}", state.pwm_duty, duty));
+        }
+        if let Some(freq) = self.freq.filter(|f| *f != state.pwm_frequency) {
+            wrong.push(format!(
+                "freq is {}, expected {}",
+                state.pwm_frequency, freq
+            ));
+        }
+        wrong
+    }
+}
+
+/// State of one run.
+struct Run<'a> {
+    device: &'a DeviceHandle,
+    started: Instant,
+    vars: Variables,
+    steps: Vec<StepReport>,
+}
+
+impl Run<'_> {
+    fn record(&mut self, description: String, outcome: Outcome) {
+        self.steps.push(StepReport {
+            description,
+            outcome,
+            at: self.started.elapsed(),
+        });
+    }
+
+    fn run_steps<'s>(&'s mut self, steps: &'s [Step]) -> BoxFuture<'s, Result<(), Abort>> {
+        async move {
+            for step in steps {
+                self.step(step).await?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    async fn step(&mut self, step: &Step) -> Result<(), Abort> {
+        match step {
+            Step::Set(outputs) => {
+                let outputs = outputs.resolve(&self.vars)?;
+                for command in outputs.commands() {
+                    self.device.handle_command(command).await?;
+                }
+                self.record(format!("set{}", outputs), Outcome::Passed);
+            }
+            Step::Wait(ms) => {
+                time::sleep(Duration::from_millis(*ms)).await;
+                self.record(format!("wait {} ms", ms), Outcome::Passed);
+            }
+            Step::Read(reading) => {
+                let (description, command) = match reading {
+                    Reading::Time => ("read time", DeviceCommands::GetTime),
+                    Reading::State => ("read state", DeviceCommands::GetState),
+                };
+                let response = self.device.handle_command(command).await?;
+                self.record(description.into(), Outcome::Read(response));
+            }
+            Step::Expect(outputs) => {
+                let outputs = outputs.resolve(&self.vars)?;
+                let state = match self.device.get_state().await? {
+                    DeviceResponses::State(state) => state,
+                    other => {
+                        return Err(Abort::Driver(DriverError::MalformedResponse(format!(
+                            "{:?}",
+                            other
+                        ))))
+                    }
+                };
+                let wrong = outputs.mismatches(&state);
+                let outcome = if wrong.is_empty() {
+                    Outcome::Passed
+                } else {
+                    Outcome::Failed(wrong.join(", "))
+                };
+                self.record(format!("expect{}", outputs), outcome);
+            }
+            Step::Loop(l) => match &l.sweep {
+                Some(sweep) => {
+                    let saved = self.vars.get(&sweep.var).copied();
+                    for value in sweep.values() {
+                        self.vars.insert(sweep.var.clone(), value);
+                        self.run_steps(&l.steps).await?;
+                    }
+                    match saved {
+                        Some(value) => self.vars.insert(sweep.var.clone(), value),
+                        None => self.vars.remove(&sweep.var),
+                    };
+                }
+                None => {
+                    for _ in 0..l.times.unwrap_or(1) {
+                        self.run_steps(&l.steps).await?;
+                    }
+                }
+            },
+        }
+        Ok(())
+    }
+}
+
+impl Sequence {
+    pub fn from_toml(text: &str) -> Result<Self, SequenceError> {
+        Ok(toml::from_str(text)?)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, SequenceError> {
+        Self::from_toml(&fs::read_to_string(path)?)
+    }
+
+    /// Runs every step against `device` and reports how each went.
+    pub async fn run(&self, device: &DeviceHandle) -> Report {
+        let mut run = Run {
+            device,
+            started: Instant::now(),
+            vars: Variables::new(),
+            steps: Vec::new(),
+        };
+        let aborted = match run.run_steps(&self.steps).await {
+            Ok(()) => false,
+            Err(e) => {
+                run.record("abort".into(), Outcome::Failed(e.to_string()));
+                true
+            }
+        };
+        Report {
+            name: self.name.clone(),
+            aborted,
+            elapsed: run.started.elapsed(),
+            steps: run.steps,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DeviceDriver;
+
+    fn sweep(from: u32, to: u32, by: u32) -> Vec<u32> {
+        let sweep = Sweep {
+            var: "v".into(),
+            from,
+            to,
+            by,
+        };
+        sweep.values().collect()
+    }
+
+    #[test]
+    fn sweeps_both_ways() {
+        assert_eq!(sweep(10, 30, 10), [10, 20, 30]);
+        assert_eq!(sweep(30, 10, 10), [30, 20, 10]);
+        assert_eq!(sweep(5, 5, 1), [5]);
+        // The end is left out when the step doesn't land on it.
+        assert_eq!(sweep(0, 10, 4), [0, 4, 8]);
+        assert_eq!(sweep(10, 0, 4), [10, 6, 2]);
+        assert_eq!(sweep(1, 3, 0), [1, 2, 3]);
+    }
+
+    async fn run(toml: &str) -> Report {
+        let (client, _sim) = iced_sim::spawn(256);
+        let (device, _) = DeviceDriver::new(client).spawn();
+        Sequence::from_toml(toml).unwrap().run(&device).await
+    }
+
+    fn outcomes(report: &Report) -> Vec<(&str, bool)> {
+        report
+            .steps
+            .iter()
+            .map(|s| {
+                let failed = matches!(s.outcome, Outcome::Failed(_));
+                (s.description.as_str(), !failed)
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn runs_a_sweep() {
+        let report = run(r#"
+            name = "sweep"
+            [[step]]
+            set = { pwm = true, freq = 1000 }
+            [[step]]
+            [step.loop]
+            sweep = { var = "d", from = 10, to = 30, by = 10 }
+            [[step.loop.step]]
+            set = { duty = "d" }
+            [[step.loop.step]]
+            expect = { duty = "d", pwm = true }
+            [[step]]
+            read = "time"
+        "#)
+        .await;
+        assert!(report.passed());
+        assert_eq!(report.name, "sweep");
+        assert_eq!(report.steps.len(), 8);
+        assert_eq!(report.steps[4].description, "expect duty 20 pwm on");
+        assert!(matches!(
+            report.steps[7].outcome,
+            Outcome::Read(DeviceResponses::Time(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn goes_on_after_a_failed_expect() {
+        let report = run(r#"
+            [[step]]
+            set = { duty = 40 }
+            [[step]]
+            expect = { duty = 50 }
+            [[step]]
+            expect = { duty = 40 }
+        "#)
+        .await;
+        assert!(!report.aborted);
+        assert_eq!(report.failures(), 1);
+        assert!(!report.passed());
+        assert_eq!(
+            outcomes(&report),
+            [
+                ("set duty 40", true),
+                ("expect duty 50", false),
+                ("expect duty 40", true)
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn aborts_on_an_unknown_variable() {
+        let report = run(r#"
+            [[step]]
+            set = { duty = "x" }
+            [[step]]
+            wait = 1
+        "#)
+        .await;
+        assert!(report.aborted);
+        assert_eq!(report.steps.len(), 1);
+        match &report.steps[0].outcome {
+            Outcome::Failed(e) => assert_eq!(e, "unknown variable \"x\""),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn aborts_on_a_refused_command() {
+        let report = run(r#"
+            [[step]]
+            set = { led = true }
+            [[step]]
+            set = { freq = 0 }
+            [[step]]
+            set = { led = false }
+        "#)
+        .await;
+        assert!(report.aborted);
+        assert_eq!(report.failures(), 1);
+        assert_eq!(outcomes(&report), [("set led on", true), ("abort", false)]);
+    }
+}
@@ -0,0 +1,218 @@
+//! The driver as an SCPI client, for devices driven like a bench instrument.
+//!
+//! [`DeviceDriver::with_scpi`] keeps the whole API, commands are sent as the
+//! lines in [`iced_protocol::scpi`]. A setting has no reply of its own, so each
+//! one is followed by `SYST:ERR?` and a queued error becomes
+//! [`DriverError::Rejected`]. `GetState` takes five queries. Framing and
+//! telemetry have no SCPI equivalent and are reported as unsupported.
+
+use crate::{DeviceCommands, DeviceDriver, DeviceResponse, DeviceResponses, DeviceState};
+use crate::{DriverError, Transport};
+use futures::stream::StreamExt;
+use iced_protocol::scpi::{Answer, Message, Query};
+use iced_protocol::{Event, FRAME_SIZE};
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::time::{self, Instant};
+
+fn unexpected(answer: Answer) -> DriverError {
+    DriverError::MalformedResponse(format!("{:?}", answer))
+}
+
+impl<T: Transport> DeviceDriver<T> {
+    /// Carries out `command`, each exchange tried `attempts` times.
+    pub(crate) async fn scpi_command(
+        &mut self,
+        command: DeviceCommands,
+        timeout: Duration,
+        attempts: u32,
+    ) -> DeviceResponse {
+        match command {
+            DeviceCommands::SetFraming(_) | DeviceCommands::SetTelemetry(_) => {
+                Err(DriverError::Unsupported(command))
+            }
+            DeviceCommands::GetTime => {
+                let uptime = self.scpi_number(Query::Uptime, timeout, attempts).await?;
+                Ok(DeviceResponses::Time(uptime))
+            }
+            DeviceCommands::GetState => {
+                let duty = self.scpi_number(Query::Duty, timeout, attempts).await?;
+                Ok(DeviceResponses::State(DeviceState {
+                    led_on: self.scpi_flag(Query::Led, timeout, attempts).await?,
+                    pwm_enabled: self.scpi_flag(Query::Output, timeout, attempts).await?,
+                    pwm_duty: duty
+                        .try_into()
+                        .map_err(|_| unexpected(Answer::Number(duty)))?,
+                    pwm_frequency: self
+                        .scpi_number(Query::Frequency, timeout, attempts)
+                        .await?,
+                    uptime_ms: self.scpi_number(Query::Uptime, timeout, attempts).await?,
+                }))
+            }
+            DeviceCommands::Identify => {
+                // Errors from before the session would be blamed on its first setting.
+                self.scpi_send(&Message::Clear).await?;
+                match self.scpi_query(Query::Identify, timeout, attempts).await? {
+                    Answer::Identity(info) => Ok(DeviceResponses::Identity(info)),
+                    answer => Err(unexpected(answer)),
+                }
+            }
+            command => {
+                let mut failure = DriverError::Timeout;
+                for _ in 0..attempts {
+                    self.scpi_send(&Message::Apply(command)).await?;
+                    failure = match self.scpi_query(Query::Error, timeout, 1).await {
+                        Ok(Answer::Error(None)) => return Ok(DeviceResponses::Success),
+                        Ok(Answer::Error(Some(e))) => return Err(DriverError::Rejected(e.into())),
+                        Ok(answer) => return Err(unexpected(answer)),
+                        Err(e @ DriverError::Timeout) => e,
+                        Err(e) => return Err(e),
+                    };
+                }
+                Err(failure)
+            }
+        }
+    }
+
+    async fn scpi_flag(
+        &mut self,
+        query: Query,
+        timeout: Duration,
+        attempts: u32,
+    ) -> Result<bool, DriverError> {
+        match self.scpi_query(query, timeout, attempts).await? {
+            Answer::Flag(on) => Ok(on),
+            answer => Err(unexpected(answer)),
+        }
+    }
+
+    async fn scpi_number(
+        &mut self,
+        query: Query,
+        timeout: Duration,
+        attempts: u32,
+    ) -> Result<u32, DriverError> {
+        match self.scpi_query(query, timeout, attempts).await? {
+            Answer::Number(n) => Ok(n),
+            answer => Err(unexpected(answer)),
+        }
+    }
+
+    /// Asks `query`, resending it after a timeout.
+    async fn scpi_query(
+        &mut self,
+        query: Query,
+        timeout: Duration,
+        attempts: u32,
+    ) -> Result<Answer, DriverError> {
+        for _ in 0..attempts {
+            self.scpi_send(&Message::Query(query)).await?;
+            match self.scpi_read(timeout).await {
+                Ok(line) => {
+                    return query.decode(&line).map_err(|_| {
+                        DriverError::MalformedResponse(String::from_utf8_lossy(&line).into())
+                    })
+                }
+                Err(DriverError::Timeout) => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Err(DriverError::Timeout)
+    }
+
+    /// Sends one line, after throwing away answers left over from earlier
+    /// attempts so they can't be taken for the next one.
+    async fn scpi_send(&mut self, message: &Message) -> Result<(), DriverError> {
+        while let Ok(frame) = time::timeout(Duration::ZERO, self.port.next()).await {
+            match frame {
+                Some(Ok(Ok(line))) if Event::is_event(&line) => self.dispatch_event(&line),
+                Some(Ok(_)) => self.discarded += 1,
+                Some(Err(e)) => return Err(e.into()),
+                None => return Err(DriverError::PortClosed),
+            }
+        }
+        let mut line = [0u8; FRAME_SIZE];
+        let n = message.encode(&mut line).map_err(DriverError::Corrupt)?;
+        let port = self.port.get_mut();
+        port.write_all(&line[..n]).await?;
+        port.flush().await?;
+        Ok(())
+    }
+
+    /// Waits up to `timeout` for the next line that isn't an event.
+    async fn scpi_read(&mut self, timeout: Duration) -> Result<Vec<u8>, DriverError> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            match time::timeout_at(deadline, self.port.next()).await {
+                Ok(Some(Ok(Ok(line)))) if Event::is_event(&line) => self.dispatch_event(&line),
+                Ok(Some(Ok(Ok(line)))) => return Ok(line),
+                Ok(Some(Ok(Err(e)))) => return Err(DriverError::Corrupt(e)),
+                Ok(Some(Err(e))) => return Err(e.into()),
+                Ok(None) => return Err(DriverError::PortClosed),
+                Err(_elapsed) => return Err(DriverError::Timeout),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{DeviceCommands, DeviceDriver, DeviceResponses, DriverError, ErrorCode};
+    use iced_protocol::scpi::{self, Answer, Message, Query, ScpiError};
+    use std::time::Duration;
+
+    fn connect() -> DeviceDriver<tokio::io::DuplexStream> {
+        let (client, _sim) = iced_sim::spawn(256);
+        DeviceDriver::new(client).with_scpi()
+    }
+
+    #[tokio::test]
+    async fn drives_an_instrument() {
+        let mut driver = connect();
+        let info = driver.handshake().await.unwrap();
+        assert_eq!(info.board.as_str(), "iced-sim");
+        assert_eq!(info.capabilities, scpi::capabilities());
+
+        driver.set_gpio().await.unwrap();
+        driver.set_pwm_duty(40).await.unwrap();
+        driver.set_pwm_hz(20_000).await.unwrap();
+        driver.handle_command(DeviceCommands::PwmOff).await.unwrap();
+        match driver.get_state().await.unwrap() {
+            DeviceResponses::State(s) => {
+                assert!(s.led_on);
+                assert!(!s.pwm_enabled);
+                assert_eq!(s.pwm_duty, 40);
+                assert_eq!(s.pwm_frequency, 20_000);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(driver.get_uptime().await.is_ok());
+        assert!(matches!(
+            driver.set_telemetry(Duration::from_secs(1)).await,
+            Err(DriverError::Unsupported(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn reads_the_error_queue() {
+        // The driver checks ranges before sending, so send the line directly.
+        let mut driver = connect();
+        driver
+            .scpi_send(&Message::Apply(DeviceCommands::PwmDuty(101)))
+            .await
+            .unwrap();
+        let answer = driver
+            .scpi_query(Query::Error, Duration::from_secs(1), 1)
+            .await;
+        assert_eq!(
+            answer.unwrap(),
+            Answer::Error(Some(ScpiError::DataOutOfRange))
+        );
+        assert_eq!(
+            ErrorCode::from(ScpiError::DataOutOfRange),
+            ErrorCode::OutOfRange
+        );
+        // Nothing left, so the next setting goes through.
+        driver.set_pwm_duty(100).await.unwrap();
+    }
+}
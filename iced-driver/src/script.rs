@@ -0,0 +1,352 @@
+//! Rhai scripts with bindings to the device, for automation a sequence can't express.
+//!
+//! ```rhai
+//! set_pwm_hz(1000);
+//! pwm_on();
+//! for duty in range(0, 101, 5) {
+//!     set_pwm_duty(duty);
+//!     sleep(50);
+//!     let s = get_state();
+//!     if s.duty != duty { log(`duty stuck at ${s.duty}`); break; }
+//! }
+//! ```
+//!
+//! Device functions: `set_gpio`, `clear_gpio`, `pwm_on`, `pwm_off`,
+//! `set_pwm_duty(percent)`, `set_pwm_hz(hz)`, `get_time`, `get_uptime`,
+//! `get_state`, `identify`, `set_telemetry(ms)`, `set_framing("line" | "binary")`
+//! and `command("D50")` for a raw frame. Besides those there are `sleep(ms)`
+//! and `log(message)`, `print` goes to the log as well. A device error stops
+//! the script unless it's caught with `try`/`catch`.
+//!
+//! Scripts run on a blocking thread, are limited to a number of operations and
+//! can be stopped from outside with the token from [`Script::cancel_token`].
+
+use crate::{
+    DeviceCommands, DeviceHandle, DeviceInfo, DeviceResponses, DeviceState, DriverError, Framing,
+};
+use rhai::{Dynamic, Engine, EvalAltResult, Map, Position};
+use std::fmt;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::runtime::Handle;
+use tokio_util::sync::CancellationToken;
+
+/// Operations a script may run before it's stopped, so a runaway loop can't hang the caller.
+pub const DEFAULT_MAX_OPERATIONS: u64 = 1_000_000;
+
+#[derive(Debug)]
+pub enum ScriptError {
+    /// The script doesn't parse.
+    Compile(String),
+    /// The script failed, or a device call it didn't catch did.
+    Runtime(String),
+    /// The script ran more than its operation limit.
+    StepLimit(u64),
+    /// The script was stopped through its cancel token.
+    Cancelled,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScriptError::Compile(e) => write!(f, "syntax error: {}", e),
+            ScriptError::Runtime(e) => write!(f, "script error: {}", e),
+            ScriptError::StepLimit(n) => write!(f, "script stopped after {} operations", n),
+            ScriptError::Cancelled => write!(f, "script cancelled"),
+        }
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+type Log = Arc<dyn Fn(&str) + Send + Sync>;
+
+pub struct Script {
+    source: String,
+    max_operations: u64,
+    cancel: CancellationToken,
+}
+
+type CallResult<T> = Result<T, Box<EvalAltResult>>;
+
+fn runtime_error(message: impl fmt::Display) -> Box<EvalAltResult> {
+    message.to_string().into()
+}
+
+fn terminated() -> Box<EvalAltResult> {
+    EvalAltResult::ErrorTerminated(Dynamic::UNIT, Position::NONE).into()
+}
+
+fn state_map(s: &DeviceState) -> Map {
+    let mut map = Map::new();
+    map.insert("led".into(), s.led_on.into());
+    map.insert("pwm".into(), s.pwm_enabled.into());
+    map.insert("duty".into(), i64::from(s.pwm_duty).into());
+    map.insert("freq".into(), i64::from(s.pwm_frequency).into());
+    map.insert("uptime_ms".into(), i64::from(s.uptime_ms).into());
+    map
+}
+
+fn info_map(i: &DeviceInfo) -> Map {
+    let mut map = Map::new();
+    map.insert(
+        "protocol_version".into(),
+        i64::from(i.protocol_version).into(),
+    );
+    map.insert("firmware".into(), i.firmware_version.as_str().into());
+    map.insert("board".into(), i.board.as_str().into());
+    map.insert("capabilities".into(), i.capabilities.to_string().into());
+    map
+}
+
+fn response_value(response: DeviceResponses) -> Dynamic {
+    match response {
+        DeviceResponses::Success => Dynamic::UNIT,
+        DeviceResponses::Time(ms) => i64::from(ms).into(),
+        DeviceResponses::State(s) => state_map(&s).into(),
+        DeviceResponses::Identity(i) => info_map(&i).into(),
+    }
+}
+
+fn argument<T: TryFrom<i64>>(name: &str, value: i64) -> CallResult<T> {
+    T::try_from(value).map_err(|_| runtime_error(format!("{} {} is out of range", name, value)))
+}
+
+/// What the bindings share: the device and a way to wait on it from the script thread.
+struct Bindings {
+    runtime: Handle,
+    device: DeviceHandle,
+    cancel: CancellationToken,
+}
+
+impl Bindings {
+    fn command(&self, command: DeviceCommands) -> CallResult<DeviceResponses> {
+        self.runtime.block_on(async {
+            tokio::select! {
+                response = self.device.handle_command(command) => response.map_err(runtime_error),
+                _ = self.cancel.cancelled() => Err(terminated()),
+            }
+        })
+    }
+
+    fn sleep(&self, ms: i64) -> CallResult<()> {
+        let period = Duration::from_millis(argument("sleep", ms)?);
+        self.runtime.block_on(async {
+            tokio::select! {
+                _ = tokio::time::sleep(period) => Ok(()),
+                _ = self.cancel.cancelled() => Err(terminated()),
+            }
+        })
+    }
+}
+
+fn register(engine: &mut Engine, bindings: Bindings, log: Log) {
+    let b = Rc::new(bindings);
+    let simple = [
+        ("set_gpio", DeviceCommands::SetGpioPin),
+        ("clear_gpio", DeviceCommands::ClearGpioPin),
+        ("pwm_on", DeviceCommands::PwmOn),
+        ("pwm_off", DeviceCommands::PwmOff),
+    ];
+    for (name, command) in simple {
+        let b = b.clone();
+        engine.register_fn(name, move || b.command(command).map(|_| ()));
+    }
+    let d = b.clone();
+    engine.register_fn("set_pwm_duty", move |percent: i64| {
+        let command = DeviceCommands::PwmDuty(argument("duty", percent)?);
+        d.command(command).map(|_| ())
+    });
+    let d = b.clone();
+    engine.register_fn("set_pwm_hz", move |hz: i64| {
+        let command = DeviceCommands::PwmSetFreq(argument("frequency", hz)?);
+        d.command(command).map(|_| ())
+    });
+    let d = b.clone();
+    engine.register_fn("set_telemetry", move |ms: i64| {
+        let command = DeviceCommands::SetTelemetry(argument("telemetry period", ms)?);
+        d.command(command).map(|_| ())
+    });
+    let d = b.clone();
+    engine.register_fn("set_framing", move |framing: &str| {
+        let framing = match framing {
+            "line" => Framing::Line,
+            "binary" => Framing::Binary,
+            other => return Err(runtime_error(format!("unknown framing {:?}", other))),
+        };
+        d.command(DeviceCommands::SetFraming(framing)).map(|_| ())
+    });
+    let d = b.clone();
+    engine.register_fn("get_time", move || {
+        d.command(DeviceCommands::GetTime).map(response_value)
+    });
+    let d = b.clone();
+    engine.register_fn("get_uptime", move || {
+        match d.command(DeviceCommands::GetState)? {
+            DeviceResponses::State(s) => Ok(s.uptime().as_secs_f64()),
+            r => Err(runtime_error(DriverError::MalformedResponse(format!(
+                "{:?}",
+                r
+            )))),
+        }
+    });
+    let d = b.clone();
+    engine.register_fn("get_state", move || {
+        d.command(DeviceCommands::GetState).map(response_value)
+    });
+    let d = b.clone();
+    engine.register_fn("identify", move || {
+        d.command(DeviceCommands::Identify).map(response_value)
+    });
+    let d = b.clone();
+    engine.register_fn("command", move |frame: &str| {
+        let command = DeviceCommands::decode(frame.as_bytes())
+            .map_err(|e| runtime_error(format!("can't parse {:?}: {}", frame, e)))?;
+        d.command(command).map(response_value)
+    });
+    engine.register_fn("sleep", move |ms: i64| b.sleep(ms));
+
+    let l = log.clone();
+    engine.register_fn("log", move |message: &str| l(message));
+    engine.register_fn("log", move |value: Dynamic| log(&value.to_string()));
+}
+
+impl Script {
+    pub fn new(source: impl Into<String>) -> Self {
+        Self {
+            source: source.into(),
+            max_operations: DEFAULT_MAX_OPERATIONS,
+            cancel: CancellationToken::new(),
+        }
+    }
+
+    /// Stops the script after `operations` steps, 0 for no limit.
+    pub fn with_max_operations(mut self, operations: u64) -> Self {
+        self.max_operations = operations;
+        self
+    }
+
+    /// A token that stops the script when cancelled, even while it sleeps or
+    /// waits for the device.
+    pub fn cancel_token(&self) -> CancellationToken {
+        self.cancel.clone()
+    }
+
+    /// Checks the script for syntax errors without running it.
+    pub fn check(&self) -> Result<(), ScriptError> {
+        Engine::new()
+            .compile(&self.source)
+            .map(|_| ())
+            .map_err(|e| ScriptError::Compile(e.to_string()))
+    }
+
+    /// Runs the script against `device`, passing every `log` and `print` line to `log`.
+    pub async fn run(
+        self,
+        device: DeviceHandle,
+        log: impl Fn(&str) + Send + Sync + 'static,
+    ) -> Result<(), ScriptError> {
+        let runtime = Handle::current();
+        tokio::task::spawn_blocking(move || self.run_blocking(runtime, device, log))
+            .await
+            .map_err(|e| ScriptError::Runtime(e.to_string()))?
+    }
+
+    fn run_blocking(
+        self,
+        runtime: Handle,
+        device: DeviceHandle,
+        log: impl Fn(&str) + Send + Sync + 'static,
+    ) -> Result<(), ScriptError> {
+        let log: Log = Arc::new(log);
+        let mut engine = Engine::new();
+        engine.set_max_operations(self.max_operations);
+        let cancel = self.cancel.clone();
+        engine.on_progress(move |_| cancel.is_cancelled().then_some(Dynamic::UNIT));
+        let print = log.clone();
+        engine.on_print(move |line| print(line));
+        let bindings = Bindings {
+            runtime,
+            device,
+            cancel: self.cancel.clone(),
+        };
+        register(&mut engine, bindings, log);
+
+        let ast = engine
+            .compile(&self.source)
+            .map_err(|e| ScriptError::Compile(e.to_string()))?;
+        engine.run_ast(&ast).map_err(|e| match *e {
+            EvalAltResult::ErrorTooManyOperations(_) => ScriptError::StepLimit(self.max_operations),
+            EvalAltResult::ErrorTerminated(..) => ScriptError::Cancelled,
+            e => ScriptError::Runtime(e.to_string()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::DeviceDriver;
+    use std::sync::Mutex;
+
+    fn device() -> DeviceHandle {
+        let (client, _sim) = iced_sim::spawn(256);
+        DeviceDriver::new(client).spawn().0
+    }
+
+    /// Runs `script` and returns its result with the lines it logged.
+    async fn run(script: Script) -> (Result<(), ScriptError>, Vec<String>) {
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let log = lines.clone();
+        let result = script
+            .run(device(), move |line| {
+                log.lock().unwrap().push(line.to_string())
+            })
+            .await;
+        let lines = lines.lock().unwrap().clone();
+        (result, lines)
+    }
+
+    #[tokio::test]
+    async fn drives_the_device() {
+        let source =
+            "set_pwm_duty(40); let s = get_state(); log(s.duty); log(get_uptime() >= 0.0);";
+        let (result, lines) = run(Script::new(source)).await;
+        result.unwrap();
+        assert_eq!(lines, ["40", "true"]);
+    }
+
+    #[tokio::test]
+    async fn stops_at_the_step_limit() {
+        let (result, _) = run(Script::new("loop {}").with_max_operations(1000)).await;
+        assert!(matches!(result, Err(ScriptError::StepLimit(1000))));
+    }
+
+    #[tokio::test]
+    async fn stops_when_cancelled() {
+        let script = Script::new("loop {}").with_max_operations(0);
+        let cancel = script.cancel_token();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            cancel.cancel();
+        });
+        let (result, _) = tokio::time::timeout(Duration::from_secs(5), run(script))
+            .await
+            .expect("the script kept running");
+        assert!(matches!(result, Err(ScriptError::Cancelled)));
+    }
+
+    #[tokio::test]
+    async fn raises_device_errors() {
+        let (result, _) = run(Script::new("set_pwm_duty(101);")).await;
+        match result {
+            Err(ScriptError::Runtime(e)) => assert!(e.contains("out of range"), "{}", e),
+            other => panic!("unexpected {:?}", other),
+        }
+        let caught = "try { set_pwm_duty(101); } catch (e) { log(\"caught\"); }";
+        let (result, lines) = run(Script::new(caught)).await;
+        result.unwrap();
+        assert_eq!(lines, ["caught"]);
+    }
+}
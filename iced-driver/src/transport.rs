@@ -0,0 +1,139 @@
+use crate::capture::Capture;
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{self, TcpStream};
+use tokio::time;
+use tokio_serial::{DataBits, FlowControl, Parity, SerialPortBuilderExt, SerialStream, StopBits};
+
+/// Paths starting with this, as in `socket://host:port`, open a bridge's raw port instead of a serial port.
+pub const SOCKET_SCHEME: &str = "socket://";
+
+/// Anything the driver can talk to: a serial port, a socket, a duplex pipe.
+pub trait Transport: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T> Transport for T where T: AsyncRead + AsyncWrite + Unpin + Send {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SerialPortParams {
+    pub baudrate: u32,
+    pub parity: Parity,
+    pub data_bits: DataBits,
+    pub stop_bits: StopBits,
+    pub timeout: Duration,
+}
+
+impl SerialPortParams {
+    pub fn new() -> Self {
+        Self {
+            baudrate: 115200,
+            parity: Parity::None,
+            data_bits: DataBits::Eight,
+            stop_bits: StopBits::One,
+            timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+impl Default for SerialPortParams {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn open_serial(path: &str, params: &SerialPortParams) -> io::Result<SerialStream> {
+    tokio_serial::new(path, params.baudrate)
+        .data_bits(params.data_bits)
+        .flow_control(FlowControl::None)
+        .stop_bits(params.stop_bits)
+        .parity(params.parity)
+        .timeout(params.timeout)
+        .open_native_async()
+        .map_err(io::Error::from)
+}
+
+/// Connects to `host:port`, giving up after `timeout` per address.
+pub async fn open_socket(address: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "address didn't resolve");
+    for addr in net::lookup_host(address).await? {
+        match time::timeout(timeout, TcpStream::connect(addr)).await {
+            Ok(Ok(stream)) => {
+                stream.set_nodelay(true)?;
+                return Ok(stream);
+            }
+            Ok(Err(e)) => last_error = e,
+            Err(_elapsed) => {
+                last_error = io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("connecting to {} timed out", addr),
+                )
+            }
+        }
+    }
+    Err(last_error)
+}
+
+/// A serial port, or a TCP connection to a bridge that owns one.
+#[derive(Debug)]
+pub enum Port {
+    Serial(SerialStream),
+    Socket(TcpStream),
+    /// Either of the others, with the traffic recorded, see [`Port::capture`].
+    Captured(Box<Capture<Port>>),
+}
+
+/// Opens `path` as a serial port, or as a TCP connection if it starts with [`SOCKET_SCHEME`].
+///
+/// Must be called from within a tokio runtime.
+pub async fn open_port(path: &str, params: &SerialPortParams) -> io::Result<Port> {
+    match path.strip_prefix(SOCKET_SCHEME) {
+        Some(address) => open_socket(address, params.timeout).await.map(Port::Socket),
+        None => open_serial(path, params).map(Port::Serial),
+    }
+}
+
+impl AsyncRead for Port {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Port::Serial(s) => Pin::new(s).poll_read(cx, buf),
+            Port::Socket(s) => Pin::new(s).poll_read(cx, buf),
+            Port::Captured(s) => Pin::new(s.as_mut()).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Port {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Port::Serial(s) => Pin::new(s).poll_write(cx, buf),
+            Port::Socket(s) => Pin::new(s).poll_write(cx, buf),
+            Port::Captured(s) => Pin::new(s.as_mut()).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Port::Serial(s) => Pin::new(s).poll_flush(cx),
+            Port::Socket(s) => Pin::new(s).poll_flush(cx),
+            Port::Captured(s) => Pin::new(s.as_mut()).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Port::Serial(s) => Pin::new(s).poll_shutdown(cx),
+            Port::Socket(s) => Pin::new(s).poll_shutdown(cx),
+            Port::Captured(s) => Pin::new(s.as_mut()).poll_shutdown(cx),
+        }
+    }
+}
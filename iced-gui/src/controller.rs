@@ -1,49 +1,466 @@
 use crate::gui::components::serial::SerialPortParams;
 
 
+use futures::stream::{self, BoxStream, SelectAll, StreamExt};
 use iced::{subscription, Subscription};
-use iced_driver::{DeviceCommands, DeviceDriver};
+use iced_driver::hotplug::{serial_number_of, wait_for_port, DEFAULT_POLL};
+use iced_driver::manager::DeviceStatus;
+use iced_driver::runner::{Report, Sequence};
+use iced_driver::script::Script;
+use iced_driver::{
+    DeviceCommands, DeviceDriver, DeviceEvent, DeviceHandle, DeviceInfo, DeviceManager,
+    DeviceResponses, DeviceState, DriverError, Framing,
+};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::time::Duration;
 
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
-use tokio_serial::{SerialPortBuilderExt};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
 
 
 pub enum WorkerState {
     Disconnected,
-    Ready(UnboundedReceiver<Commands>),
-    Connected(UnboundedReceiver<Commands>, DeviceDriver),
-    Error,
+    Ready(UnboundedReceiver<Commands>, Box<Devices>),
+}
+
+/// Every board the worker has open or is waiting for, by name. The name is
+/// the path the board was opened with.
+pub struct Devices {
+    manager: DeviceManager,
+    connections: HashMap<String, Connection>,
+    /// Events of every board, each stream ends with a `None` once its driver
+    /// task stops.
+    events: SelectAll<BoxStream<'static, (String, Option<DeviceEvent>)>>,
+    /// The task of every open board, doing its commands and sequences in order.
+    boards: HashMap<String, Board>,
+    /// Boards the user asked for, opened by their own task.
+    opening: HashSet<String>,
+    opened_tx: UnboundedSender<(String, Result<Opened, DriverError>)>,
+    opened: UnboundedReceiver<(String, Result<Opened, DriverError>)>,
+    /// Boards that were lost, each waited for by its own task.
+    reconnects: HashMap<String, JoinHandle<()>>,
+    reconnected_tx: UnboundedSender<(String, Opened)>,
+    reconnected: UnboundedReceiver<(String, Opened)>,
+    /// What the tasks of the boards and of broadcasts have to report.
+    results_tx: UnboundedSender<WorkerEvent>,
+    results: UnboundedReceiver<WorkerEvent>,
+    script_tx: UnboundedSender<(String, ScriptOutput)>,
+    script_output: UnboundedReceiver<(String, ScriptOutput)>,
+}
+
+pub struct Connection {
+    params: SerialPortParams,
+    /// Lets the worker find the board again if it's unplugged.
+    serial_number: Option<String>,
+    /// Restored after reconnecting.
+    last_state: Option<DeviceState>,
+    /// Stops the script running on the board, so the GUI stays responsive and can stop it.
+    script: Option<CancellationToken>,
+}
+
+/// A board that answered the handshake, ready to be added.
+type Opened = (DeviceHandle, Connection, DeviceInfo);
+
+/// Work for one board.
+enum Job {
+    Command(DeviceCommands),
+    Sequence(Sequence),
+}
+
+/// The task doing a board's jobs, one at a time so they reach the board in
+/// the order they were given. Stopped when dropped.
+struct Board {
+    jobs: UnboundedSender<Job>,
+    task: JoinHandle<()>,
+}
+
+impl Board {
+    fn spawn(name: String, device: DeviceHandle, results: UnboundedSender<WorkerEvent>) -> Self {
+        let (jobs, mut queue) = unbounded_channel();
+        let task = tokio::spawn(async move {
+            while let Some(job) = queue.recv().await {
+                let event = match job {
+                    Job::Command(cmd) => run_command(&name, &device, cmd).await,
+                    Job::Sequence(sequence) => {
+                        let report = sequence.run(&device).await;
+                        Some(WorkerEvent::SequenceReport(name.clone(), report))
+                    }
+                };
+                if let Some(event) = event {
+                    let _ = results.send(event);
+                }
+            }
+        });
+        Self { jobs, task }
+    }
+}
+
+impl Drop for Board {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Sends `cmd` and follows every accepted command with a state query, so the
+/// GUI shows what the device ended up doing.
+async fn run_command(
+    name: &str,
+    device: &DeviceHandle,
+    cmd: DeviceCommands,
+) -> Option<WorkerEvent> {
+    let mut resp = device.handle_command(cmd).await;
+    if let Ok(DeviceResponses::Success) = resp {
+        resp = device.get_state().await;
+    }
+    match resp {
+        Ok(DeviceResponses::State(s)) => Some(WorkerEvent::DeviceState(name.to_string(), s)),
+        Ok(_) => None,
+        // A lost port is reported once its events end.
+        Err(DriverError::PortClosed) => None,
+        Err(e) => Some(WorkerEvent::DeviceError(name.to_string(), e.to_string())),
+    }
+}
+
+enum ScriptOutput {
+    Log(String),
+    Done(Result<(), String>),
+}
+
+/// Runs a script next to the worker, its output goes to `output` under `name`.
+fn start_script(
+    name: String,
+    source: String,
+    device: DeviceHandle,
+    output: UnboundedSender<(String, ScriptOutput)>,
+) -> CancellationToken {
+    let script = Script::new(source);
+    let cancel = script.cancel_token();
+    tokio::spawn(async move {
+        let log = output.clone();
+        let log_name = name.clone();
+        let result = script
+            .run(device, move |line| {
+                let _ = log.send((log_name.clone(), ScriptOutput::Log(line.to_string())));
+            })
+            .await;
+        let _ = output.send((name, ScriptOutput::Done(result.map_err(|e| e.to_string()))));
+    });
+    cancel
+}
+
+/// A board that was lost and is waited for.
+pub struct Reconnect {
+    serial_number: String,
+    params: SerialPortParams,
+    last_state: Option<DeviceState>,
 }
 
 #[derive(Debug, Clone)]
 pub enum Commands {
-    Nothing,
-    Disconnect,
     Connect(String, SerialPortParams),
-    DeviceCommand(DeviceCommands),
+    /// Closes the named board, or stops waiting for it to come back.
+    Disconnect(String),
+    DeviceCommand(String, DeviceCommands),
+    /// Sends a command to every board.
+    Broadcast(DeviceCommands),
+    /// Reads the state of every board.
+    Status,
+    RunSequence(String, Sequence),
+    RunScript(String, String),
+    CancelScript(String),
 }
 
+/// Events about one board carry its name.
 #[derive(Debug, Clone)]
 pub enum WorkerEvent {
     WorkerHandle(UnboundedSender<Commands>),
-    Connected,
-    Disconnected,
-    McuEvent(McuEvent),
+    /// A board the user asked for was opened.
+    Connected(String, DeviceInfo),
+    ConnectFailed(String),
+    Disconnected(String),
+    /// The port went away, the worker waits for the same board to come back.
+    Reconnecting(String),
+    /// The board came back on its own.
+    Reconnected(String, DeviceInfo),
+    DeviceState(String, DeviceState),
+    DeviceError(String, String),
+    McuEvent(String, DeviceEvent),
+    /// Every board, after a broadcast or a status request.
+    Status(Vec<DeviceStatus>),
+    SequenceReport(String, Report),
+    /// A line a board's script logged.
+    ScriptLog(String, String),
+    ScriptFinished(String, Result<(), String>),
     Idle,
-    Error,
 }
 
-#[derive(Debug, Clone)]
-pub enum McuEvent {
-    Millis(u32),
-    Error,
+const TELEMETRY_PERIOD: Duration = Duration::from_secs(1);
+
+/// Opens the port and only hands the driver out once the device on the other
+/// end answered the handshake with a protocol version we speak.
+async fn open_device(
+    path: &str,
+    params: &SerialPortParams,
+    restore: Option<DeviceState>,
+) -> Result<Opened, DriverError> {
+    let mut d = DeviceDriver::open(path, params).await?;
+    let info = d.handshake().await?;
+    // Devices without binary framing keep using lines.
+    d.negotiate_framing(Framing::Binary).await?;
+    if let Some(state) = &restore {
+        d.restore(state).await?;
+    }
+    // Keep the state on the control page current without polling.
+    if info.capabilities.supports(&DeviceCommands::SetTelemetry(0)) {
+        d.set_telemetry(TELEMETRY_PERIOD).await?;
+    }
+    // The driver task stops once the worker drops the handle.
+    let (device, _task) = d.spawn();
+    let connection = Connection {
+        params: *params,
+        serial_number: serial_number_of(path).unwrap_or(None),
+        last_state: restore,
+        script: None,
+    };
+    Ok((device, connection, info))
 }
 
-#[derive(Debug, Clone)]
-pub enum DeviceResponse {
-    Error,
-    Time(u32),
-    None,
+/// Waits for the board to come back and opens it again, until it does.
+/// Failed attempts are shown on the board's tab through `errors`.
+fn spawn_reconnect(
+    name: String,
+    reconnect: Reconnect,
+    reconnected: UnboundedSender<(String, Opened)>,
+    errors: UnboundedSender<WorkerEvent>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        loop {
+            let opened = match wait_for_port(&reconnect.serial_number, DEFAULT_POLL).await {
+                Ok(port) => open_device(&port, &reconnect.params, reconnect.last_state).await,
+                Err(e) => Err(e),
+            };
+            match opened {
+                Ok(opened) => {
+                    let _ = reconnected.send((name, opened));
+                    return;
+                }
+                Err(e) => {
+                    // The port can show up before the board is ready to answer.
+                    let error = format!("Reconnecting: {}", e);
+                    let _ = errors.send(WorkerEvent::DeviceError(name.clone(), error));
+                    tokio::time::sleep(DEFAULT_POLL).await;
+                }
+            }
+        }
+    })
+}
+
+impl Devices {
+    fn new() -> Self {
+        let (opened_tx, opened) = unbounded_channel();
+        let (reconnected_tx, reconnected) = unbounded_channel();
+        let (results_tx, results) = unbounded_channel();
+        let (script_tx, script_output) = unbounded_channel();
+        Self {
+            manager: DeviceManager::new(),
+            connections: HashMap::new(),
+            events: SelectAll::new(),
+            boards: HashMap::new(),
+            opening: HashSet::new(),
+            opened_tx,
+            opened,
+            reconnects: HashMap::new(),
+            reconnected_tx,
+            reconnected,
+            results_tx,
+            results,
+            script_tx,
+            script_output,
+        }
+    }
+
+    fn add(&mut self, name: String, (device, connection, info): Opened) -> DeviceInfo {
+        let tagged = name.clone();
+        let events = device
+            .events()
+            .map(Some)
+            .chain(stream::once(async { None }))
+            .map(move |e| (tagged.clone(), e));
+        self.events.push(events.boxed());
+        let board = Board::spawn(name.clone(), device.clone(), self.results_tx.clone());
+        self.boards.insert(name.clone(), board);
+        self.manager.add(name.clone(), device);
+        self.connections.insert(name, connection);
+        info
+    }
+
+    fn close(&mut self, name: &str) {
+        if let Some(task) = self.reconnects.remove(name) {
+            task.abort();
+        }
+        if let Some(cancel) = self.connections.remove(name).and_then(|c| c.script) {
+            cancel.cancel();
+        }
+        self.boards.remove(name);
+        self.manager.remove(name);
+    }
+
+    /// The events of `name` ended, wait for the board to come back if it can be recognized.
+    fn connection_lost(&mut self, name: String) -> Option<WorkerEvent> {
+        // The stream of a board that was closed, or replaced by a new connection.
+        if !self.manager.get(&name).is_some_and(|d| d.is_closed()) {
+            return None;
+        }
+        self.boards.remove(&name);
+        self.manager.remove(&name);
+        let connection = self.connections.remove(&name)?;
+        match connection.serial_number {
+            Some(serial_number) => {
+                let reconnect = Reconnect {
+                    serial_number,
+                    params: connection.params,
+                    last_state: connection.last_state,
+                };
+                let task = spawn_reconnect(
+                    name.clone(),
+                    reconnect,
+                    self.reconnected_tx.clone(),
+                    self.results_tx.clone(),
+                );
+                self.reconnects.insert(name.clone(), task);
+                Some(WorkerEvent::Reconnecting(name))
+            }
+            None => Some(WorkerEvent::Disconnected(name)),
+        }
+    }
+
+    /// A board the user asked for was opened, or failed to.
+    fn connected(
+        &mut self,
+        name: String,
+        opened: Result<Opened, DriverError>,
+    ) -> Option<WorkerEvent> {
+        // Closed again while it was being opened.
+        if !self.opening.remove(&name) {
+            return None;
+        }
+        match opened {
+            Ok(opened) => {
+                // Opened by hand while it was being waited for.
+                self.close(&name);
+                let info = self.add(name.clone(), opened);
+                Some(WorkerEvent::Connected(name, info))
+            }
+            Err(e) => Some(WorkerEvent::ConnectFailed(format!("{}: {}", name, e))),
+        }
+    }
+
+    /// Runs `work` next to the worker, its event comes back through `results`.
+    fn spawn<F>(&self, work: F)
+    where
+        F: Future<Output = WorkerEvent> + Send + 'static,
+    {
+        let results = self.results_tx.clone();
+        tokio::spawn(async move {
+            let _ = results.send(work.await);
+        });
+    }
+
+    /// Nothing here waits on a board, each has its own task and their
+    /// results come back through `results`.
+    fn handle(&mut self, command: Commands) -> Option<WorkerEvent> {
+        match command {
+            Commands::Connect(name, _)
+                if self.manager.get(&name).is_some() || self.opening.contains(&name) =>
+            {
+                let open = format!("{} is already open", name);
+                Some(WorkerEvent::ConnectFailed(open))
+            }
+            Commands::Connect(name, params) => {
+                self.opening.insert(name.clone());
+                let opened = self.opened_tx.clone();
+                tokio::spawn(async move {
+                    let result = open_device(&name, &params, None).await;
+                    let _ = opened.send((name, result));
+                });
+                None
+            }
+            Commands::Disconnect(name) => {
+                self.opening.remove(&name);
+                self.close(&name);
+                Some(WorkerEvent::Disconnected(name))
+            }
+            // Nothing to send commands to until the board is back.
+            Commands::DeviceCommand(name, cmd) => {
+                let _ = self.boards.get(&name)?.jobs.send(Job::Command(cmd));
+                None
+            }
+            // The board's commands wait until the sequence is done.
+            Commands::RunSequence(name, sequence) => {
+                let _ = self.boards.get(&name)?.jobs.send(Job::Sequence(sequence));
+                None
+            }
+            Commands::Broadcast(cmd) => {
+                let manager = self.manager.clone();
+                self.spawn(async move {
+                    let replies = manager.broadcast(cmd).await;
+                    let mut status = manager.status().await;
+                    // Both are in name order.
+                    for (s, (_, reply)) in status.iter_mut().zip(replies) {
+                        if let Err(e) = reply {
+                            s.state = Err(e.to_string());
+                        }
+                    }
+                    WorkerEvent::Status(status)
+                });
+                None
+            }
+            Commands::Status => {
+                let manager = self.manager.clone();
+                self.spawn(async move { WorkerEvent::Status(manager.status().await) });
+                None
+            }
+            Commands::RunScript(name, source) => {
+                let device = self.manager.get(&name)?.clone();
+                let connection = self.connections.get_mut(&name)?;
+                if connection.script.is_some() {
+                    let busy = "A script is already running".to_string();
+                    return Some(WorkerEvent::DeviceError(name, busy));
+                }
+                connection.script =
+                    Some(start_script(name, source, device, self.script_tx.clone()));
+                None
+            }
+            Commands::CancelScript(name) => {
+                if let Some(cancel) = self.connections.get(&name).and_then(|c| c.script.as_ref()) {
+                    cancel.cancel();
+                }
+                None
+            }
+        }
+    }
+
+    /// Keeps the states read for restoring after a reconnect.
+    fn keep_states(&mut self, event: WorkerEvent) -> WorkerEvent {
+        match &event {
+            WorkerEvent::DeviceState(name, state) => {
+                if let Some(c) = self.connections.get_mut(name) {
+                    c.last_state = Some(*state);
+                }
+            }
+            WorkerEvent::Status(status) => {
+                for s in status {
+                    if let (Some(c), Ok(state)) = (self.connections.get_mut(&s.name), &s.state) {
+                        c.last_state = Some(*state);
+                    }
+                }
+            }
+            _ => (),
+        }
+        event
+    }
 }
 
 pub fn connect() -> Subscription<WorkerEvent> {
@@ -57,56 +474,46 @@ pub fn connect() -> Subscription<WorkerEvent> {
                     let (mtx, srx) = unbounded_channel::<Commands>();
                     (
                         Some(WorkerEvent::WorkerHandle(mtx)),
-                        WorkerState::Ready(srx),
+                        WorkerState::Ready(srx, Box::new(Devices::new())),
                     )
                 }
-                WorkerState::Ready(mut srx) => {
-                    if let Some(command) = srx.recv().await {
-                        match command {
-                            Commands::Connect(pn, sp) => {
-                                let port = tokio_serial::new(pn, sp.baudrate)
-                                    .data_bits(sp.data_bits)
-                                    .flow_control(tokio_serial::FlowControl::None)
-                                    .stop_bits(sp.stop_bits)
-                                    .parity(sp.parity)
-                                    .timeout(sp.timeout)
-                                    .open_native_async();
-                                println!("Open result: {:?}", port);
-                                match port {
-                                    Ok(p) => (
-                                        Some(WorkerEvent::Connected),
-                                        WorkerState::Connected(srx, DeviceDriver::new(p)),
-                                    ),
-                                    Err(_e) => (Some(WorkerEvent::Error), WorkerState::Ready(srx)),
+                WorkerState::Ready(mut srx, mut devices) => {
+                    let event = tokio::select! {
+                        command = srx.recv() => match command {
+                            Some(command) => devices.handle(command),
+                            None => Some(WorkerEvent::Idle),
+                        },
+                        Some((name, event)) = devices.events.next() => match event {
+                            Some(e) => {
+                                if let (DeviceEvent::Telemetry(s), Some(c)) = (&e, devices.connections.get_mut(&name)) {
+                                    c.last_state = Some(*s);
                                 }
+                                Some(WorkerEvent::McuEvent(name, e))
                             }
-                            _ => (Some(WorkerEvent::Error), WorkerState::Ready(srx)),
+                            // The driver task stopped, the port is gone.
+                            None => devices.connection_lost(name),
+                        },
+                        Some((name, opened)) = devices.opened.recv() => devices.connected(name, opened),
+                        Some(event) = devices.results.recv() => Some(devices.keep_states(event)),
+                        Some((name, opened)) = devices.reconnected.recv() => {
+                            // Unless it was closed or opened by hand in the meantime.
+                            devices.reconnects.remove(&name).map(|_| {
+                                let info = devices.add(name.clone(), opened);
+                                WorkerEvent::Reconnected(name, info)
+                            })
                         }
-                    } else {
-                        (Some(WorkerEvent::Idle), WorkerState::Ready(srx))
-                    }
-                }
-                WorkerState::Connected(mut srx, mut device) => {
-                    if let Some(command) = srx.recv().await {
-                        println!("Command: {:?}", command);
-                        match command {
-                            Commands::Nothing => (None, WorkerState::Connected(srx, device)),
-                            Commands::Disconnect => {
-                                (Some(WorkerEvent::Disconnected), WorkerState::Ready(srx))
-                            }
-                            Commands::DeviceCommand(cmd) => {
-                                let resp = device.handle_command(cmd).await;
-                                println!("{:?}", resp);
-                                (None, WorkerState::Connected(srx, device))
+                        Some((name, output)) = devices.script_output.recv() => match output {
+                            ScriptOutput::Log(line) => Some(WorkerEvent::ScriptLog(name, line)),
+                            ScriptOutput::Done(result) => {
+                                if let Some(c) = devices.connections.get_mut(&name) {
+                                    c.script = None;
+                                }
+                                Some(WorkerEvent::ScriptFinished(name, result))
                             }
-                            _ => (Some(WorkerEvent::Error), WorkerState::Error),
-                        }
-                    } else {
-                        (Some(WorkerEvent::Idle), WorkerState::Connected(srx, device))
-                    }
+                        },
+                    };
+                    (event, WorkerState::Ready(srx, devices))
                 }
-                WorkerState::Error => (Some(WorkerEvent::Error), WorkerState::Error),
-                _ => (Some(WorkerEvent::Error), WorkerState::Error),
             }
         },
     )
@@ -1,7 +1,9 @@
 use super::protocol::Protocol;
 use crate::controller::{connect, Commands, WorkerEvent};
 use crate::gui::components::serial::SerialPortParams;
-use crate::gui::pages::{control::control_page, open_page::main_page};
+use crate::gui::pages::{
+    control::control_page, open_page::main_page, script::script_page, sequence::sequence_page,
+};
 
 
 use iced::executor;
@@ -9,13 +11,36 @@ use iced::theme::{Palette, Theme};
 use iced::widget::Container;
 use iced::{Application, Element, Subscription};
 use iced::{Color, Command, Length};
+use iced_driver::runner::{Report, Sequence};
+use iced_driver::script::Script;
+use iced_driver::{
+    DeviceCommands, DeviceEvent, DeviceInfo, DeviceState, Discovery, FoundDevice, SOCKET_SCHEME,
+};
 
 use tokio::sync::mpsc::UnboundedSender;
-use tokio_serial::{self, SerialPortInfo};
+
+/// What the GUI knows about one open board, shown on its own tab.
+pub struct DeviceTab {
+    /// The path it was opened with, the worker knows it by this name.
+    pub name: String,
+    pub info: DeviceInfo,
+    pub state: Option<DeviceState>,
+    pub error: Option<String>,
+    pub button_presses: u32,
+    pub reconnecting: bool,
+    pub sequence_running: bool,
+    pub sequence_report: Option<Report>,
+    pub script_running: bool,
+    pub script_log: Vec<String>,
+    /// Why the last script stopped early.
+    pub script_error: Option<String>,
+}
 
 pub enum AppState {
     HomePage,
     ControlPage,
+    SequencePage,
+    ScriptPage,
 }
 
 pub struct App {
@@ -23,9 +48,36 @@ pub struct App {
     pub slide_value: i32,
     pub pwm_duty: u8,
     pub pwm_frequency: u32,
-    pub ports: Vec<SerialPortInfo>,
+    pub devices: Vec<FoundDevice>,
+    pub st_link_only: bool,
+    pub probe_ports: bool,
+    pub discovery_error: Option<String>,
+    /// `socket://host:port` of a bridge serving a board on another machine.
+    pub remote_address: String,
     pub params: SerialPortParams,
     pub device_handle: Option<UnboundedSender<Commands>>,
+    pub connect_error: Option<String>,
+    pub tabs: Vec<DeviceTab>,
+    /// Name of the tab on the control page.
+    pub selected: Option<String>,
+    pub sequence_path: String,
+    /// Why the sequence file couldn't be loaded.
+    pub sequence_error: Option<String>,
+    pub script_path: String,
+    pub script_lines: Vec<String>,
+    pub script_error: Option<String>,
+}
+
+impl App {
+    /// The board on the control page.
+    pub fn tab(&self) -> Option<&DeviceTab> {
+        let selected = self.selected.as_ref()?;
+        self.tabs.iter().find(|tab| &tab.name == selected)
+    }
+
+    fn tab_mut(&mut self, name: &str) -> Option<&mut DeviceTab> {
+        self.tabs.iter_mut().find(|tab| tab.name == name)
+    }
 }
 
 impl Application for App {
@@ -41,9 +93,21 @@ impl Application for App {
                 slide_value: 0,
                 pwm_duty: 50,
                 pwm_frequency: 1000,
-                ports: Vec::new(),
+                devices: Vec::new(),
+                st_link_only: true,
+                probe_ports: false,
+                discovery_error: None,
+                remote_address: String::from(SOCKET_SCHEME),
                 params: SerialPortParams::new(),
                 device_handle: None,
+                connect_error: None,
+                tabs: Vec::new(),
+                selected: None,
+                sequence_path: String::new(),
+                sequence_error: None,
+                script_path: String::new(),
+                script_lines: vec![String::new()],
+                script_error: None,
             },
             Command::none(),
         )
@@ -64,28 +128,275 @@ impl Application for App {
                 Command::none()
             }
             Protocol::RefreshPorts => {
-                if let Ok(ports) = tokio_serial::available_ports() {
-                    self.ports = ports;
+                let mut discovery = if self.st_link_only {
+                    Discovery::new()
+                } else {
+                    Discovery::all_ports()
+                };
+                if self.probe_ports {
+                    discovery = discovery.with_probe(self.params);
+                }
+                Command::perform(
+                    async move { discovery.run().await.map_err(|e| e.to_string()) },
+                    Protocol::DevicesFound,
+                )
+            }
+            Protocol::DevicesFound(found) => {
+                match found {
+                    Ok(devices) => {
+                        self.devices = devices;
+                        self.discovery_error = None;
+                    }
+                    Err(e) => self.discovery_error = Some(e),
                 }
                 Command::none()
             }
+            Protocol::StLinkOnly(x) => {
+                self.st_link_only = x;
+                Command::none()
+            }
+            Protocol::ProbePorts(x) => {
+                self.probe_ports = x;
+                Command::none()
+            }
+            Protocol::RemoteAddress(x) => {
+                self.remote_address = x;
+                Command::none()
+            }
             Protocol::SerialPortParams(x) => {
                 self.params = x;
                 Command::none()
             }
+            Protocol::ShowSequencePage => {
+                self.state = AppState::SequencePage;
+                Command::none()
+            }
+            Protocol::ShowHomePage => {
+                self.state = AppState::HomePage;
+                Command::none()
+            }
+            Protocol::SelectDevice(name) => {
+                self.selected = Some(name);
+                Command::none()
+            }
+            Protocol::ShowControlPage => {
+                self.state = AppState::ControlPage;
+                Command::none()
+            }
+            Protocol::SequencePath(x) => {
+                self.sequence_path = x;
+                Command::none()
+            }
+            Protocol::RunSequence => {
+                match Sequence::load(&self.sequence_path) {
+                    Ok(sequence) => {
+                        if let (Some(worker_handle), Some(name)) =
+                            (&self.device_handle, self.selected.clone())
+                        {
+                            let sent = worker_handle
+                                .send(Commands::RunSequence(name.clone(), sequence))
+                                .is_ok();
+                            if let Some(tab) = self.tab_mut(&name) {
+                                tab.sequence_running = sent;
+                                tab.sequence_report = None;
+                            }
+                        }
+                        self.sequence_error = None;
+                    }
+                    Err(e) => self.sequence_error = Some(e.to_string()),
+                }
+                Command::none()
+            }
+            Protocol::ShowScriptPage => {
+                self.state = AppState::ScriptPage;
+                Command::none()
+            }
+            Protocol::ScriptPath(x) => {
+                self.script_path = x;
+                Command::none()
+            }
+            Protocol::LoadScript => {
+                match std::fs::read_to_string(&self.script_path) {
+                    Ok(source) => {
+                        self.script_lines = source.lines().map(String::from).collect();
+                        if self.script_lines.is_empty() {
+                            self.script_lines.push(String::new());
+                        }
+                        self.script_error = None;
+                    }
+                    Err(e) => self.script_error = Some(e.to_string()),
+                }
+                Command::none()
+            }
+            Protocol::SaveScript => {
+                let mut source = self.script_lines.join("\n");
+                source.push('\n');
+                self.script_error = std::fs::write(&self.script_path, source)
+                    .err()
+                    .map(|e| e.to_string());
+                Command::none()
+            }
+            Protocol::ScriptLine(i, x) => {
+                if let Some(line) = self.script_lines.get_mut(i) {
+                    *line = x;
+                }
+                Command::none()
+            }
+            Protocol::ScriptNewLine(i) => {
+                self.script_lines.insert(i + 1, String::new());
+                Command::none()
+            }
+            Protocol::RunScript => {
+                let source = self.script_lines.join("\n");
+                match Script::new(source.as_str()).check() {
+                    Ok(()) => {
+                        if let (Some(worker_handle), Some(name)) =
+                            (&self.device_handle, self.selected.clone())
+                        {
+                            let sent = worker_handle
+                                .send(Commands::RunScript(name.clone(), source))
+                                .is_ok();
+                            if let Some(tab) = self.tab_mut(&name) {
+                                tab.script_running = sent;
+                                tab.script_log.clear();
+                                tab.script_error = None;
+                            }
+                        }
+                        self.script_error = None;
+                    }
+                    Err(e) => self.script_error = Some(e.to_string()),
+                }
+                Command::none()
+            }
             Protocol::WorkerEvent(e) => {
-                println!("Worker Event: {:?}", e);
                 match e {
                     WorkerEvent::WorkerHandle(mtx) => {
                         self.device_handle = Some(mtx);
                         Command::none()
                     }
-                    WorkerEvent::Connected => {
+                    WorkerEvent::Connected(name, info) => {
                         self.state = AppState::ControlPage;
+                        self.connect_error = None;
+                        match self.tab_mut(&name) {
+                            // Opened by hand while it was being waited for.
+                            Some(tab) => {
+                                tab.info = info;
+                                tab.reconnecting = false;
+                            }
+                            None => self.tabs.push(DeviceTab {
+                                name: name.clone(),
+                                info,
+                                state: None,
+                                error: None,
+                                button_presses: 0,
+                                reconnecting: false,
+                                sequence_running: false,
+                                sequence_report: None,
+                                script_running: false,
+                                script_log: Vec::new(),
+                                script_error: None,
+                            }),
+                        }
+                        if let Some(worker_handle) = &self.device_handle {
+                            let _ = worker_handle.send(Commands::DeviceCommand(
+                                name.clone(),
+                                DeviceCommands::GetState,
+                            ));
+                        }
+                        self.selected = Some(name);
+                        Command::none()
+                    }
+                    // Leaves the page and tab the user is on alone.
+                    WorkerEvent::Reconnected(name, info) => {
+                        if let Some(tab) = self.tab_mut(&name) {
+                            tab.info = info;
+                            tab.reconnecting = false;
+                        }
+                        if let Some(worker_handle) = &self.device_handle {
+                            let _ = worker_handle
+                                .send(Commands::DeviceCommand(name, DeviceCommands::GetState));
+                        }
+                        Command::none()
+                    }
+                    WorkerEvent::ConnectFailed(e) => {
+                        self.connect_error = Some(e);
+                        Command::none()
+                    }
+                    WorkerEvent::Reconnecting(name) => {
+                        if let Some(tab) = self.tab_mut(&name) {
+                            tab.reconnecting = true;
+                            // Its sequence stopped with the connection.
+                            tab.sequence_running = false;
+                        }
+                        Command::none()
+                    }
+                    WorkerEvent::Disconnected(name) => {
+                        self.tabs.retain(|tab| tab.name != name);
+                        if self.selected.as_ref() == Some(&name) {
+                            self.selected = self.tabs.first().map(|tab| tab.name.clone());
+                        }
+                        if self.tabs.is_empty() {
+                            self.state = AppState::HomePage;
+                        }
+                        Command::none()
+                    }
+                    WorkerEvent::DeviceState(name, s) => {
+                        if let Some(tab) = self.tab_mut(&name) {
+                            tab.state = Some(s);
+                            tab.error = None;
+                        }
+                        Command::none()
+                    }
+                    WorkerEvent::DeviceError(name, e) => {
+                        if let Some(tab) = self.tab_mut(&name) {
+                            tab.error = Some(e);
+                        }
+                        Command::none()
+                    }
+                    WorkerEvent::McuEvent(name, event) => {
+                        if let Some(tab) = self.tab_mut(&name) {
+                            match event {
+                                DeviceEvent::Button => tab.button_presses += 1,
+                                DeviceEvent::Telemetry(s) => tab.state = Some(s),
+                                DeviceEvent::Fault(f) => {
+                                    tab.error = Some(format!("Device fault: {:?}", f))
+                                }
+                            }
+                        }
+                        Command::none()
+                    }
+                    WorkerEvent::Status(status) => {
+                        for s in status {
+                            if let Some(tab) = self.tab_mut(&s.name) {
+                                match s.state {
+                                    Ok(state) => {
+                                        tab.state = Some(state);
+                                        tab.error = None;
+                                    }
+                                    Err(e) => tab.error = Some(e),
+                                }
+                            }
+                        }
+                        Command::none()
+                    }
+                    WorkerEvent::SequenceReport(name, report) => {
+                        if let Some(tab) = self.tab_mut(&name) {
+                            tab.sequence_running = false;
+                            tab.sequence_report = Some(report);
+                        }
+                        Command::none()
+                    }
+                    WorkerEvent::ScriptLog(name, line) => {
+                        if let Some(tab) = self.tab_mut(&name) {
+                            tab.script_log.push(line);
+                        }
                         Command::none()
                     }
-                    WorkerEvent::Disconnected => {
-                        self.state = AppState::HomePage;
+                    WorkerEvent::ScriptFinished(name, result) => {
+                        if let Some(tab) = self.tab_mut(&name) {
+                            tab.script_running = false;
+                            tab.script_error = result.err();
+                        }
                         Command::none()
                     }
                     _ => Command::none(),
@@ -93,7 +404,7 @@ impl Application for App {
             }
             Protocol::WorkerCommand(cmd) => {
                 if let Some(worker_handle) = &self.device_handle {
-                    worker_handle.send(cmd);
+                    let _ = worker_handle.send(cmd);
                 }
                 Command::none()
             }
@@ -111,10 +422,12 @@ impl Application for App {
         connect().map(Protocol::WorkerEvent)
     }
 
-    fn view(&self) -> Element<Protocol> {
+    fn view(&self) -> Element<'_, Protocol> {
         let c = match self.state {
-            AppState::HomePage => main_page(&self),
-            AppState::ControlPage => control_page(&self),
+            AppState::HomePage => main_page(self),
+            AppState::ControlPage => control_page(self),
+            AppState::SequencePage => sequence_page(self),
+            AppState::ScriptPage => script_page(self),
         };
         Container::new(c)
             .width(Length::Fill)
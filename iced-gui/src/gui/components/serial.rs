@@ -7,9 +7,10 @@ use iced::Element;
 use iced_lazy::Component;
 use iced_native;
 use iced_style;
-use std::time::Duration;
 use tokio_serial::{self, DataBits, Parity, StopBits};
 
+pub use iced_driver::SerialPortParams;
+
 const BAUDRATES: [u32; 14] = [
     110, 300, 600, 1200, 2400, 4800, 9600, 14400, 19200, 38400, 57600, 115200, 128000, 256000,
 ];
@@ -104,15 +105,6 @@ impl std::fmt::Display for GuiStopBits {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-pub struct SerialPortParams {
-    pub baudrate: u32,
-    pub parity: Parity,
-    pub data_bits: DataBits,
-    pub stop_bits: StopBits,
-    pub timeout: std::time::Duration,
-}
-
 pub struct SerialPortComponent<Message> {
     params: SerialPortParams,
     on_change: Box<dyn Fn(SerialPortParams) -> Message>,
@@ -124,7 +116,7 @@ impl<Message> SerialPortComponent<Message> {
         on_change: impl Fn(SerialPortParams) -> Message + 'static,
     ) -> Self {
         Self {
-            params: params,
+            params,
             on_change: Box::new(on_change),
         }
     }
@@ -253,15 +245,3 @@ pub enum SerialPortParamsMessage {
     None,
 }
 
-impl SerialPortParams {
-    pub fn new() -> Self {
-        Self {
-            baudrate: 115200,
-            parity: Parity::None,
-            data_bits: DataBits::Eight,
-            stop_bits: StopBits::One,
-            timeout: Duration::from_secs(1),
-        }
-    }
-}
-
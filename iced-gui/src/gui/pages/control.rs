@@ -1,13 +1,13 @@
-use crate::gui::app::App;
+use crate::gui::app::{App, DeviceTab};
 use crate::gui::protocol::Protocol;
 use iced::alignment::{Alignment, Horizontal};
 
 use crate::controller::Commands;
 use iced::widget::{button, row, slider, text, Column, Container};
 use iced::{Length};
-use iced::{Element, Theme};
+use iced::{theme, Element, Theme};
 
-use iced_driver::DeviceCommands;
+use iced_driver::{DeviceCommands, DeviceState};
 use iced_native::widget::container::{Appearance, StyleSheet};
 
 const SPACING: f32 = 20.0;
@@ -39,7 +39,85 @@ pub fn center_aligned_button(
     )
 }
 
-pub fn control_page(app: &App) -> Element<Protocol> {
+pub fn device_state_view(name: &str, state: Option<DeviceState>) -> Element<'static, Protocol> {
+    let on_off = |b: bool| if b { "ON" } else { "OFF" };
+    let status: Element<Protocol> = match state {
+        Some(s) => Column::new()
+            .spacing(5)
+            .align_items(Alignment::Center)
+            .push(text(format!("LED: {}", on_off(s.led_on))))
+            .push(text(format!(
+                "PWM: {}, {} %, {} Hz",
+                on_off(s.pwm_enabled),
+                s.pwm_duty,
+                s.pwm_frequency
+            )))
+            .push(text(format!("Uptime: {:.1} s", s.uptime().as_secs_f32())))
+            .into(),
+        None => text("Device state unknown").into(),
+    };
+    row![
+        status,
+        button("Refresh").on_press(Protocol::WorkerCommand(Commands::DeviceCommand(
+            name.to_string(),
+            DeviceCommands::GetState
+        )))
+    ]
+    .spacing(SPACING)
+    .align_items(Alignment::Center)
+    .into()
+}
+
+/// One button per open board, the selected one highlighted, and one to open another.
+fn tab_bar(app: &App) -> Element<'_, Protocol> {
+    let mut tabs = row![].spacing(5).align_items(Alignment::Center);
+    for tab in &app.tabs {
+        let mut label = tab.name.clone();
+        if tab.reconnecting {
+            label.push_str(" (lost)");
+        }
+        let style = if app.selected.as_ref() == Some(&tab.name) {
+            theme::Button::Primary
+        } else {
+            theme::Button::Secondary
+        };
+        tabs = tabs.push(
+            button(text(label))
+                .style(style)
+                .on_press(Protocol::SelectDevice(tab.name.clone())),
+        );
+    }
+    tabs.push(button("+").on_press(Protocol::ShowHomePage))
+        .into()
+}
+
+/// Commands for every open board at once.
+fn all_devices(app: &App) -> Element<'_, Protocol> {
+    let all = |command| Protocol::WorkerCommand(Commands::Broadcast(command));
+    row![
+        text("All boards:"),
+        button("LED ON").on_press(all(DeviceCommands::SetGpioPin)),
+        button("LED OFF").on_press(all(DeviceCommands::ClearGpioPin)),
+        button("PWM OFF").on_press(all(DeviceCommands::PwmOff)),
+        button(text(format!("Set Duty {} %", app.pwm_duty)))
+            .on_press(all(DeviceCommands::PwmDuty(app.pwm_duty))),
+        button("Refresh").on_press(Protocol::WorkerCommand(Commands::Status))
+    ]
+    .spacing(10)
+    .align_items(Alignment::Center)
+    .into()
+}
+
+pub fn control_page(app: &App) -> Element<'_, Protocol> {
+    match app.tab() {
+        Some(tab) => control_tab(app, tab),
+        None => text("No board open").into(),
+    }
+}
+
+fn control_tab<'a>(app: &'a App, tab: &'a DeviceTab) -> Element<'a, Protocol> {
+    let device =
+        |command| Protocol::WorkerCommand(Commands::DeviceCommand(tab.name.clone(), command));
     // let my_app = ContainerStyles(Appearance {
     //     text_color: None,
     //     background: Some(iced::Background::Color(Color::from_rgba8(0,0,0,0.0))),
@@ -53,14 +131,16 @@ pub fn control_page(app: &App) -> Element<Protocol> {
         .align_items(Alignment::Center);
 
     main_column = main_column.push("Iced Device Control");
+    main_column = main_column.push(tab_bar(app));
+    if app.tabs.len() > 1 {
+        main_column = main_column.push(all_devices(app));
+    }
     main_column = main_column.push(
         row![
-            center_aligned_button("LED ON".into(), 100.0).on_press(Protocol::WorkerCommand(
-                Commands::DeviceCommand(DeviceCommands::SetGpioPin)
-            )),
-            center_aligned_button("LED OFF".into(), 100.0).on_press(Protocol::WorkerCommand(
-                Commands::DeviceCommand(DeviceCommands::ClearGpioPin)
-            ))
+            center_aligned_button("LED ON".into(), 100.0)
+                .on_press(device(DeviceCommands::SetGpioPin)),
+            center_aligned_button("LED OFF".into(), 100.0)
+                .on_press(device(DeviceCommands::ClearGpioPin))
         ]
         .spacing(SPACING)
         .align_items(Alignment::Center),
@@ -68,12 +148,8 @@ pub fn control_page(app: &App) -> Element<Protocol> {
 
     main_column = main_column.push(
         row![
-            center_aligned_button("PWM ON".into(), 100.0).on_press(Protocol::WorkerCommand(
-                Commands::DeviceCommand(DeviceCommands::PwmOn)
-            )),
-            center_aligned_button("PWM OFF".into(), 100.0).on_press(Protocol::WorkerCommand(
-                Commands::DeviceCommand(DeviceCommands::PwmOff)
-            ))
+            center_aligned_button("PWM ON".into(), 100.0).on_press(device(DeviceCommands::PwmOn)),
+            center_aligned_button("PWM OFF".into(), 100.0).on_press(device(DeviceCommands::PwmOff))
         ]
         .spacing(SPACING)
         .align_items(Alignment::Center),
@@ -89,11 +165,8 @@ pub fn control_page(app: &App) -> Element<Protocol> {
             text(duty)
                 .width(75)
                 .horizontal_alignment(Horizontal::Center),
-            button(Container::new("Set Duty").width(150).center_x().center_y()).on_press(
-                Protocol::WorkerCommand(Commands::DeviceCommand(DeviceCommands::PwmDuty(
-                    app.pwm_duty
-                )))
-            )
+            button(Container::new("Set Duty").width(150).center_x().center_y())
+                .on_press(device(DeviceCommands::PwmDuty(app.pwm_duty)))
         ]
         .spacing(SPACING)
         .align_items(Alignment::Center),
@@ -112,15 +185,33 @@ pub fn control_page(app: &App) -> Element<Protocol> {
                     .center_x()
                     .center_y()
             )
-            .on_press(Protocol::WorkerCommand(Commands::DeviceCommand(
-                DeviceCommands::PwmSetFreq(app.pwm_frequency)
-            )))
+            .on_press(device(DeviceCommands::PwmSetFreq(app.pwm_frequency)))
         ]
         .spacing(SPACING)
         .align_items(Alignment::Center),
     );
-    main_column =
-        main_column.push(button("Close").on_press(Protocol::WorkerCommand(Commands::Disconnect)));
+    main_column = main_column.push(text(format!(
+        "{}, firmware {}",
+        tab.info.board, tab.info.firmware_version
+    )));
+    if tab.reconnecting {
+        main_column = main_column.push(text("Connection lost, waiting for the board to come back"));
+    }
+    main_column = main_column.push(device_state_view(&tab.name, tab.state));
+    main_column = main_column.push(text(format!("Button presses: {}", tab.button_presses)));
+    if let Some(e) = &tab.error {
+        main_column = main_column.push(text(e));
+    }
+    main_column = main_column.push(
+        row![
+            button("Sequences").on_press(Protocol::ShowSequencePage),
+            button("Scripts").on_press(Protocol::ShowScriptPage),
+            button("Close").on_press(Protocol::WorkerCommand(Commands::Disconnect(
+                tab.name.clone()
+            )))
+        ]
+        .spacing(SPACING),
+    );
 
     Container::new(main_column)
         // .style(my_app)
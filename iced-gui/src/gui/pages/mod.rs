@@ -1,2 +1,4 @@
 pub mod control;
 pub mod open_page;
+pub mod script;
+pub mod sequence;
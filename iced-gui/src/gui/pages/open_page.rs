@@ -3,12 +3,12 @@ use crate::gui::components::serial::SerialPortComponent;
 use crate::gui::protocol::Protocol;
 use iced::alignment::Alignment;
 
-use iced::widget::{button, row, scrollable, text, Column, Container};
+use iced::widget::{button, checkbox, row, scrollable, text, text_input, Column, Container};
 use iced::Element;
 use iced::{Length};
 
 
-pub fn main_page(app: &App) -> Element<Protocol> {
+pub fn main_page(app: &App) -> Element<'_, Protocol> {
     // let _s = iced::widget::button::Appearance {
     //     shadow_offset: Vector::default(),
     //     background: None,
@@ -24,12 +24,19 @@ pub fn main_page(app: &App) -> Element<Protocol> {
 
     let b = button("Refresh Ports").on_press(Protocol::RefreshPorts);
     let c = Column::with_children(
-        app.ports
+        app.devices
             .iter()
-            .map(|port| {
+            .map(|device| {
+                let serial = device.serial_number.as_deref().unwrap_or("no serial number");
+                let board = match &device.info {
+                    Some(info) => format!("{}, firmware {}", info.board, info.firmware_version),
+                    None => String::new(),
+                };
                 row![
-                    text(&port.port_name.to_string()),
-                    button("Open Port").on_press(Protocol::OpenPort(port.port_name.to_string()))
+                    text(device.port_name.to_string()),
+                    text(serial),
+                    text(board),
+                    button("Open Port").on_press(Protocol::OpenPort(device.port_name.to_string()))
                 ]
                 // row![
                 //     text(port).width(150),
@@ -46,13 +53,33 @@ pub fn main_page(app: &App) -> Element<Protocol> {
 
     let port_container = scrollable(c).height(200);
 
-    let content = Column::new()
+    let filters = row![
+        checkbox("Only ST-Link boards", app.st_link_only, Protocol::StLinkOnly),
+        checkbox("Identify devices", app.probe_ports, Protocol::ProbePorts)
+    ]
+    .spacing(20);
+
+    let remote = row![
+        text_input("socket://host:port", &app.remote_address, Protocol::RemoteAddress).width(300),
+        button("Open Remote").on_press(Protocol::OpenPort(app.remote_address.clone()))
+    ]
+    .spacing(20)
+    .align_items(Alignment::Center);
+
+    let mut content = Column::new()
         .align_items(Alignment::Center)
         .spacing(10)
         .push(sp)
         .push(text("Select a port from the list below"))
+        .push(filters)
         .push(b)
-        .push(port_container);
+        .push(port_container)
+        .push(remote)
+        .push(text(app.discovery_error.as_deref().unwrap_or("")))
+        .push(text(app.connect_error.as_deref().unwrap_or("")));
+    if !app.tabs.is_empty() {
+        content = content.push(button("Back").on_press(Protocol::ShowControlPage));
+    }
 
     Container::new(content)
         .width(Length::Fill)
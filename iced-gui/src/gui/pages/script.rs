@@ -0,0 +1,97 @@
+use crate::controller::Commands;
+use crate::gui::app::App;
+use crate::gui::protocol::Protocol;
+use iced::alignment::Alignment;
+use iced::widget::{button, row, scrollable, text, text_input, Column, Container};
+use iced::Element;
+use iced::Length;
+
+const SPACING: f32 = 20.0;
+
+/// One text input per line, Enter opens a new line below.
+fn editor(lines: &[String]) -> Element<'_, Protocol> {
+    let lines = Column::with_children(
+        lines
+            .iter()
+            .enumerate()
+            .map(|(i, line)| {
+                row![
+                    text(format!("{:>3}", i + 1)).width(30),
+                    text_input("", line, move |x| Protocol::ScriptLine(i, x))
+                        .on_submit(Protocol::ScriptNewLine(i))
+                        .padding(2)
+                ]
+                .spacing(10)
+                .align_items(Alignment::Center)
+                .into()
+            })
+            .collect(),
+    )
+    .spacing(2)
+    .padding(10);
+    scrollable(lines).height(300).into()
+}
+
+fn log_view(log: &[String]) -> Element<'_, Protocol> {
+    let lines = Column::with_children(log.iter().map(|line| text(line).into()).collect())
+        .spacing(2)
+        .padding(10);
+    scrollable(lines).height(150).into()
+}
+
+pub fn script_page(app: &App) -> Element<'_, Protocol> {
+    let tab = app.tab();
+    let mut run = button("Run");
+    let mut cancel = button("Stop");
+    match tab {
+        Some(tab) if tab.script_running => {
+            cancel = cancel.on_press(Protocol::WorkerCommand(Commands::CancelScript(
+                tab.name.clone(),
+            )));
+        }
+        Some(_) => run = run.on_press(Protocol::RunScript),
+        None => (),
+    }
+
+    let mut content = Column::new()
+        .spacing(SPACING)
+        .align_items(Alignment::Center)
+        .push("Scripts")
+        .push(
+            row![
+                text_input(
+                    "Path to a .rhai script",
+                    &app.script_path,
+                    Protocol::ScriptPath
+                )
+                .width(400),
+                button("Load").on_press(Protocol::LoadScript),
+                button("Save").on_press(Protocol::SaveScript)
+            ]
+            .spacing(SPACING)
+            .align_items(Alignment::Center),
+        )
+        .push(editor(&app.script_lines))
+        .push(row![run, cancel].spacing(SPACING));
+
+    if let Some(e) = &app.script_error {
+        content = content.push(text(e));
+    }
+    if let Some(tab) = tab {
+        if tab.script_running {
+            content = content.push(text(format!("Running on {}...", tab.name)));
+        }
+        if let Some(e) = &tab.script_error {
+            content = content.push(text(e));
+        }
+        content = content.push(log_view(&tab.script_log));
+    }
+    content = content.push(button("Back").on_press(Protocol::ShowControlPage));
+
+    Container::new(content)
+        .width(Length::Fill)
+        .height(Length::Fill)
+        .center_x()
+        .center_y()
+        .into()
+}
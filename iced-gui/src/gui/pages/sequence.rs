@@ -0,0 +1,99 @@
+use crate::gui::app::App;
+use crate::gui::protocol::Protocol;
+use iced::alignment::Alignment;
+use iced::widget::{button, row, scrollable, text, text_input, Column, Container};
+use iced::Element;
+use iced::Length;
+use iced_driver::runner::{Outcome, Report};
+
+const SPACING: f32 = 20.0;
+
+fn report_view(report: &Report) -> Element<'_, Protocol> {
+    let steps = Column::with_children(
+        report
+            .steps
+            .iter()
+            .map(|step| {
+                let (result, detail) = match &step.outcome {
+                    Outcome::Passed => ("pass", String::new()),
+                    Outcome::Read(response) => ("read", format!("{:?}", response)),
+                    Outcome::Failed(message) => ("FAIL", message.clone()),
+                };
+                row![
+                    text(format!("{:.3} s", step.at.as_secs_f32())).width(80),
+                    text(result).width(50),
+                    text(&step.description).width(250),
+                    text(detail)
+                ]
+                .spacing(10)
+                .into()
+            })
+            .collect(),
+    )
+    .spacing(5)
+    .padding(10);
+
+    let summary = format!(
+        "{}: {}, {} steps, {} failed, {:.3} s",
+        if report.name.is_empty() {
+            "Sequence"
+        } else {
+            &report.name
+        },
+        if report.passed() { "PASSED" } else { "FAILED" },
+        report.steps.len(),
+        report.failures(),
+        report.elapsed.as_secs_f32()
+    );
+    Column::new()
+        .spacing(10)
+        .align_items(Alignment::Center)
+        .push(text(summary))
+        .push(scrollable(steps).height(300))
+        .into()
+}
+
+pub fn sequence_page(app: &App) -> Element<'_, Protocol> {
+    let tab = app.tab();
+    let mut run = button("Run");
+    if tab.is_some_and(|tab| !tab.sequence_running) {
+        run = run.on_press(Protocol::RunSequence);
+    }
+    let mut content = Column::new()
+        .spacing(SPACING)
+        .align_items(Alignment::Center)
+        .push("Sequences")
+        .push(
+            row![
+                text_input(
+                    "Path to a .toml sequence",
+                    &app.sequence_path,
+                    Protocol::SequencePath
+                )
+                .width(400),
+                run
+            ]
+            .spacing(SPACING)
+            .align_items(Alignment::Center),
+        );
+
+    if let Some(e) = &app.sequence_error {
+        content = content.push(text(e));
+    }
+    if let Some(tab) = tab {
+        if tab.sequence_running {
+            content = content.push(text(format!("Running on {}...", tab.name)));
+        }
+        if let Some(report) = &tab.sequence_report {
+            content = content.push(report_view(report));
+        }
+    }
+    content = content.push(button("Back").on_press(Protocol::ShowControlPage));
+
+    Container::new(content)
+        .width(Length::Fill)
+        .height(Length::Fill)
+        .center_x()
+        .center_y()
+        .into()
+}
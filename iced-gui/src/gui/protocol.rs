@@ -3,16 +3,34 @@
 // }
 use crate::controller;
 use crate::gui::components::serial::SerialPortParams;
+use iced_driver::FoundDevice;
 
 #[derive(Debug, Clone)]
 pub enum Protocol {
     Debug,
     RefreshPorts,
+    DevicesFound(Result<Vec<FoundDevice>, String>),
+    StLinkOnly(bool),
+    ProbePorts(bool),
     OpenPort(String),
+    RemoteAddress(String),
     ChangeSlider(i32),
     PwmFrequency(u32),
     PwmDuty(u8),
     SerialPortParams(SerialPortParams),
+    ShowHomePage,
+    SelectDevice(String),
+    ShowSequencePage,
+    ShowControlPage,
+    SequencePath(String),
+    RunSequence,
+    ShowScriptPage,
+    ScriptPath(String),
+    LoadScript,
+    SaveScript,
+    ScriptLine(usize, String),
+    ScriptNewLine(usize),
+    RunScript,
     WorkerEvent(controller::WorkerEvent),
     WorkerCommand(controller::Commands),
 }
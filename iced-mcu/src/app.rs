@@ -1,11 +1,17 @@
 #![no_std]
 #![no_main]
 
+use iced_protocol::{DeviceState, Framing};
+
 pub struct AppState {
     pub pwm_period: u16,
     pub pwm_duty_cycle: u16,
+    pub pwm_frequency: u32,
     pub pwm_state: bool,
     pub led_state: bool,
+    pub framing: Framing,
+    /// Milliseconds between telemetry events, 0 when they're off.
+    pub telemetry_period: u16,
 }
 
 impl AppState {
@@ -13,8 +19,22 @@ impl AppState {
         Self {
             pwm_period: 0,
             pwm_duty_cycle: 0,
+            pwm_frequency: 0,
             pwm_state: false,
             led_state: false,
+            framing: Framing::Line,
+            telemetry_period: 0,
+        }
+    }
+
+    /// Builds the `GetState` report, `uptime_ms` comes from the SysTick counter.
+    pub fn report(&self, uptime_ms: u32) -> DeviceState {
+        DeviceState {
+            led_on: self.led_state,
+            pwm_enabled: self.pwm_state,
+            pwm_duty: self.pwm_duty_cycle as u8,
+            pwm_frequency: self.pwm_frequency,
+            uptime_ms,
         }
     }
 }
@@ -5,11 +5,10 @@ pub mod app;
 pub mod protocol;
 use core::borrow::BorrowMut;
 use core::cell::RefCell;
-use core::fmt::Write;
 use core::ops::DerefMut;
 use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use cortex_m::{
-    interrupt::{free, Mutex},
+    interrupt::{free, CriticalSection, Mutex},
     peripheral::NVIC,
 };
 use cortex_m_rt::{entry, exception};
@@ -31,22 +30,27 @@ use stm32l4xx_hal::{
 };
 
 use app::AppState;
-use protocol::{parse_command, AppCommand};
+use protocol::{device_info, AppCommand, DeviceEvent, Fault, Framing, FRAME_SIZE};
+#[cfg(not(feature = "modbus"))]
+use protocol::{parse_request, Instrument, Request, Response, Seq};
+#[cfg(feature = "modbus")]
+use protocol::{Transaction, MODBUS_SILENCE_BITS, MODBUS_UNIT};
 
 static TIM: Mutex<RefCell<Option<Timer<TIM2>>>> = Mutex::new(RefCell::new(None));
 static MILLIS: AtomicU32 = AtomicU32::new(0);
 static MESSAGE_RECEIVED: AtomicBool = AtomicBool::new(false);
 static MESSAGE_SENT: AtomicBool = AtomicBool::new(true);
-static FRAME_SENDER: Mutex<RefCell<Option<FrameSender<Box<SerialDMA>, TxDma2, 100>>>> =
+static RX_OVERRUN: AtomicBool = AtomicBool::new(false);
+static FRAME_SENDER: Mutex<RefCell<Option<FrameSender<Box<SerialDMA>, TxDma2, FRAME_SIZE>>>> =
     Mutex::new(RefCell::new(None));
-static FRAME_READER: Mutex<RefCell<Option<FrameReader<Box<SerialDMA>, RxDma2, 100>>>> =
+static FRAME_READER: Mutex<RefCell<Option<FrameReader<Box<SerialDMA>, RxDma2, FRAME_SIZE>>>> =
     Mutex::new(RefCell::new(None));
 // static PWM_HANDLE: Mutex<RefCell<Option<dyn Pins<TIM2, Channels=(bool, PB3, bool, bool)>>>> = Mutex::new(RefCell::new(None))
 static PWM_HANDLE: Mutex<RefCell<Option<Pwm<TIM2, C2>>>> = Mutex::new(RefCell::new(None));
-type MessageFrame = Vec<u8, 100>;
+type MessageFrame = Vec<u8, FRAME_SIZE>;
 static MESSAGE: Mutex<RefCell<MessageFrame>> = Mutex::new(RefCell::new(Vec::new()));
 
-pool!(SerialDMA: DMAFrame<100>);
+pool!(SerialDMA: DMAFrame<FRAME_SIZE>);
 
 
 #[exception]
@@ -112,17 +116,27 @@ fn USART2() {
         let mut fr_ref = FRAME_READER.borrow(cs).borrow_mut();
         // let mut fs_ref = FRAME_SENDER.borrow(cs).borrow_mut();
         if let Some(ref mut fr) = fr_ref.deref_mut() {
-            if fr.check_character_match(true) {
+            // Modbus RTU frames end with a silence instead of a delimiter.
+            #[cfg(not(feature = "modbus"))]
+            let frame_ended = fr.check_character_match(true);
+            #[cfg(feature = "modbus")]
+            let frame_ended = fr.check_receiver_timeout(true);
+            if frame_ended {
                 if let Some(dma_buf) = SerialDMA::alloc() {
                     let dma_buf = dma_buf.init(DMAFrame::new());
                     let mut msg = MESSAGE.borrow(cs).borrow_mut();
                     if let ref mut msg_ref = msg.deref_mut() {
+                        #[cfg(not(feature = "modbus"))]
                         let buf = fr.character_match_interrupt(dma_buf);
+                        #[cfg(feature = "modbus")]
+                        let buf = fr.receiver_timeout_interrupt(dma_buf);
                         msg_ref.extend_from_slice(buf.read());
                         MESSAGE_RECEIVED.store(true, Ordering::Relaxed);
                     }
                     // Echo the buffer back over the serial
                     // cx.resources.frame_sender.send(buf).ok();
+                } else {
+                    RX_OVERRUN.store(true, Ordering::Relaxed);
                 }
             }
             // if let Some(_buf) = fs.transfer_complete_interrupt() {
@@ -132,6 +146,56 @@ fn USART2() {
     });
 }
 
+#[cfg(not(feature = "modbus"))]
+fn send_response(cs: &CriticalSection, response: &Response, seq: Option<Seq>, framing: Framing) {
+    let mut line = [0u8; FRAME_SIZE];
+    if let Ok(n) = response.encode_seq(seq, &mut line) {
+        send_line(cs, &line[..n], framing);
+    }
+}
+
+fn send_event(cs: &CriticalSection, event: &DeviceEvent, framing: Framing) {
+    let mut line = [0u8; FRAME_SIZE];
+    if let Ok(n) = event.encode(&mut line) {
+        send_line(cs, &line[..n], framing);
+    }
+}
+
+/// Wraps a line frame in `framing` and starts sending it.
+fn send_line(cs: &CriticalSection, line: &[u8], framing: Framing) {
+    let mut out = [0u8; FRAME_SIZE];
+    if let Ok(n) = framing.wrap(line, &mut out) {
+        send_frame(cs, &out[..n]);
+    }
+}
+
+/// Starts sending `frame` as is, dropped if a transfer is ongoing.
+fn send_frame(cs: &CriticalSection, frame: &[u8]) {
+    let mut fs_ref = FRAME_SENDER.borrow(cs).borrow_mut();
+    if let Some(ref mut fs) = fs_ref.deref_mut() {
+        if let Some(dma_buf) = SerialDMA::alloc() {
+            let mut dma_buf = dma_buf.init(DMAFrame::new());
+            dma_buf.write_slice(frame);
+            if fs.send(dma_buf).is_ok() {
+                MESSAGE_SENT.store(false, Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+/// Points the USART2 character match at a new frame delimiter. Waits for the
+/// last byte to leave the shift register since the USART has to be disabled.
+fn set_character_match(delimiter: u8) {
+    // The HAL doesn't expose this after construction, the serial halves are
+    // only touched from interrupts that stay quiet while UE is cleared.
+    let usart = unsafe { &*stm32::USART2::ptr() };
+    while usart.isr.read().tc().bit_is_clear() {}
+    usart.cr1.modify(|_, w| w.ue().clear_bit());
+    // Compare all 8 bits, not just the low 4 the HAL configures.
+    usart.cr2.modify(|_, w| w.addm7().set_bit().add().bits(delimiter));
+    usart.cr1.modify(|_, w| w.ue().set_bit());
+}
+
 #[entry]
 fn main() -> ! {
     static mut MEMORY: [u8; 1024] = [0; 1024];
@@ -166,10 +230,15 @@ fn main() -> ! {
     // The registers for GPIO A are controlled by the AHB2 (Advanced High-performance Bus 2)
     let mut gpioa = p.GPIOA.split(&mut rcc.ahb2);
     let mut gpiob = p.GPIOB.split(&mut rcc.ahb2);
+    let mut gpioc = p.GPIOC.split(&mut rcc.ahb2);
     // We configure the user_led to be a push pull output.
     let mut user_led = gpioa
         .pa5
         .into_push_pull_output(&mut gpioa.moder, &mut gpioa.otyper);
+    // The blue user button is on PC13, pulled up on the board and low while pressed.
+    let user_button = gpioc
+        .pc13
+        .into_floating_input(&mut gpioc.moder, &mut gpioc.pupdr);
     // Unmask the TIM2 interrupt to allow the interrupt to trigger
     unsafe {
         NVIC::unmask(stm32::Interrupt::TIM2);
@@ -194,12 +263,18 @@ fn main() -> ! {
     let config = stm32l4xx_hal::serial::Config::default()
         .baudrate(115200.bps())
         .parity_none()
-        .stopbits(stm32l4xx_hal::serial::StopBits::STOP1)
-        .character_match(b'\n');
+        .stopbits(stm32l4xx_hal::serial::StopBits::STOP1);
+    #[cfg(not(feature = "modbus"))]
+    let config = config.character_match(b'\n');
+    #[cfg(feature = "modbus")]
+    let config = config.receiver_timeout(MODBUS_SILENCE_BITS);
     let mut serial = serial::Serial::usart2(p.USART2, (tx, rx), config, clocks, &mut rcc.apb1r1);
 
     // Listen for interrupt on reception
+    #[cfg(not(feature = "modbus"))]
     serial.listen(stm32l4xx_hal::serial::Event::CharacterMatch);
+    #[cfg(feature = "modbus")]
+    serial.listen(stm32l4xx_hal::serial::Event::ReceiverTimeout);
     // let (mut tx, mut rx) = serial.split();
     let (tx, rx) = serial.split();
     // Get DMA Channels
@@ -216,7 +291,7 @@ fn main() -> ! {
     let tx_dma = tx.with_dma(dma_ch7);
     let rx_dma = rx.with_dma(dma_ch6);
 
-    let fs: FrameSender<Box<SerialDMA>, _, 100> = tx_dma.frame_sender();
+    let fs: FrameSender<Box<SerialDMA>, _, FRAME_SIZE> = tx_dma.frame_sender();
     let fr = if let Some(dma_buf) = SerialDMA::alloc() {
         let dma_buf = dma_buf.init(DMAFrame::new());
         rx_dma.frame_reader(dma_buf)
@@ -243,21 +318,35 @@ fn main() -> ! {
     });
 
     let mut app = AppState::new();
-    let max_duty = pwm.get_max_duty();
     app.pwm_state = true;
-    app.pwm_duty_cycle = (max_duty / 4) as u16;
+    app.pwm_duty_cycle = 25;
+    app.pwm_frequency = 1000;
     let max_duty = pwm.get_max_duty();
     pwm.set_duty(max_duty / 4);
     pwm.enable();
 
     
     let mut t = millis();
+    let mut button_down = false;
+    let mut button_pressed_at = 0;
+    let mut button_pending = false;
+    #[cfg(not(feature = "modbus"))]
+    let mut instrument = Instrument::new();
     loop {
         if MESSAGE_RECEIVED.load(Ordering::Relaxed) {
+            let mut next_framing = None;
             free(|cs| {
                 let mut msg = MESSAGE.borrow(cs).borrow_mut();
                 if let ref mut msg_ref = msg.deref_mut() {
-                    if let Some(app_command) = parse_command(msg_ref.as_slice()) {
+                    #[cfg(not(feature = "modbus"))]
+                    let request = parse_request(msg_ref.as_slice(), app.framing, &mut instrument);
+                    #[cfg(not(feature = "modbus"))]
+                    let commands = request.commands().iter().copied();
+                    #[cfg(feature = "modbus")]
+                    let transaction = Transaction::parse(msg_ref.as_slice(), MODBUS_UNIT, &app.report(millis()));
+                    #[cfg(feature = "modbus")]
+                    let commands = transaction.iter().flat_map(Transaction::commands);
+                    for app_command in commands {
                         match app_command {
                             AppCommand::SetGpioPin => {
                                 app.led_state = true;
@@ -276,6 +365,7 @@ fn main() -> ! {
                                 pwm.disable();
                             },
                             AppCommand::PwmDuty(duty) => {
+                                let duty = u16::from(duty);
                                 if duty > 100 {
                                 } else {
                                     app.pwm_duty_cycle = duty; 
@@ -284,6 +374,7 @@ fn main() -> ! {
                                 }
                             },
                             AppCommand::PwmSetFreq(hz) => {
+                                app.pwm_frequency = hz;
                                 if app.pwm_state {
                                     pwm.disable();
                                     unsafe {
@@ -346,36 +437,55 @@ fn main() -> ! {
                                     pwm.set_duty((((max_duty/100) as u16) * app.pwm_duty_cycle).into());
                                 }
                             },
-                            // AppCommand::GetTime => {},
-                            // AppCommand::GetStatus => {},
+                            AppCommand::SetFraming(framing) => {
+                                next_framing = Some(framing);
+                            },
+                            AppCommand::SetTelemetry(ms) => {
+                                app.telemetry_period = ms;
+                                t = millis();
+                            },
                             _ => (),
                         };
-                        let mut fs_ref = FRAME_SENDER.borrow(cs).borrow_mut();
-                        if let Some(ref mut fs) = fs_ref.deref_mut() {
-                            if let Some(dma_buf) = SerialDMA::alloc() {
-                                let mut dma_buf = dma_buf.init(DMAFrame::new());
-                                dma_buf.write_slice(msg_ref.as_slice());
-                                if fs.send(dma_buf).is_ok() {
-                                    MESSAGE_SENT.store(false, Ordering::SeqCst);
-                                }
-                            }
+                    }
+                    #[cfg(not(feature = "modbus"))]
+                    match request {
+                        Request::Command(seq, Ok(app_command)) => {
+                            let response = match app_command {
+                                AppCommand::GetTime => Response::Time(millis()),
+                                AppCommand::GetState => Response::State(app.report(millis())),
+                                AppCommand::Identify => Response::Identity(device_info()),
+                                _ => Response::Ack(app_command),
+                            };
+                            send_response(cs, &response, seq, app.framing);
                         }
-                    } else {
-                        let mut fs_ref = FRAME_SENDER.borrow(cs).borrow_mut();
-                        if let Some(ref mut fs) = fs_ref.deref_mut() {
-                            if let Some(dma_buf) = SerialDMA::alloc() {
-                                let mut dma_buf = dma_buf.init(DMAFrame::new());
-                                let _ = write!(dma_buf, "X\n");
-                                if fs.send(dma_buf).is_ok() {
-                                    MESSAGE_SENT.store(false, Ordering::SeqCst);
-                                }
+                        Request::Command(seq, Err(code)) => send_response(cs, &Response::Error(code), seq, app.framing),
+                        // SCPI only answers queries, errors wait in the queue for `SYST:ERR?`.
+                        Request::Scpi(Some(message)) => {
+                            let mut line = [0u8; FRAME_SIZE];
+                            if let Some(n) = instrument.answer(&message, &app.report(millis()), &device_info(), &mut line) {
+                                send_frame(cs, &line[..n]);
                             }
                         }
+                        Request::Scpi(None) => (),
+                    }
+                    #[cfg(feature = "modbus")]
+                    if let Some(transaction) = transaction {
+                        let mut adu = [0u8; FRAME_SIZE];
+                        if let Some(n) = transaction.reply(&app.report(millis()), &device_info(), &mut adu) {
+                            send_frame(cs, &adu[..n]);
+                        }
                     }
                     msg_ref.clear();
                 }
             });
-            user_led.set_state(PinState::from(app.led_state));
+            // The acknowledgement goes out in the old framing, switch once it's sent.
+            if let Some(framing) = next_framing {
+                while !MESSAGE_SENT.load(Ordering::SeqCst) {
+                    core::hint::spin_loop();
+                }
+                set_character_match(framing.delimiter());
+                app.framing = framing;
+            }
             MESSAGE_RECEIVED.store(false, Ordering::SeqCst);
             // free(|cs| {
             //     let mut fr_ref = FRAME_READER.borrow(cs).borrow_mut();
@@ -389,6 +499,34 @@ fn main() -> ! {
             //     }
             // });
         }
+        // Debounced press of the user button.
+        let down = user_button.is_low();
+        if down && !button_down && millis().wrapping_sub(button_pressed_at) > 50 {
+            button_pressed_at = millis();
+            button_pending = true;
+        }
+        button_down = down;
+        // Events wait for the transmitter so they don't get dropped, replies go first.
+        // A Modbus slave only ever answers, it has no events.
+        if !cfg!(feature = "modbus")
+            && MESSAGE_SENT.load(Ordering::SeqCst) && !MESSAGE_RECEIVED.load(Ordering::Relaxed) {
+            let event = if RX_OVERRUN.swap(false, Ordering::Relaxed) {
+                Some(DeviceEvent::Fault(Fault::Overrun))
+            } else if button_pending {
+                button_pending = false;
+                Some(DeviceEvent::Button)
+            } else if app.telemetry_period != 0
+                && millis().wrapping_sub(t) >= u32::from(app.telemetry_period)
+            {
+                t = millis();
+                Some(DeviceEvent::Telemetry(app.report(t)))
+            } else {
+                None
+            };
+            if let Some(event) = event {
+                free(|cs| send_event(cs, &event, app.framing));
+            }
+        }
         // if MESSAGE_SENT.load(Ordering::Relaxed) {
         //     // let m = millis();
         //     // while (millis() - m) < 1000 {};
@@ -1,52 +1,72 @@
-#![no_std]
-#![no_main]
-
-use btoi::btoi;
-
-#[derive(Debug, PartialEq)]
-pub enum AppCommand {
-    PwmOn,
-    PwmOff,
-    PwmDuty(u16),
-    PwmSetFreq(u32),
-    SetGpioPin,
-    ClearGpioPin,
-    GetTime,
-    GetStatus,
-}
+pub use iced_protocol::{Command as AppCommand, Error, ErrorCode, Event as DeviceEvent, Fault, Framing, Response, Seq, FRAME_SIZE};
+use iced_protocol::scpi;
+use iced_protocol::sequence::split_seq;
+use iced_protocol::{Capabilities, DeviceInfo, Label, PROTOCOL_VERSION};
+
+pub use iced_protocol::scpi::Instrument;
+
+pub const BOARD: &str = "NUCLEO-L476RG";
+
+#[cfg(feature = "modbus")]
+pub use iced_protocol::modbus::{Transaction, DEFAULT_UNIT as MODBUS_UNIT};
+/// Silence that ends a Modbus RTU frame, 3.5 characters of 11 bits.
+#[cfg(feature = "modbus")]
+pub const MODBUS_SILENCE_BITS: u32 = 39;
 
-pub fn parse_pwm_duty(input: &[u8]) -> Option<AppCommand> {
-    if input.len() <= 2 {
-        return None;
+/// Reply to `Identify`, this firmware handles every command of the protocol it's built against.
+pub fn device_info() -> DeviceInfo {
+    DeviceInfo {
+        protocol_version: PROTOCOL_VERSION,
+        firmware_version: Label::new(env!("CARGO_PKG_VERSION")).unwrap(),
+        board: Label::new(BOARD).unwrap(),
+        capabilities: Capabilities::all(),
     }
-    if let Ok(num) = btoi::<u16>(&input[1..(input.len() - 1)]) {
-        Some(AppCommand::PwmDuty(num))
-    } else {
-        None
+}
+
+pub fn parse_command(buffer: &[u8]) -> Result<AppCommand, ErrorCode> {
+    match AppCommand::decode(buffer) {
+        Ok(command) if command.in_range() => Ok(command),
+        Ok(_) => Err(ErrorCode::OutOfRange),
+        Err(_) => Err(ErrorCode::Malformed),
     }
 }
 
-pub fn parse_pwm_frequency(input: &[u8]) -> Option<AppCommand> {
-    if input.len() <= 2 {
-        return None;
+/// Strips the framing from a received frame before parsing the command in it.
+/// The sequence number, if the host sent one, has to be echoed on the reply.
+pub fn parse_frame(buffer: &[u8], framing: Framing) -> (Option<Seq>, Result<AppCommand, ErrorCode>) {
+    let mut message = [0u8; FRAME_SIZE];
+    match framing.unwrap(buffer, &mut message).map(split_seq) {
+        Ok(Ok((seq, m))) => (seq, parse_command(m)),
+        Err(Error::Checksum) => (None, Err(ErrorCode::Checksum)),
+        _ => (None, Err(ErrorCode::Malformed)),
     }
-    if let Ok(num) = btoi::<u32>(&input[1..(input.len() - 1)]) {
-        Some(AppCommand::PwmSetFreq(num))
-    } else {
-        None
+}
+
+/// A received frame, in either of the command sets.
+pub enum Request {
+    /// A single-letter command, with the sequence number to echo.
+    Command(Option<Seq>, Result<AppCommand, ErrorCode>),
+    /// An SCPI line, `None` when it was invalid and its error got queued.
+    Scpi(Option<scpi::Message>),
+}
+
+impl Request {
+    /// What to apply to the device, in order.
+    pub fn commands(&self) -> &[AppCommand] {
+        match self {
+            Request::Command(_, Ok(command)) => core::slice::from_ref(command),
+            Request::Scpi(Some(message)) => message.commands(),
+            Request::Command(_, Err(_)) | Request::Scpi(None) => &[],
+        }
     }
 }
 
-pub fn parse_command(buffer: &[u8]) -> Option<AppCommand> {
-    match buffer[0] {
-        b'E' => Some(AppCommand::PwmOn),
-        b'O' => Some(AppCommand::PwmOff),
-        b'D' => parse_pwm_duty(buffer),
-        b'F' => parse_pwm_frequency(buffer),
-        b'P' => Some(AppCommand::SetGpioPin),
-        b'C' => Some(AppCommand::ClearGpioPin),
-        b'T' => Some(AppCommand::GetTime),
-        b'S' => Some(AppCommand::GetStatus),
-        _ => None,
+/// Parses a frame as SCPI or as a single-letter command. SCPI is only understood in line framing.
+pub fn parse_request(buffer: &[u8], framing: Framing, instrument: &mut Instrument) -> Request {
+    if framing == Framing::Line && scpi::is_scpi(buffer) {
+        Request::Scpi(instrument.parse(buffer))
+    } else {
+        let (seq, parsed) = parse_frame(buffer, framing);
+        Request::Command(seq, parsed)
     }
 }
@@ -0,0 +1,199 @@
+use crate::{parse_argument, trim_frame, write_frame, Error, Framing};
+
+/// Highest accepted duty cycle, in percent.
+pub const MAX_DUTY: u8 = 100;
+/// Lowest PWM frequency the firmware can configure, in Hz.
+pub const MIN_FREQUENCY: u32 = 1;
+/// Highest PWM frequency the firmware can configure, in Hz.
+pub const MAX_FREQUENCY: u32 = 1_000_000;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Command {
+    PwmOn,
+    PwmOff,
+    /// Duty cycle in percent.
+    PwmDuty(u8),
+    /// PWM frequency in Hz.
+    PwmSetFreq(u32),
+    SetGpioPin,
+    ClearGpioPin,
+    GetTime,
+    GetState,
+    /// Switch framing. The device acknowledges in the current framing and uses
+    /// the new one from the next frame on.
+    SetFraming(Framing),
+    /// Ask for the device's protocol version, firmware, board and capabilities.
+    Identify,
+    /// Send an `Event::Telemetry` every given number of milliseconds, 0 stops them.
+    SetTelemetry(u16),
+}
+
+impl Command {
+    /// Writes the command frame, delimiter included, into `buf`.
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        match self {
+            Command::PwmOn => write_frame(buf, format_args!("E")),
+            Command::PwmOff => write_frame(buf, format_args!("O")),
+            Command::PwmDuty(duty) => write_frame(buf, format_args!("D{}", duty)),
+            Command::PwmSetFreq(hz) => write_frame(buf, format_args!("F{}", hz)),
+            Command::SetGpioPin => write_frame(buf, format_args!("P")),
+            Command::ClearGpioPin => write_frame(buf, format_args!("C")),
+            Command::GetTime => write_frame(buf, format_args!("T")),
+            Command::GetState => write_frame(buf, format_args!("S")),
+            Command::SetFraming(f) => write_frame(buf, format_args!("B{}", *f as u8)),
+            Command::Identify => write_frame(buf, format_args!("I")),
+            Command::SetTelemetry(ms) => write_frame(buf, format_args!("R{}", ms)),
+        }
+    }
+
+    /// The byte a frame for this command starts with.
+    pub fn tag(&self) -> u8 {
+        match self {
+            Command::PwmOn => b'E',
+            Command::PwmOff => b'O',
+            Command::PwmDuty(_) => b'D',
+            Command::PwmSetFreq(_) => b'F',
+            Command::SetGpioPin => b'P',
+            Command::ClearGpioPin => b'C',
+            Command::GetTime => b'T',
+            Command::GetState => b'S',
+            Command::SetFraming(_) => b'B',
+            Command::Identify => b'I',
+            Command::SetTelemetry(_) => b'R',
+        }
+    }
+
+    /// Whether sending the command twice leaves the device as sending it once,
+    /// which makes it safe to repeat when a reply is lost.
+    ///
+    /// `SetFraming` isn't: once its ack is lost the device has switched, and a
+    /// repeat in the old framing can't reach it.
+    pub fn is_idempotent(&self) -> bool {
+        match self {
+            Command::PwmOn
+            | Command::PwmOff
+            | Command::PwmDuty(_)
+            | Command::PwmSetFreq(_)
+            | Command::SetGpioPin
+            | Command::ClearGpioPin
+            | Command::GetTime
+            | Command::GetState
+            | Command::Identify
+            | Command::SetTelemetry(_) => true,
+            Command::SetFraming(_) => false,
+        }
+    }
+
+    /// Whether the argument is within what the device accepts.
+    pub fn in_range(&self) -> bool {
+        match self {
+            Command::PwmDuty(duty) => *duty <= MAX_DUTY,
+            Command::PwmSetFreq(hz) => (MIN_FREQUENCY..=MAX_FREQUENCY).contains(hz),
+            _ => true,
+        }
+    }
+
+    /// Parses a single frame, with or without its delimiter.
+    pub fn decode(frame: &[u8]) -> Result<Self, Error> {
+        let frame = trim_frame(frame);
+        let (tag, arg) = frame.split_first().ok_or(Error::Empty)?;
+        let no_arg = |cmd| {
+            if arg.is_empty() {
+                Ok(cmd)
+            } else {
+                Err(Error::InvalidArgument)
+            }
+        };
+        match tag {
+            b'E' => no_arg(Command::PwmOn),
+            b'O' => no_arg(Command::PwmOff),
+            b'D' => parse_argument(arg).map(Command::PwmDuty),
+            b'F' => parse_argument(arg).map(Command::PwmSetFreq),
+            b'P' => no_arg(Command::SetGpioPin),
+            b'C' => no_arg(Command::ClearGpioPin),
+            b'T' => no_arg(Command::GetTime),
+            b'S' => no_arg(Command::GetState),
+            b'B' => match arg {
+                b"0" => Ok(Command::SetFraming(Framing::Line)),
+                b"1" => Ok(Command::SetFraming(Framing::Binary)),
+                _ => Err(Error::InvalidArgument),
+            },
+            b'I' => no_arg(Command::Identify),
+            b'R' => parse_argument(arg).map(Command::SetTelemetry),
+            t => Err(Error::UnknownTag(*t)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FRAME_SIZE;
+
+    const ALL: [Command; 14] = [
+        Command::PwmOn,
+        Command::PwmOff,
+        Command::PwmDuty(0),
+        Command::PwmDuty(100),
+        Command::PwmSetFreq(1),
+        Command::PwmSetFreq(u32::MAX),
+        Command::SetGpioPin,
+        Command::ClearGpioPin,
+        Command::GetTime,
+        Command::GetState,
+        Command::SetFraming(Framing::Line),
+        Command::SetFraming(Framing::Binary),
+        Command::Identify,
+        Command::SetTelemetry(1000),
+    ];
+
+    #[test]
+    fn round_trip() {
+        for cmd in ALL {
+            let mut buf = [0u8; FRAME_SIZE];
+            let n = cmd.encode(&mut buf).unwrap();
+            assert_eq!(buf[0], cmd.tag());
+            assert_eq!(buf[n - 1], b'\n');
+            assert_eq!(Command::decode(&buf[..n]), Ok(cmd));
+        }
+    }
+
+    #[test]
+    fn encodes_legacy_frames() {
+        let mut buf = [0u8; FRAME_SIZE];
+        let n = Command::PwmDuty(42).encode(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"D42\n");
+        let n = Command::PwmSetFreq(1000).encode(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"F1000\n");
+    }
+
+    #[test]
+    fn rejects_malformed_frames() {
+        assert_eq!(Command::decode(b"\n"), Err(Error::Empty));
+        assert_eq!(Command::decode(b"Z\n"), Err(Error::UnknownTag(b'Z')));
+        assert_eq!(Command::decode(b"D\n"), Err(Error::InvalidArgument));
+        assert_eq!(Command::decode(b"D256\n"), Err(Error::InvalidArgument));
+        assert_eq!(Command::decode(b"Fabc\n"), Err(Error::InvalidArgument));
+        assert_eq!(Command::decode(b"E1\n"), Err(Error::InvalidArgument));
+        assert_eq!(Command::decode(b"B2\n"), Err(Error::InvalidArgument));
+    }
+
+    #[test]
+    fn range_checks() {
+        assert!(Command::PwmDuty(MAX_DUTY).in_range());
+        assert!(!Command::PwmDuty(MAX_DUTY + 1).in_range());
+        assert!(!Command::PwmSetFreq(0).in_range());
+        assert!(!Command::PwmSetFreq(MAX_FREQUENCY + 1).in_range());
+        assert!(Command::GetState.in_range());
+    }
+
+    #[test]
+    fn small_buffer() {
+        let mut buf = [0u8; 3];
+        assert_eq!(
+            Command::PwmSetFreq(1000).encode(&mut buf),
+            Err(Error::BufferTooSmall)
+        );
+    }
+}
@@ -0,0 +1,105 @@
+//! Frames the device sends on its own, not in reply to a command.
+//!
+//! They start with [`EVENT_TAG`] and never carry a sequence number, so they
+//! can't be mistaken for a reply.
+
+use crate::{parse_argument, trim_frame, write_frame, DeviceState, Error};
+
+/// First byte of every event frame.
+pub const EVENT_TAG: u8 = b'!';
+
+/// Why the device reports a [`Event::Fault`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Fault {
+    /// A received frame was dropped because no receive buffer was free.
+    Overrun,
+    /// A code this version of the protocol doesn't know.
+    Other(u8),
+}
+
+impl Fault {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Fault::Overrun => 1,
+            Fault::Other(c) => c,
+        }
+    }
+}
+
+impl From<u8> for Fault {
+    fn from(code: u8) -> Self {
+        match code {
+            1 => Fault::Overrun,
+            c => Fault::Other(c),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Event {
+    /// The user button was pressed.
+    Button,
+    /// Periodic state report, see [`Command::SetTelemetry`](crate::Command::SetTelemetry).
+    Telemetry(DeviceState),
+    Fault(Fault),
+}
+
+impl Event {
+    /// Whether `frame`, with or without its delimiter, holds an event.
+    pub fn is_event(frame: &[u8]) -> bool {
+        frame.first() == Some(&EVENT_TAG)
+    }
+
+    /// Writes the event frame, delimiter included, into `buf`.
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        match self {
+            Event::Button => write_frame(buf, format_args!("!B")),
+            Event::Telemetry(s) => write_frame(buf, format_args!("!S{}", s)),
+            Event::Fault(f) => write_frame(buf, format_args!("!F{}", f.to_u8())),
+        }
+    }
+
+    /// Parses a single frame, with or without its delimiter.
+    pub fn decode(frame: &[u8]) -> Result<Self, Error> {
+        let frame = trim_frame(frame);
+        let body = match frame.split_first() {
+            Some((&EVENT_TAG, body)) => body,
+            Some((t, _)) => return Err(Error::UnknownTag(*t)),
+            None => return Err(Error::Empty),
+        };
+        match body.split_first() {
+            Some((b'B', [])) => Ok(Event::Button),
+            Some((b'S', arg)) => DeviceState::decode(arg).map(Event::Telemetry),
+            Some((b'F', arg)) => parse_argument::<u8>(arg).map(|c| Event::Fault(c.into())),
+            Some((t, _)) => Err(Error::UnknownTag(*t)),
+            None => Err(Error::Empty),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FRAME_SIZE;
+
+    #[test]
+    fn round_trip() {
+        let all = [
+            Event::Button,
+            Event::Telemetry(DeviceState::default()),
+            Event::Fault(Fault::Overrun),
+            Event::Fault(Fault::Other(9)),
+        ];
+        for event in all {
+            let mut buf = [0u8; FRAME_SIZE];
+            let n = event.encode(&mut buf).unwrap();
+            assert!(Event::is_event(&buf[..n]));
+            assert_eq!(Event::decode(&buf[..n]), Ok(event));
+        }
+        assert_eq!(Event::decode(b"!B\n"), Ok(Event::Button));
+        assert_eq!(Event::decode(b"B\n"), Err(Error::UnknownTag(b'B')));
+        assert_eq!(Event::decode(b"!Q\n"), Err(Error::UnknownTag(b'Q')));
+    }
+}
@@ -0,0 +1,214 @@
+//! Binary framing: a message is followed by its CRC-16, COBS encoded so it
+//! contains no zero bytes, and terminated by [`BINARY_DELIMITER`].
+//!
+//! The message itself is the line frame without its `\n`, so both framings
+//! carry exactly the same commands and responses.
+
+use crate::{trim_frame, Error, DELIMITER, FRAME_SIZE};
+
+/// Terminator of every binary frame.
+pub const BINARY_DELIMITER: u8 = 0x00;
+
+/// Longest message that still fits in a [`FRAME_SIZE`] binary frame once the
+/// CRC, COBS overhead byte and delimiter are added.
+pub const MAX_MESSAGE: usize = FRAME_SIZE - 4;
+
+/// How frames are delimited on the wire.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Framing {
+    /// ASCII lines terminated by `\n`.
+    #[default]
+    Line = 0,
+    /// COBS encoded, CRC-16 protected frames terminated by `0x00`.
+    Binary = 1,
+}
+
+impl Framing {
+    pub fn delimiter(&self) -> u8 {
+        match self {
+            Framing::Line => DELIMITER,
+            Framing::Binary => BINARY_DELIMITER,
+        }
+    }
+
+    /// Turns a line frame, as produced by `Command::encode` or
+    /// `Response::encode`, into a frame of this framing.
+    pub fn wrap(&self, line: &[u8], dst: &mut [u8]) -> Result<usize, Error> {
+        match self {
+            Framing::Line => {
+                let out = dst.get_mut(..line.len()).ok_or(Error::BufferTooSmall)?;
+                out.copy_from_slice(line);
+                Ok(line.len())
+            }
+            Framing::Binary => encode_binary(trim_frame(line), dst),
+        }
+    }
+
+    /// Extracts the message from a frame of this framing, with or without its delimiter.
+    pub fn unwrap<'a>(&self, frame: &'a [u8], dst: &'a mut [u8]) -> Result<&'a [u8], Error> {
+        match self {
+            Framing::Line => Ok(trim_frame(frame)),
+            Framing::Binary => {
+                let n = decode_binary(frame, dst)?;
+                Ok(&dst[..n])
+            }
+        }
+    }
+}
+
+/// CRC-16/MODBUS (reflected 0x8005, initial value 0xffff).
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xffffu16;
+    for b in data {
+        crc ^= u16::from(*b);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xa001
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
+
+/// COBS encodes `src` into `dst`, without a trailing delimiter.
+pub fn cobs_encode(src: &[u8], dst: &mut [u8]) -> Result<usize, Error> {
+    let mut code_idx = 0;
+    let mut out = 1;
+    let mut code = 1u8;
+    for b in src {
+        if *b == 0 {
+            *dst.get_mut(code_idx).ok_or(Error::BufferTooSmall)? = code;
+            code_idx = out;
+            out += 1;
+            code = 1;
+        } else {
+            *dst.get_mut(out).ok_or(Error::BufferTooSmall)? = *b;
+            out += 1;
+            code += 1;
+            if code == 0xff {
+                *dst.get_mut(code_idx).ok_or(Error::BufferTooSmall)? = code;
+                code_idx = out;
+                out += 1;
+                code = 1;
+            }
+        }
+    }
+    *dst.get_mut(code_idx).ok_or(Error::BufferTooSmall)? = code;
+    Ok(out)
+}
+
+/// Decodes a COBS block, without its delimiter, from `src` into `dst`.
+pub fn cobs_decode(src: &[u8], dst: &mut [u8]) -> Result<usize, Error> {
+    let mut i = 0;
+    let mut out = 0;
+    while i < src.len() {
+        let code = src[i] as usize;
+        if code == 0 || i + code > src.len() {
+            return Err(Error::InvalidFrame);
+        }
+        let block = &src[i + 1..i + code];
+        dst.get_mut(out..out + block.len())
+            .ok_or(Error::BufferTooSmall)?
+            .copy_from_slice(block);
+        out += block.len();
+        i += code;
+        if code < 0xff && i < src.len() {
+            *dst.get_mut(out).ok_or(Error::BufferTooSmall)? = 0;
+            out += 1;
+        }
+    }
+    Ok(out)
+}
+
+/// Builds a complete binary frame, delimiter included, around `message`.
+pub fn encode_binary(message: &[u8], dst: &mut [u8]) -> Result<usize, Error> {
+    if message.len() > MAX_MESSAGE {
+        return Err(Error::BufferTooSmall);
+    }
+    let mut raw = [0u8; MAX_MESSAGE + 2];
+    raw[..message.len()].copy_from_slice(message);
+    raw[message.len()..message.len() + 2].copy_from_slice(&crc16(message).to_le_bytes());
+    let n = cobs_encode(&raw[..message.len() + 2], dst)?;
+    *dst.get_mut(n).ok_or(Error::BufferTooSmall)? = BINARY_DELIMITER;
+    Ok(n + 1)
+}
+
+/// Checks and strips a binary frame, writing the message to `dst`.
+pub fn decode_binary(frame: &[u8], dst: &mut [u8]) -> Result<usize, Error> {
+    let frame = frame.strip_suffix(&[BINARY_DELIMITER]).unwrap_or(frame);
+    let mut raw = [0u8; FRAME_SIZE];
+    let n = cobs_decode(frame, &mut raw)?;
+    if n < 2 {
+        return Err(Error::InvalidFrame);
+    }
+    let (message, crc) = raw[..n].split_at(n - 2);
+    if crc16(message).to_le_bytes() != crc {
+        return Err(Error::Checksum);
+    }
+    dst.get_mut(..message.len())
+        .ok_or(Error::BufferTooSmall)?
+        .copy_from_slice(message);
+    Ok(message.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Command, Response};
+
+    #[test]
+    fn crc_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x4b37);
+    }
+
+    #[test]
+    fn cobs_round_trip() {
+        let cases: [&[u8]; 6] = [
+            b"",
+            &[0],
+            &[0, 0],
+            &[1, 0, 2, 0],
+            b"S1,0,25,1000,42",
+            &[0xff; 300],
+        ];
+        for case in cases {
+            let mut enc = [0u8; 400];
+            let mut dec = [0u8; 400];
+            let n = cobs_encode(case, &mut enc).unwrap();
+            assert!(!enc[..n].contains(&0));
+            let m = cobs_decode(&enc[..n], &mut dec).unwrap();
+            assert_eq!(&dec[..m], case);
+        }
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        let mut line = [0u8; FRAME_SIZE];
+        let mut frame = [0u8; FRAME_SIZE];
+        let mut msg = [0u8; FRAME_SIZE];
+        let n = Command::PwmSetFreq(1000).encode(&mut line).unwrap();
+        let f = Framing::Binary.wrap(&line[..n], &mut frame).unwrap();
+        assert_eq!(frame[f - 1], BINARY_DELIMITER);
+        assert!(!frame[..f - 1].contains(&0));
+        let m = Framing::Binary.unwrap(&frame[..f], &mut msg).unwrap();
+        assert_eq!(Command::decode(m), Ok(Command::PwmSetFreq(1000)));
+    }
+
+    #[test]
+    fn detects_corruption() {
+        let mut line = [0u8; FRAME_SIZE];
+        let mut frame = [0u8; FRAME_SIZE];
+        let mut msg = [0u8; FRAME_SIZE];
+        let n = Response::Time(1234).encode(&mut line).unwrap();
+        let f = Framing::Binary.wrap(&line[..n], &mut frame).unwrap();
+        frame[2] ^= 0x04;
+        assert_eq!(
+            Framing::Binary.unwrap(&frame[..f], &mut msg),
+            Err(Error::Checksum)
+        );
+        assert_eq!(decode_binary(&[3, 1], &mut msg), Err(Error::InvalidFrame));
+    }
+}
@@ -0,0 +1,240 @@
+//! What the device reports in reply to [`Command::Identify`].
+
+use crate::{parse_argument, Command, Error};
+use core::fmt;
+use core::str;
+
+/// Version of the command set described by this crate. The host refuses
+/// devices reporting a different one.
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// Every command tag this version of the protocol knows, in capability bit order.
+pub const COMMAND_TAGS: &[u8] = b"EODFPCTSBIR";
+
+/// Longest [`Label`] a device may send.
+pub const MAX_LABEL: usize = 16;
+
+/// Short piece of text in an identity report, such as the board name.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct Label {
+    buf: [u8; MAX_LABEL],
+    len: usize,
+}
+
+impl Label {
+    /// Fails if `s` is too long or contains a character the frame uses as a separator.
+    pub fn new(s: &str) -> Result<Self, Error> {
+        let bytes = s.as_bytes();
+        if bytes.len() > MAX_LABEL || bytes.iter().any(|b| !b.is_ascii_graphic() || *b == b',') {
+            return Err(Error::InvalidArgument);
+        }
+        let mut buf = [0u8; MAX_LABEL];
+        buf[..bytes.len()].copy_from_slice(bytes);
+        Ok(Self {
+            buf,
+            len: bytes.len(),
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Only ever built from a checked `&str`.
+        str::from_utf8(&self.buf[..self.len]).unwrap_or_default()
+    }
+}
+
+impl fmt::Display for Label {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for Label {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+/// The set of commands a device accepts, one bit per entry of [`COMMAND_TAGS`].
+#[derive(Copy, Clone, PartialEq, Eq, Default)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    /// Every command this crate knows.
+    pub fn all() -> Self {
+        Self((1 << COMMAND_TAGS.len()) - 1)
+    }
+
+    /// Tags this crate doesn't know, from newer firmware, are ignored.
+    pub fn from_tags(tags: &[u8]) -> Self {
+        let bits = tags
+            .iter()
+            .filter_map(|t| COMMAND_TAGS.iter().position(|c| c == t))
+            .fold(0, |bits, i| bits | 1 << i);
+        Self(bits)
+    }
+
+    pub fn supports(&self, command: &Command) -> bool {
+        COMMAND_TAGS
+            .iter()
+            .position(|t| *t == command.tag())
+            .is_some_and(|i| self.0 & 1 << i != 0)
+    }
+
+    fn tags(&self) -> impl Iterator<Item = u8> + '_ {
+        COMMAND_TAGS
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| self.0 & 1 << i != 0)
+            .map(|(_, t)| *t)
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.tags()
+            .try_for_each(|t| fmt::Write::write_char(f, t as char))
+    }
+}
+
+impl fmt::Debug for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Capabilities({})", self)
+    }
+}
+
+/// Reply to [`Command::Identify`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DeviceInfo {
+    /// The [`PROTOCOL_VERSION`] the firmware was built against.
+    pub protocol_version: u8,
+    pub firmware_version: Label,
+    pub board: Label,
+    pub capabilities: Capabilities,
+}
+
+impl DeviceInfo {
+    pub fn is_compatible(&self) -> bool {
+        self.protocol_version == PROTOCOL_VERSION
+    }
+
+    /// Parses the comma separated fields following the `I` tag.
+    pub(crate) fn decode(arg: &[u8]) -> Result<Self, Error> {
+        let mut fields = arg.split(|b| *b == b',');
+        let mut next = || fields.next().ok_or(Error::InvalidArgument);
+        let label = |f: &[u8]| {
+            str::from_utf8(f)
+                .map_err(|_| Error::InvalidArgument)
+                .and_then(Label::new)
+        };
+        let info = DeviceInfo {
+            protocol_version: parse_argument(next()?)?,
+            firmware_version: label(next()?)?,
+            board: label(next()?)?,
+            capabilities: Capabilities::from_tags(next()?),
+        };
+        if fields.next().is_some() {
+            return Err(Error::InvalidArgument);
+        }
+        Ok(info)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Framing, Response, FRAME_SIZE};
+
+    #[test]
+    fn capabilities() {
+        let caps = Capabilities::from_tags(b"ETSZ");
+        assert!(caps.supports(&Command::PwmOn));
+        assert!(caps.supports(&Command::GetState));
+        assert!(!caps.supports(&Command::SetFraming(Framing::Binary)));
+        assert_eq!(Capabilities::from_tags(COMMAND_TAGS), Capabilities::all());
+    }
+
+    #[test]
+    fn round_trip() {
+        let info = DeviceInfo {
+            protocol_version: PROTOCOL_VERSION,
+            firmware_version: Label::new("0.1.0").unwrap(),
+            board: Label::new("NUCLEO-L476RG").unwrap(),
+            capabilities: Capabilities::all(),
+        };
+        let mut buf = [0u8; FRAME_SIZE];
+        let n = Response::Identity(info).encode(&mut buf).unwrap();
+        assert_eq!(&buf[..n], b"I1,0.1.0,NUCLEO-L476RG,EODFPCTSBIR\n");
+        assert_eq!(Response::decode(&buf[..n]), Ok(Response::Identity(info)));
+        assert_eq!(Label::new("a,b"), Err(Error::InvalidArgument));
+        assert_eq!(
+            Response::decode(b"I1,0.1.0,NUCLEO\n"),
+            Err(Error::InvalidArgument)
+        );
+    }
+}
+
+/// Labels are plain strings and capabilities their tag letters, as on the wire.
+#[cfg(feature = "serde")]
+mod serde_impls {
+    use super::{Capabilities, Label};
+    use core::fmt;
+    use serde::de::{self, Deserializer, Unexpected, Visitor};
+    use serde::{Deserialize, Serialize, Serializer};
+
+    impl Serialize for Label {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.serialize_str(self.as_str())
+        }
+    }
+
+    struct LabelVisitor;
+
+    impl Visitor<'_> for LabelVisitor {
+        type Value = Label;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(
+                f,
+                "up to {} printable characters without commas",
+                super::MAX_LABEL
+            )
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<Label, E> {
+            Label::new(v).map_err(|_| E::invalid_value(Unexpected::Str(v), &self))
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Label {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            deserializer.deserialize_str(LabelVisitor)
+        }
+    }
+
+    impl Serialize for Capabilities {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            serializer.collect_str(self)
+        }
+    }
+
+    struct CapabilitiesVisitor;
+
+    impl Visitor<'_> for CapabilitiesVisitor {
+        type Value = Capabilities;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.write_str("command tag letters")
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<Capabilities, E> {
+            Ok(Capabilities::from_tags(v.as_bytes()))
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Capabilities {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            deserializer.deserialize_str(CapabilitiesVisitor)
+        }
+    }
+}
@@ -0,0 +1,123 @@
+#![cfg_attr(not(test), no_std)]
+
+//! The wire protocol spoken between `iced-mcu` and the host.
+//!
+//! Every frame is a single line of ASCII terminated by `\n`. The host sends a
+//! [`Command`] and the device answers with exactly one [`Response`]. After a
+//! [`Command::SetFraming`] both sides can switch to the [`framing`] module's
+//! binary frames instead. Frames may start with a [`sequence`] number that the
+//! device echoes on its reply. The device may also send an [`Event`] at any time.
+//!
+//! The [`modbus`] module maps the same commands onto Modbus RTU registers, for
+//! firmware built to be a Modbus slave instead. Lines in [`scpi`] are accepted
+//! alongside the single-letter commands, for tools that expect a bench instrument.
+
+pub mod command;
+pub mod event;
+pub mod framing;
+pub mod info;
+pub mod modbus;
+pub mod response;
+pub mod scpi;
+pub mod sequence;
+
+use core::fmt;
+use core::str;
+
+pub use command::{Command, MAX_DUTY, MAX_FREQUENCY, MIN_FREQUENCY};
+pub use event::{Event, Fault};
+pub use framing::Framing;
+pub use info::{Capabilities, DeviceInfo, Label, PROTOCOL_VERSION};
+pub use response::{DeviceState, ErrorCode, Response};
+pub use sequence::Seq;
+
+/// Size of the firmware's DMA frames, no encoded frame may be longer than this.
+pub const FRAME_SIZE: usize = 100;
+
+/// Terminator of every frame.
+pub const DELIMITER: u8 = b'\n';
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The output buffer can't hold the encoded frame.
+    BufferTooSmall,
+    /// The frame didn't contain anything besides the terminator.
+    Empty,
+    /// The first byte of the frame isn't a known tag.
+    UnknownTag(u8),
+    /// The argument following the tag is missing or isn't a valid number.
+    InvalidArgument,
+    /// A binary frame isn't valid COBS or is too short to hold a CRC.
+    InvalidFrame,
+    /// A binary frame's CRC doesn't match its contents.
+    Checksum,
+    /// The frame starts with a digit but not with a valid `seq:` prefix.
+    InvalidSequence,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::BufferTooSmall => write!(f, "buffer too small"),
+            Error::Empty => write!(f, "empty frame"),
+            Error::UnknownTag(t) => write!(f, "unknown tag 0x{:02x}", t),
+            Error::InvalidArgument => write!(f, "invalid argument"),
+            Error::InvalidFrame => write!(f, "invalid binary frame"),
+            Error::Checksum => write!(f, "checksum mismatch"),
+            Error::InvalidSequence => write!(f, "invalid sequence number"),
+        }
+    }
+}
+
+/// `core::fmt::Write` over a byte slice, used to format frames without allocating.
+pub(crate) struct SliceWriter<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl<'a> SliceWriter<'a> {
+    pub(crate) fn new(buf: &'a mut [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    pub(crate) fn written(&self) -> usize {
+        self.pos
+    }
+}
+
+impl fmt::Write for SliceWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.pos + s.len();
+        if end > self.buf.len() {
+            return Err(fmt::Error);
+        }
+        self.buf[self.pos..end].copy_from_slice(s.as_bytes());
+        self.pos = end;
+        Ok(())
+    }
+}
+
+/// Formats `args` followed by the delimiter into `buf`, returning the frame length.
+pub(crate) fn write_frame(buf: &mut [u8], args: fmt::Arguments) -> Result<usize, Error> {
+    let mut w = SliceWriter::new(buf);
+    fmt::write(&mut w, args).map_err(|_| Error::BufferTooSmall)?;
+    fmt::Write::write_char(&mut w, DELIMITER as char).map_err(|_| Error::BufferTooSmall)?;
+    Ok(w.written())
+}
+
+/// Strips the delimiter (and a carriage return before it) from the end of a frame.
+pub(crate) fn trim_frame(frame: &[u8]) -> &[u8] {
+    let frame = frame.strip_suffix(&[DELIMITER]).unwrap_or(frame);
+    frame.strip_suffix(b"\r").unwrap_or(frame)
+}
+
+/// Parses the decimal argument that follows a single byte tag.
+pub(crate) fn parse_argument<N: str::FromStr>(arg: &[u8]) -> Result<N, Error> {
+    if arg.is_empty() {
+        return Err(Error::InvalidArgument);
+    }
+    str::from_utf8(arg)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or(Error::InvalidArgument)
+}
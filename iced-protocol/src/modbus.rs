@@ -0,0 +1,795 @@
+//! The device as a Modbus RTU slave, for tooling that speaks Modbus rather than the line protocol.
+//!
+//! | Table            | Address | Content                                   |
+//! |------------------|---------|-------------------------------------------|
+//! | coil             | 0       | LED                                       |
+//! | coil             | 1       | PWM output enabled                        |
+//! | holding register | 0       | duty cycle in percent                     |
+//! | holding register | 1, 2    | PWM frequency in Hz, high word first      |
+//! | input register   | 0, 1    | milliseconds since boot, high word first  |
+//!
+//! Read coils (1), read holding registers (3), read input registers (4), write
+//! single coil (5), write single register (6), write multiple registers (16)
+//! and the basic objects of read device identification (43/14) are supported.
+//! Writes turn into the same [`Command`]s as the line protocol and have the
+//! same ranges. Write both frequency registers with function 16 so the output
+//! never runs at a frequency made of one old and one new word.
+
+use crate::framing::crc16;
+use crate::{Capabilities, Command, DeviceInfo, DeviceState, Error, ErrorCode, Label};
+
+/// Slave address the firmware and simulator answer to unless told otherwise.
+pub const DEFAULT_UNIT: u8 = 1;
+/// Requests to this address are carried out by every slave and answered by none.
+pub const BROADCAST: u8 = 0;
+
+pub const COIL_LED: u16 = 0;
+pub const COIL_PWM: u16 = 1;
+pub const COILS: u16 = 2;
+pub const HOLDING_DUTY: u16 = 0;
+pub const HOLDING_FREQUENCY: u16 = 1;
+pub const HOLDING_REGISTERS: u16 = 3;
+pub const INPUT_UPTIME: u16 = 0;
+pub const INPUT_REGISTERS: u16 = 2;
+
+/// Most registers one request reads or writes, the size of the largest table.
+pub const MAX_REGISTERS: usize = 3;
+/// Longest frame, address and CRC included, the standard allows.
+pub const MAX_ADU: usize = 256;
+
+/// VendorName reported by device identification.
+pub const VENDOR: &str = "iced";
+
+const READ_COILS: u8 = 0x01;
+const READ_HOLDING_REGISTERS: u8 = 0x03;
+const READ_INPUT_REGISTERS: u8 = 0x04;
+const WRITE_COIL: u8 = 0x05;
+const WRITE_REGISTER: u8 = 0x06;
+const WRITE_REGISTERS: u8 = 0x10;
+const ENCAPSULATED: u8 = 0x2b;
+const READ_DEVICE_ID: u8 = 0x0e;
+/// Read device ID code for the basic objects, streamed.
+const BASIC_DEVICE_ID: u8 = 0x01;
+/// Set on the function code of an exception reply.
+const EXCEPTION_FLAG: u8 = 0x80;
+const COIL_ON: u16 = 0xff00;
+
+/// Why a slave refused a request.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Exception {
+    IllegalFunction,
+    IllegalDataAddress,
+    IllegalDataValue,
+    DeviceFailure,
+}
+
+impl Exception {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            Exception::IllegalFunction => 1,
+            Exception::IllegalDataAddress => 2,
+            Exception::IllegalDataValue => 3,
+            Exception::DeviceFailure => 4,
+        }
+    }
+}
+
+impl From<u8> for Exception {
+    fn from(code: u8) -> Self {
+        match code {
+            1 => Exception::IllegalFunction,
+            2 => Exception::IllegalDataAddress,
+            3 => Exception::IllegalDataValue,
+            _ => Exception::DeviceFailure,
+        }
+    }
+}
+
+impl From<Exception> for ErrorCode {
+    fn from(e: Exception) -> Self {
+        match e {
+            Exception::IllegalFunction | Exception::IllegalDataAddress => ErrorCode::Malformed,
+            Exception::IllegalDataValue => ErrorCode::OutOfRange,
+            Exception::DeviceFailure => ErrorCode::Other(e.to_u8()),
+        }
+    }
+}
+
+/// The commands a Modbus master can give, the line protocol's framing and
+/// telemetry have no equivalent.
+pub fn capabilities() -> Capabilities {
+    Capabilities::from_tags(b"EODFPCTSI")
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Request {
+    ReadCoils {
+        start: u16,
+        count: u16,
+    },
+    ReadHoldingRegisters {
+        start: u16,
+        count: u16,
+    },
+    ReadInputRegisters {
+        start: u16,
+        count: u16,
+    },
+    WriteCoil {
+        address: u16,
+        on: bool,
+    },
+    WriteRegister {
+        address: u16,
+        value: u16,
+    },
+    /// Only the first `count` of `values` are written.
+    WriteRegisters {
+        start: u16,
+        count: u16,
+        values: [u16; MAX_REGISTERS],
+    },
+    ReadDeviceIdentification,
+}
+
+/// Builds a frame in a byte slice and seals it with the CRC.
+struct AduWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> AduWriter<'a> {
+    fn new(buf: &'a mut [u8], unit: u8, function: u8) -> Result<Self, Error> {
+        let mut w = Self { buf, len: 0 };
+        w.bytes(&[unit, function])?;
+        Ok(w)
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        let end = self.len + bytes.len();
+        self.buf
+            .get_mut(self.len..end)
+            .ok_or(Error::BufferTooSmall)?
+            .copy_from_slice(bytes);
+        self.len = end;
+        Ok(())
+    }
+
+    fn byte(&mut self, b: u8) -> Result<(), Error> {
+        self.bytes(&[b])
+    }
+
+    fn word(&mut self, w: u16) -> Result<(), Error> {
+        self.bytes(&w.to_be_bytes())
+    }
+
+    fn finish(mut self) -> Result<usize, Error> {
+        let crc = crc16(&self.buf[..self.len]);
+        self.bytes(&crc.to_le_bytes())?;
+        Ok(self.len)
+    }
+}
+
+fn word(data: &[u8], at: usize) -> u16 {
+    u16::from_be_bytes([data[at], data[at + 1]])
+}
+
+/// Checks the CRC and splits a frame into its unit and function code, and the data after them.
+fn open(adu: &[u8]) -> Result<(u8, u8, &[u8]), Error> {
+    if adu.len() < 4 {
+        return Err(Error::InvalidFrame);
+    }
+    let (body, crc) = adu.split_at(adu.len() - 2);
+    if crc16(body).to_le_bytes() != crc {
+        return Err(Error::Checksum);
+    }
+    Ok((body[0], body[1], &body[2..]))
+}
+
+fn check_range(start: u16, count: u16, size: u16, max: u16) -> Result<(), Exception> {
+    if count == 0 || count > max {
+        return Err(Exception::IllegalDataValue);
+    }
+    if u32::from(start) + u32::from(count) > u32::from(size) {
+        return Err(Exception::IllegalDataAddress);
+    }
+    Ok(())
+}
+
+fn holding_registers(state: &DeviceState) -> [u16; MAX_REGISTERS] {
+    [
+        u16::from(state.pwm_duty),
+        (state.pwm_frequency >> 16) as u16,
+        state.pwm_frequency as u16,
+    ]
+}
+
+fn input_registers(state: &DeviceState) -> [u16; INPUT_REGISTERS as usize] {
+    [(state.uptime_ms >> 16) as u16, state.uptime_ms as u16]
+}
+
+/// The commands writing `values` from holding register `start` on takes.
+fn write_holding(
+    start: u16,
+    values: &[u16],
+    state: &DeviceState,
+) -> Result<[Option<Command>; 2], Exception> {
+    let mut table = holding_registers(state);
+    let start = usize::from(start);
+    table[start..start + values.len()].copy_from_slice(values);
+    let mut commands = [None, None];
+    if start == usize::from(HOLDING_DUTY) {
+        let duty = u8::try_from(table[0]).map_err(|_| Exception::IllegalDataValue)?;
+        commands[0] = Some(Command::PwmDuty(duty));
+    }
+    if start + values.len() > usize::from(HOLDING_FREQUENCY) {
+        let hz = u32::from(table[1]) << 16 | u32::from(table[2]);
+        commands[1] = Some(Command::PwmSetFreq(hz));
+    }
+    match commands.iter().flatten().all(Command::in_range) {
+        true => Ok(commands),
+        false => Err(Exception::IllegalDataValue),
+    }
+}
+
+impl Request {
+    pub fn function(&self) -> u8 {
+        match self {
+            Request::ReadCoils { .. } => READ_COILS,
+            Request::ReadHoldingRegisters { .. } => READ_HOLDING_REGISTERS,
+            Request::ReadInputRegisters { .. } => READ_INPUT_REGISTERS,
+            Request::WriteCoil { .. } => WRITE_COIL,
+            Request::WriteRegister { .. } => WRITE_REGISTER,
+            Request::WriteRegisters { .. } => WRITE_REGISTERS,
+            Request::ReadDeviceIdentification => ENCAPSULATED,
+        }
+    }
+
+    /// Writes the request frame for slave `unit` into `buf`.
+    pub fn encode(&self, unit: u8, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut w = AduWriter::new(buf, unit, self.function())?;
+        match *self {
+            Request::ReadCoils { start, count }
+            | Request::ReadHoldingRegisters { start, count }
+            | Request::ReadInputRegisters { start, count } => {
+                w.word(start)?;
+                w.word(count)?;
+            }
+            Request::WriteCoil { address, on } => {
+                w.word(address)?;
+                w.word(if on { COIL_ON } else { 0 })?;
+            }
+            Request::WriteRegister { address, value } => {
+                w.word(address)?;
+                w.word(value)?;
+            }
+            Request::WriteRegisters {
+                start,
+                count,
+                values,
+            } => {
+                let values = values
+                    .get(..usize::from(count))
+                    .ok_or(Error::InvalidArgument)?;
+                w.word(start)?;
+                w.word(count)?;
+                w.byte(count as u8 * 2)?;
+                for v in values {
+                    w.word(*v)?;
+                }
+            }
+            Request::ReadDeviceIdentification => w.bytes(&[READ_DEVICE_ID, BASIC_DEVICE_ID, 0])?,
+        }
+        w.finish()
+    }
+
+    /// Parses a request frame, returning the unit it's for.
+    ///
+    /// Fails on frames a slave ignores: too short, or with a bad CRC. A frame
+    /// that parses but asks for something the device doesn't have gives the
+    /// exception to answer with instead of a request.
+    pub fn decode(adu: &[u8]) -> Result<(u8, Result<Request, Exception>), Error> {
+        let (unit, function, data) = open(adu)?;
+        let fixed = |n| match data.len() == n {
+            true => Ok(()),
+            false => Err(Error::InvalidFrame),
+        };
+        let request = match function {
+            READ_COILS | READ_HOLDING_REGISTERS | READ_INPUT_REGISTERS => {
+                fixed(4)?;
+                let (start, count) = (word(data, 0), word(data, 2));
+                match function {
+                    READ_COILS => check_range(start, count, COILS, COILS)
+                        .map(|_| Request::ReadCoils { start, count }),
+                    READ_HOLDING_REGISTERS => {
+                        check_range(start, count, HOLDING_REGISTERS, HOLDING_REGISTERS)
+                            .map(|_| Request::ReadHoldingRegisters { start, count })
+                    }
+                    _ => check_range(start, count, INPUT_REGISTERS, INPUT_REGISTERS)
+                        .map(|_| Request::ReadInputRegisters { start, count }),
+                }
+            }
+            WRITE_COIL => {
+                fixed(4)?;
+                let address = word(data, 0);
+                let on = match word(data, 2) {
+                    COIL_ON => Ok(true),
+                    0 => Ok(false),
+                    _ => Err(Exception::IllegalDataValue),
+                };
+                on.and_then(|on| {
+                    check_range(address, 1, COILS, 1).map(|_| Request::WriteCoil { address, on })
+                })
+            }
+            WRITE_REGISTER => {
+                fixed(4)?;
+                let (address, value) = (word(data, 0), word(data, 2));
+                check_range(address, 1, HOLDING_REGISTERS, 1)
+                    .map(|_| Request::WriteRegister { address, value })
+            }
+            WRITE_REGISTERS => {
+                let bytes = usize::from(*data.get(4).ok_or(Error::InvalidFrame)?);
+                fixed(5 + bytes)?;
+                let (start, count) = (word(data, 0), word(data, 2));
+                check_range(start, count, HOLDING_REGISTERS, MAX_REGISTERS as u16)
+                    .and_then(|_| match bytes == usize::from(count) * 2 {
+                        true => Ok(()),
+                        false => Err(Exception::IllegalDataValue),
+                    })
+                    .map(|_| {
+                        let mut values = [0; MAX_REGISTERS];
+                        for (i, v) in values.iter_mut().take(usize::from(count)).enumerate() {
+                            *v = word(data, 5 + 2 * i);
+                        }
+                        Request::WriteRegisters {
+                            start,
+                            count,
+                            values,
+                        }
+                    })
+            }
+            ENCAPSULATED => {
+                fixed(3)?;
+                match (data[0], data[1], data[2]) {
+                    (READ_DEVICE_ID, BASIC_DEVICE_ID, 0) => Ok(Request::ReadDeviceIdentification),
+                    (READ_DEVICE_ID, BASIC_DEVICE_ID, _) => Err(Exception::IllegalDataAddress),
+                    (READ_DEVICE_ID, _, _) => Err(Exception::IllegalDataValue),
+                    _ => Err(Exception::IllegalFunction),
+                }
+            }
+            _ => Err(Exception::IllegalFunction),
+        };
+        Ok((unit, request))
+    }
+
+    /// The commands that carry out the request on a device in `state`, none
+    /// for reads. Fails with the exception to answer if a value is out of range.
+    pub fn commands(&self, state: &DeviceState) -> Result<[Option<Command>; 2], Exception> {
+        match *self {
+            Request::WriteCoil { address, on } => {
+                let command = match (address, on) {
+                    (COIL_LED, true) => Command::SetGpioPin,
+                    (COIL_LED, false) => Command::ClearGpioPin,
+                    (_, true) => Command::PwmOn,
+                    (_, false) => Command::PwmOff,
+                };
+                Ok([Some(command), None])
+            }
+            Request::WriteRegister { address, value } => write_holding(address, &[value], state),
+            Request::WriteRegisters {
+                start,
+                count,
+                values,
+            } => write_holding(start, &values[..usize::from(count)], state),
+            _ => Ok([None, None]),
+        }
+    }
+
+    /// Writes the reply from slave `unit` into `buf`, reads are answered from
+    /// `state` and `info` as they are after the request's commands ran.
+    pub fn encode_reply(
+        &self,
+        unit: u8,
+        state: &DeviceState,
+        info: &DeviceInfo,
+        buf: &mut [u8],
+    ) -> Result<usize, Error> {
+        let mut w = AduWriter::new(buf, unit, self.function())?;
+        match *self {
+            Request::ReadCoils { start, count } => {
+                let coils = [state.led_on, state.pwm_enabled];
+                let bits = coils[usize::from(start)..][..usize::from(count)]
+                    .iter()
+                    .rev()
+                    .fold(0, |bits, on| bits << 1 | u8::from(*on));
+                w.bytes(&[1, bits])?;
+            }
+            Request::ReadHoldingRegisters { start, count } => {
+                w.byte(count as u8 * 2)?;
+                for v in &holding_registers(state)[usize::from(start)..][..usize::from(count)] {
+                    w.word(*v)?;
+                }
+            }
+            Request::ReadInputRegisters { start, count } => {
+                w.byte(count as u8 * 2)?;
+                for v in &input_registers(state)[usize::from(start)..][..usize::from(count)] {
+                    w.word(*v)?;
+                }
+            }
+            Request::WriteCoil { address, on } => {
+                w.word(address)?;
+                w.word(if on { COIL_ON } else { 0 })?;
+            }
+            Request::WriteRegister { address, value } => {
+                w.word(address)?;
+                w.word(value)?;
+            }
+            Request::WriteRegisters { start, count, .. } => {
+                w.word(start)?;
+                w.word(count)?;
+            }
+            Request::ReadDeviceIdentification => {
+                // Basic conformity, everything in this one reply.
+                w.bytes(&[READ_DEVICE_ID, BASIC_DEVICE_ID, BASIC_DEVICE_ID, 0, 0, 3])?;
+                let objects = [VENDOR, info.board.as_str(), info.firmware_version.as_str()];
+                for (id, value) in objects.iter().enumerate() {
+                    w.bytes(&[id as u8, value.len() as u8])?;
+                    w.bytes(value.as_bytes())?;
+                }
+            }
+        }
+        w.finish()
+    }
+}
+
+/// Writes an exception reply from slave `unit` to a request with `function` into `buf`.
+pub fn encode_exception(
+    unit: u8,
+    function: u8,
+    exception: Exception,
+    buf: &mut [u8],
+) -> Result<usize, Error> {
+    let mut w = AduWriter::new(buf, unit, function | EXCEPTION_FLAG)?;
+    w.byte(exception.to_u8())?;
+    w.finish()
+}
+
+/// Length of the request frame at the start of `buf`, `None` until enough of
+/// it has arrived to tell. Frames with an unknown function code take the rest of `buf`.
+pub fn request_len(buf: &[u8]) -> Option<usize> {
+    match *buf.get(1)? {
+        READ_COILS
+        | READ_HOLDING_REGISTERS
+        | READ_INPUT_REGISTERS
+        | WRITE_COIL
+        | WRITE_REGISTER => Some(8),
+        WRITE_REGISTERS => buf.get(6).map(|n| 9 + usize::from(*n)),
+        ENCAPSULATED => Some(7),
+        _ => Some(buf.len()),
+    }
+}
+
+/// Length of the reply frame at the start of `buf`, like [`request_len`].
+pub fn reply_len(buf: &[u8]) -> Option<usize> {
+    match *buf.get(1)? {
+        f if f & EXCEPTION_FLAG != 0 => Some(5),
+        READ_COILS | READ_HOLDING_REGISTERS | READ_INPUT_REGISTERS => {
+            buf.get(2).map(|n| 5 + usize::from(*n))
+        }
+        WRITE_COIL | WRITE_REGISTER | WRITE_REGISTERS => Some(8),
+        ENCAPSULATED => {
+            // Objects follow the count in byte 7, each an id and a length before the value.
+            let mut end = 8;
+            for _ in 0..*buf.get(7)? {
+                end += 2 + usize::from(*buf.get(end + 1)?);
+            }
+            Some(end + 2)
+        }
+        _ => Some(buf.len()),
+    }
+}
+
+/// A slave's answer to a [`Request`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Reply {
+    /// Coil states, the first coil read in bit 0.
+    Coils(u8),
+    /// Only the first `count` of `values` were read.
+    Registers {
+        values: [u16; MAX_REGISTERS],
+        count: usize,
+    },
+    /// The write was carried out.
+    Written,
+    /// The VendorName, ProductCode and MajorMinorRevision objects.
+    Identification {
+        vendor: Label,
+        product: Label,
+        revision: Label,
+    },
+    Exception(Exception),
+}
+
+fn label(value: &[u8]) -> Result<Label, Error> {
+    core::str::from_utf8(value)
+        .map_err(|_| Error::InvalidArgument)
+        .and_then(Label::new)
+}
+
+impl Reply {
+    /// Parses `adu` as the answer to `request`, returning the unit it came from.
+    pub fn decode(adu: &[u8], request: &Request) -> Result<(u8, Reply), Error> {
+        let (unit, function, data) = open(adu)?;
+        if function == request.function() | EXCEPTION_FLAG {
+            return match data {
+                [code] => Ok((unit, Reply::Exception(Exception::from(*code)))),
+                _ => Err(Error::InvalidFrame),
+            };
+        }
+        if function != request.function() {
+            return Err(Error::UnknownTag(function));
+        }
+        let reply = match *request {
+            Request::ReadCoils { count, .. } => match data {
+                [1, bits] => Reply::Coils(bits & ((1u16 << count) - 1) as u8),
+                _ => return Err(Error::InvalidFrame),
+            },
+            Request::ReadHoldingRegisters { count, .. }
+            | Request::ReadInputRegisters { count, .. } => {
+                let count = usize::from(count);
+                if count > MAX_REGISTERS
+                    || data.len() != 1 + 2 * count
+                    || usize::from(data[0]) != 2 * count
+                {
+                    return Err(Error::InvalidFrame);
+                }
+                let mut values = [0; MAX_REGISTERS];
+                for (i, v) in values.iter_mut().take(count).enumerate() {
+                    *v = word(data, 1 + 2 * i);
+                }
+                Reply::Registers { values, count }
+            }
+            Request::WriteCoil { .. }
+            | Request::WriteRegister { .. }
+            | Request::WriteRegisters { .. } => {
+                // The echo is the request's first four data bytes.
+                let mut sent = [0u8; MAX_ADU];
+                request.encode(unit, &mut sent)?;
+                match data.len() == 4 && data == &sent[2..6] {
+                    true => Reply::Written,
+                    false => return Err(Error::InvalidFrame),
+                }
+            }
+            Request::ReadDeviceIdentification => {
+                let objects = data.get(6..).ok_or(Error::InvalidFrame)?;
+                let mut found = [None; 3];
+                let mut rest = objects;
+                for _ in 0..data[5] {
+                    let [id, len, tail @ ..] = rest else {
+                        return Err(Error::InvalidFrame);
+                    };
+                    let value = tail.get(..usize::from(*len)).ok_or(Error::InvalidFrame)?;
+                    if let Some(slot) = found.get_mut(usize::from(*id)) {
+                        *slot = Some(label(value)?);
+                    }
+                    rest = &tail[usize::from(*len)..];
+                }
+                match found {
+                    [Some(vendor), Some(product), Some(revision)] => Reply::Identification {
+                        vendor,
+                        product,
+                        revision,
+                    },
+                    _ => return Err(Error::InvalidFrame),
+                }
+            }
+        };
+        Ok((unit, reply))
+    }
+}
+
+/// A request a slave has to act on: run its commands, then send its reply.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Transaction {
+    unit: u8,
+    function: u8,
+    request: Result<(Request, [Option<Command>; 2]), Exception>,
+}
+
+impl Transaction {
+    /// Parses a frame received by slave `unit` on a device in `state`. Gives
+    /// `None` for frames the slave ignores, corrupt or for another slave.
+    pub fn parse(adu: &[u8], unit: u8, state: &DeviceState) -> Option<Self> {
+        let (to, request) = Request::decode(adu).ok()?;
+        if to != unit && to != BROADCAST {
+            return None;
+        }
+        let request = request.and_then(|r| r.commands(state).map(|c| (r, c)));
+        Some(Self {
+            unit: to,
+            function: adu[1],
+            request,
+        })
+    }
+
+    /// What to apply to the device, in order.
+    pub fn commands(&self) -> impl Iterator<Item = Command> {
+        let commands = match self.request {
+            Ok((_, commands)) => commands,
+            Err(_) => [None, None],
+        };
+        commands.into_iter().flatten()
+    }
+
+    /// Writes the reply once the commands ran, nothing for a broadcast.
+    pub fn reply(&self, state: &DeviceState, info: &DeviceInfo, buf: &mut [u8]) -> Option<usize> {
+        if self.unit == BROADCAST {
+            return None;
+        }
+        match self.request {
+            Ok((request, _)) => request.encode_reply(self.unit, state, info, buf),
+            Err(e) => encode_exception(self.unit, self.function, e, buf),
+        }
+        .ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PROTOCOL_VERSION;
+
+    fn state() -> DeviceState {
+        DeviceState {
+            led_on: true,
+            pwm_enabled: false,
+            pwm_duty: 25,
+            pwm_frequency: 100_000,
+            uptime_ms: 0x0001_0002,
+        }
+    }
+
+    fn info() -> DeviceInfo {
+        DeviceInfo {
+            protocol_version: PROTOCOL_VERSION,
+            firmware_version: Label::new("0.1.0").unwrap(),
+            board: Label::new("NUCLEO-L476RG").unwrap(),
+            capabilities: capabilities(),
+        }
+    }
+
+    /// Runs `request` through a slave on a device in `state()` and parses the reply.
+    fn exchange(request: Request) -> Reply {
+        let mut frame = [0u8; MAX_ADU];
+        let n = request.encode(DEFAULT_UNIT, &mut frame).unwrap();
+        assert_eq!(request_len(&frame[..n]), Some(n));
+        let transaction = Transaction::parse(&frame[..n], DEFAULT_UNIT, &state()).unwrap();
+        let mut reply = [0u8; MAX_ADU];
+        let n = transaction.reply(&state(), &info(), &mut reply).unwrap();
+        assert_eq!(reply_len(&reply[..n]), Some(n));
+        let (unit, reply) = Reply::decode(&reply[..n], &request).unwrap();
+        assert_eq!(unit, DEFAULT_UNIT);
+        reply
+    }
+
+    #[test]
+    fn matches_the_reference_crc() {
+        let mut frame = [0u8; 8];
+        let request = Request::ReadHoldingRegisters {
+            start: 0,
+            count: 10,
+        };
+        request.encode(1, &mut frame).unwrap();
+        assert_eq!(frame, [0x01, 0x03, 0x00, 0x00, 0x00, 0x0a, 0xc5, 0xcd]);
+    }
+
+    #[test]
+    fn reads_the_tables() {
+        assert_eq!(
+            exchange(Request::ReadCoils { start: 0, count: 2 }),
+            Reply::Coils(0b01)
+        );
+        assert_eq!(
+            exchange(Request::ReadHoldingRegisters { start: 0, count: 3 }),
+            Reply::Registers {
+                values: [25, 1, 34464],
+                count: 3
+            }
+        );
+        assert_eq!(
+            exchange(Request::ReadInputRegisters { start: 0, count: 2 }),
+            Reply::Registers {
+                values: [1, 2, 0],
+                count: 2
+            }
+        );
+        assert_eq!(
+            exchange(Request::ReadDeviceIdentification),
+            Reply::Identification {
+                vendor: Label::new(VENDOR).unwrap(),
+                product: Label::new("NUCLEO-L476RG").unwrap(),
+                revision: Label::new("0.1.0").unwrap(),
+            }
+        );
+    }
+
+    #[test]
+    fn writes_become_commands() {
+        let write = Request::WriteRegisters {
+            start: 0,
+            count: 3,
+            values: [40, 0, 2000],
+        };
+        assert_eq!(
+            write.commands(&state()),
+            Ok([Some(Command::PwmDuty(40)), Some(Command::PwmSetFreq(2000))])
+        );
+        assert_eq!(exchange(write), Reply::Written);
+        // The other word of the frequency comes from the state.
+        let low = Request::WriteRegister {
+            address: 2,
+            value: 0,
+        };
+        assert_eq!(
+            low.commands(&state()),
+            Ok([None, Some(Command::PwmSetFreq(65536))])
+        );
+        let pwm = Request::WriteCoil {
+            address: COIL_PWM,
+            on: true,
+        };
+        assert_eq!(pwm.commands(&state()), Ok([Some(Command::PwmOn), None]));
+    }
+
+    #[test]
+    fn refuses_what_the_device_lacks() {
+        let duty = Request::WriteRegister {
+            address: HOLDING_DUTY,
+            value: 101,
+        };
+        assert_eq!(
+            exchange(duty),
+            Reply::Exception(Exception::IllegalDataValue)
+        );
+        assert_eq!(
+            exchange(Request::ReadCoils { start: 1, count: 2 }),
+            Reply::Exception(Exception::IllegalDataAddress)
+        );
+        // Read exception status, which the device doesn't have.
+        let mut frame = [0x01, 0x07, 0, 0];
+        let crc = crc16(&frame[..2]).to_le_bytes();
+        frame[2..].copy_from_slice(&crc);
+        assert_eq!(
+            Request::decode(&frame),
+            Ok((1, Err(Exception::IllegalFunction)))
+        );
+    }
+
+    #[test]
+    fn ignores_corrupt_and_foreign_frames() {
+        let mut frame = [0u8; 8];
+        Request::ReadCoils { start: 0, count: 1 }
+            .encode(2, &mut frame)
+            .unwrap();
+        assert_eq!(Transaction::parse(&frame, DEFAULT_UNIT, &state()), None);
+        assert!(Transaction::parse(&frame, 2, &state()).is_some());
+        frame[3] ^= 1;
+        assert_eq!(Request::decode(&frame), Err(Error::Checksum));
+    }
+
+    #[test]
+    fn broadcasts_are_applied_but_not_answered() {
+        let mut frame = [0u8; 8];
+        let write = Request::WriteCoil {
+            address: COIL_LED,
+            on: false,
+        };
+        write.encode(BROADCAST, &mut frame).unwrap();
+        let transaction = Transaction::parse(&frame, DEFAULT_UNIT, &state()).unwrap();
+        assert!(transaction.commands().eq([Command::ClearGpioPin]));
+        assert_eq!(
+            transaction.reply(&state(), &info(), &mut [0; MAX_ADU]),
+            None
+        );
+    }
+}
@@ -0,0 +1,230 @@
+use crate::{parse_argument, trim_frame, write_frame, Command, DeviceInfo, Error};
+use core::fmt;
+use core::time::Duration;
+
+/// Snapshot of everything the firmware keeps in its `AppState`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DeviceState {
+    pub led_on: bool,
+    pub pwm_enabled: bool,
+    /// Duty cycle in percent.
+    pub pwm_duty: u8,
+    /// PWM frequency in Hz.
+    pub pwm_frequency: u32,
+    /// Milliseconds since the device booted.
+    pub uptime_ms: u32,
+}
+
+impl DeviceState {
+    pub fn uptime(&self) -> Duration {
+        Duration::from_millis(self.uptime_ms.into())
+    }
+
+    /// Parses the comma separated fields following the `S` tag.
+    pub(crate) fn decode(arg: &[u8]) -> Result<Self, Error> {
+        let mut fields = arg.split(|b| *b == b',');
+        let mut next = || fields.next().ok_or(Error::InvalidArgument);
+        let flag = |f: &[u8]| match f {
+            b"0" => Ok(false),
+            b"1" => Ok(true),
+            _ => Err(Error::InvalidArgument),
+        };
+        let state = DeviceState {
+            led_on: flag(next()?)?,
+            pwm_enabled: flag(next()?)?,
+            pwm_duty: parse_argument(next()?)?,
+            pwm_frequency: parse_argument(next()?)?,
+            uptime_ms: parse_argument(next()?)?,
+        };
+        if fields.next().is_some() {
+            return Err(Error::InvalidArgument);
+        }
+        Ok(state)
+    }
+}
+
+/// The comma separated fields, as sent after the `S` tag.
+impl fmt::Display for DeviceState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{},{},{},{},{}",
+            self.led_on as u8,
+            self.pwm_enabled as u8,
+            self.pwm_duty,
+            self.pwm_frequency,
+            self.uptime_ms
+        )
+    }
+}
+
+/// Reason the device gives for rejecting a command.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum ErrorCode {
+    /// The frame couldn't be parsed as a command.
+    Malformed,
+    /// The command's argument is outside the accepted range.
+    OutOfRange,
+    /// A binary frame failed its CRC check.
+    Checksum,
+    /// A code this version of the protocol doesn't know, or none at all.
+    Other(u8),
+}
+
+impl ErrorCode {
+    pub fn to_u8(self) -> u8 {
+        match self {
+            ErrorCode::Malformed => 1,
+            ErrorCode::OutOfRange => 2,
+            ErrorCode::Checksum => 3,
+            ErrorCode::Other(c) => c,
+        }
+    }
+}
+
+impl From<u8> for ErrorCode {
+    fn from(code: u8) -> Self {
+        match code {
+            1 => ErrorCode::Malformed,
+            2 => ErrorCode::OutOfRange,
+            3 => ErrorCode::Checksum,
+            c => ErrorCode::Other(c),
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorCode::Malformed => write!(f, "malformed command"),
+            ErrorCode::OutOfRange => write!(f, "argument out of range"),
+            ErrorCode::Checksum => write!(f, "checksum mismatch"),
+            ErrorCode::Other(c) => write!(f, "error code {}", c),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Response {
+    /// The command was accepted, the device echoes it back.
+    Ack(Command),
+    /// The command was rejected. Older firmware sends a bare `X`, which
+    /// decodes as `ErrorCode::Other(0)`.
+    Error(ErrorCode),
+    /// Milliseconds since the device booted.
+    Time(u32),
+    /// Reply to [`Command::GetState`].
+    State(DeviceState),
+    /// Reply to [`Command::Identify`].
+    Identity(DeviceInfo),
+}
+
+impl Response {
+    /// Writes the response frame, delimiter included, into `buf`.
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        match self {
+            Response::Ack(cmd) => cmd.encode(buf),
+            Response::Error(code) => write_frame(buf, format_args!("X{}", code.to_u8())),
+            Response::Time(ms) => write_frame(buf, format_args!("T{}", ms)),
+            Response::State(s) => write_frame(buf, format_args!("S{}", s)),
+            Response::Identity(i) => write_frame(
+                buf,
+                format_args!(
+                    "I{},{},{},{}",
+                    i.protocol_version, i.firmware_version, i.board, i.capabilities
+                ),
+            ),
+        }
+    }
+
+    /// Parses a single frame, with or without its delimiter.
+    pub fn decode(frame: &[u8]) -> Result<Self, Error> {
+        let trimmed = trim_frame(frame);
+        match trimmed.split_first() {
+            Some((b'X', [])) => Ok(Response::Error(ErrorCode::Other(0))),
+            Some((b'X', arg)) => parse_argument::<u8>(arg).map(|c| Response::Error(c.into())),
+            Some((b'T', arg)) if !arg.is_empty() => parse_argument(arg).map(Response::Time),
+            Some((b'S', arg)) if !arg.is_empty() => DeviceState::decode(arg).map(Response::State),
+            Some((b'I', arg)) if !arg.is_empty() => DeviceInfo::decode(arg).map(Response::Identity),
+            _ => Command::decode(frame).map(Response::Ack),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FRAME_SIZE;
+
+    #[test]
+    fn round_trip() {
+        let all = [
+            Response::Ack(Command::PwmOn),
+            Response::Ack(Command::PwmDuty(50)),
+            Response::Ack(Command::PwmSetFreq(2000)),
+            Response::Ack(Command::GetTime),
+            Response::Error(ErrorCode::Malformed),
+            Response::Error(ErrorCode::OutOfRange),
+            Response::Error(ErrorCode::Other(200)),
+            Response::Time(0),
+            Response::Time(u32::MAX),
+            Response::State(DeviceState::default()),
+            Response::State(DeviceState {
+                led_on: true,
+                pwm_enabled: true,
+                pwm_duty: 100,
+                pwm_frequency: u32::MAX,
+                uptime_ms: u32::MAX,
+            }),
+        ];
+        for resp in all {
+            let mut buf = [0u8; FRAME_SIZE];
+            let n = resp.encode(&mut buf).unwrap();
+            assert_eq!(Response::decode(&buf[..n]), Ok(resp));
+        }
+    }
+
+    #[test]
+    fn decodes_device_frames() {
+        assert_eq!(
+            Response::decode(b"X\n"),
+            Ok(Response::Error(ErrorCode::Other(0)))
+        );
+        assert_eq!(
+            Response::decode(b"X2\n"),
+            Ok(Response::Error(ErrorCode::OutOfRange))
+        );
+        assert_eq!(Response::decode(b"T1234\r\n"), Ok(Response::Time(1234)));
+        assert_eq!(
+            Response::decode(b"P\n"),
+            Ok(Response::Ack(Command::SetGpioPin))
+        );
+        assert_eq!(Response::decode(b"Q\n"), Err(Error::UnknownTag(b'Q')));
+        assert_eq!(
+            Response::decode(b"S\n"),
+            Ok(Response::Ack(Command::GetState))
+        );
+        assert_eq!(
+            Response::decode(b"S1,0,25,1000,42\n"),
+            Ok(Response::State(DeviceState {
+                led_on: true,
+                pwm_enabled: false,
+                pwm_duty: 25,
+                pwm_frequency: 1000,
+                uptime_ms: 42,
+            }))
+        );
+        assert_eq!(Response::decode(b"S1,0,25\n"), Err(Error::InvalidArgument));
+        assert_eq!(
+            Response::decode(b"S2,0,25,1000,42\n"),
+            Err(Error::InvalidArgument)
+        );
+        assert_eq!(
+            Response::decode(b"S1,0,25,1000,42,7\n"),
+            Err(Error::InvalidArgument)
+        );
+    }
+}
@@ -0,0 +1,630 @@
+//! SCPI, for lab automation that expects a bench instrument rather than the line protocol.
+//!
+//! | Command                        | Query                  | Meaning                               |
+//! |--------------------------------|------------------------|---------------------------------------|
+//! |                                | `*IDN?`                | `iced,<board>,0,<firmware version>`   |
+//! | `*RST`                         |                        | LED and output off, 25 % at 1000 Hz   |
+//! | `*CLS`                         |                        | empties the error queue               |
+//! | `SOURce:LED ON\|OFF`           | `SOURce:LED?`          | the LED                               |
+//! | `OUTPut[:STATe] ON\|OFF`       | `OUTPut[:STATe]?`      | PWM output enabled                    |
+//! | `SOURce:PWM:DUTY <percent>`    | `SOURce:PWM:DUTY?`     | duty cycle                            |
+//! | `SOURce:PWM:FREQuency <hz>`    | `SOURce:PWM:FREQuency?`| PWM frequency                         |
+//! |                                | `SYSTem:ERRor[:NEXT]?` | oldest queued error, `0,"No error"`   |
+//! |                                | `SYSTem:UPTime?`       | milliseconds since boot               |
+//!
+//! Keywords match in their short or long form in any case, booleans are `ON`,
+//! `OFF`, `1` or `0` and queries answer `1` or `0`. Commands don't reply, an
+//! invalid one queues an [`ScpiError`] for `SYSTem:ERRor?` instead. Only one
+//! command or query is accepted per line.
+
+use crate::{trim_frame, write_frame, Capabilities, Command, DeviceInfo, DeviceState, Error};
+use crate::{ErrorCode, Label, PROTOCOL_VERSION};
+use core::str;
+
+/// Manufacturer field of the `*IDN?` answer.
+pub const MANUFACTURER: &str = "iced";
+/// Errors kept for `SYSTem:ERRor?`, the last one is replaced by
+/// [`ScpiError::QueueOverflow`] when more occur.
+pub const QUEUE_SIZE: usize = 8;
+
+/// What `*RST` applies: both outputs off, the duty cycle and frequency the firmware boots with.
+pub const RESET: [Command; 4] = [
+    Command::ClearGpioPin,
+    Command::PwmOff,
+    Command::PwmDuty(25),
+    Command::PwmSetFreq(1000),
+];
+
+/// The commands an SCPI client can give, the line protocol's framing and
+/// telemetry have no equivalent.
+pub fn capabilities() -> Capabilities {
+    Capabilities::from_tags(b"EODFPCTSI")
+}
+
+/// Whether a line frame is SCPI rather than a single-letter command. Those
+/// are a tag followed by digits, SCPI starts with `*`, `:` or a keyword.
+pub fn is_scpi(frame: &[u8]) -> bool {
+    match trim_frame(frame) {
+        [b'*' | b':', ..] => true,
+        [a, b, ..] => a.is_ascii_alphabetic() && b.is_ascii_alphabetic(),
+        _ => false,
+    }
+}
+
+/// An entry of the error queue, with its standard SCPI code.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ScpiError {
+    /// The line isn't valid text.
+    Syntax,
+    /// A number was expected.
+    DataType,
+    /// A query or a command without parameters was given one.
+    ParameterNotAllowed,
+    MissingParameter,
+    /// No such command, or a query-only header used as a command or the reverse.
+    UndefinedHeader,
+    DataOutOfRange,
+    /// A boolean other than `ON`, `OFF`, `1` or `0`.
+    IllegalParameterValue,
+    QueueOverflow,
+}
+
+impl ScpiError {
+    const ALL: [ScpiError; 8] = [
+        ScpiError::Syntax,
+        ScpiError::DataType,
+        ScpiError::ParameterNotAllowed,
+        ScpiError::MissingParameter,
+        ScpiError::UndefinedHeader,
+        ScpiError::DataOutOfRange,
+        ScpiError::IllegalParameterValue,
+        ScpiError::QueueOverflow,
+    ];
+
+    pub fn code(self) -> i16 {
+        match self {
+            ScpiError::Syntax => -102,
+            ScpiError::DataType => -104,
+            ScpiError::ParameterNotAllowed => -108,
+            ScpiError::MissingParameter => -109,
+            ScpiError::UndefinedHeader => -113,
+            ScpiError::DataOutOfRange => -222,
+            ScpiError::IllegalParameterValue => -224,
+            ScpiError::QueueOverflow => -350,
+        }
+    }
+
+    pub fn message(self) -> &'static str {
+        match self {
+            ScpiError::Syntax => "Syntax error",
+            ScpiError::DataType => "Data type error",
+            ScpiError::ParameterNotAllowed => "Parameter not allowed",
+            ScpiError::MissingParameter => "Missing parameter",
+            ScpiError::UndefinedHeader => "Undefined header",
+            ScpiError::DataOutOfRange => "Data out of range",
+            ScpiError::IllegalParameterValue => "Illegal parameter value",
+            ScpiError::QueueOverflow => "Queue overflow",
+        }
+    }
+
+    pub fn from_code(code: i16) -> Option<Self> {
+        Self::ALL.into_iter().find(|e| e.code() == code)
+    }
+}
+
+impl From<ScpiError> for ErrorCode {
+    fn from(e: ScpiError) -> Self {
+        match e {
+            ScpiError::DataOutOfRange => ErrorCode::OutOfRange,
+            _ => ErrorCode::Malformed,
+        }
+    }
+}
+
+/// What a query asks for.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Query {
+    Identify,
+    Led,
+    Output,
+    Duty,
+    Frequency,
+    Error,
+    Uptime,
+}
+
+impl Query {
+    /// The short form of the header, without the `?`.
+    fn header(self) -> &'static str {
+        match self {
+            Query::Identify => "*IDN",
+            Query::Led => "SOUR:LED",
+            Query::Output => "OUTP",
+            Query::Duty => "SOUR:PWM:DUTY",
+            Query::Frequency => "SOUR:PWM:FREQ",
+            Query::Error => "SYST:ERR",
+            Query::Uptime => "SYST:UPT",
+        }
+    }
+
+    /// Parses the device's answer to this query.
+    pub fn decode(self, frame: &[u8]) -> Result<Answer, Error> {
+        let text = str::from_utf8(trim_frame(frame))
+            .map_err(|_| Error::InvalidArgument)?
+            .trim();
+        let number = || text.parse().map_err(|_| Error::InvalidArgument);
+        match self {
+            Query::Identify => {
+                let mut fields = text.split(',');
+                let (Some(_), Some(model), Some(_), Some(firmware), None) = (
+                    fields.next(),
+                    fields.next(),
+                    fields.next(),
+                    fields.next(),
+                    fields.next(),
+                ) else {
+                    return Err(Error::InvalidArgument);
+                };
+                Ok(Answer::Identity(DeviceInfo {
+                    // The command set is versioned with the line protocol.
+                    protocol_version: PROTOCOL_VERSION,
+                    firmware_version: Label::new(firmware.trim())?,
+                    board: Label::new(model.trim())?,
+                    capabilities: capabilities(),
+                }))
+            }
+            Query::Led | Query::Output => boolean(text)
+                .map(Answer::Flag)
+                .map_err(|_| Error::InvalidArgument),
+            Query::Duty | Query::Frequency | Query::Uptime => number().map(Answer::Number),
+            Query::Error => {
+                let (code, _) = text.split_once(',').ok_or(Error::InvalidArgument)?;
+                match code.trim().parse().map_err(|_| Error::InvalidArgument)? {
+                    0 => Ok(Answer::Error(None)),
+                    code => ScpiError::from_code(code)
+                        .map(|e| Answer::Error(Some(e)))
+                        .ok_or(Error::InvalidArgument),
+                }
+            }
+        }
+    }
+}
+
+/// An answer to a [`Query`], as read by the host.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Answer {
+    Identity(DeviceInfo),
+    Flag(bool),
+    Number(u32),
+    /// The oldest queued error, `None` when there was none.
+    Error(Option<ScpiError>),
+}
+
+/// One line of SCPI.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Message {
+    /// A setting, carried out by the line protocol command it corresponds to.
+    Apply(Command),
+    Reset,
+    Clear,
+    Query(Query),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Header {
+    Reset,
+    Clear,
+    Query(Query),
+}
+
+/// Every header, keywords in the SCPI convention of the short form in capitals.
+const HEADERS: &[(&[&str], Header)] = &[
+    (&["*IDN"], Header::Query(Query::Identify)),
+    (&["*RST"], Header::Reset),
+    (&["*CLS"], Header::Clear),
+    (&["SOURce", "LED"], Header::Query(Query::Led)),
+    (&["OUTPut"], Header::Query(Query::Output)),
+    (&["OUTPut", "STATe"], Header::Query(Query::Output)),
+    (&["SOURce", "PWM", "DUTY"], Header::Query(Query::Duty)),
+    (
+        &["SOURce", "PWM", "FREQuency"],
+        Header::Query(Query::Frequency),
+    ),
+    (&["SYSTem", "ERRor"], Header::Query(Query::Error)),
+    (&["SYSTem", "ERRor", "NEXT"], Header::Query(Query::Error)),
+    (&["SYSTem", "UPTime"], Header::Query(Query::Uptime)),
+];
+
+/// Whether `node` is the short or long form of `keyword`.
+fn keyword(keyword: &str, node: &str) -> bool {
+    let short = keyword
+        .bytes()
+        .take_while(|b| !b.is_ascii_lowercase())
+        .count();
+    node.eq_ignore_ascii_case(keyword) || node.eq_ignore_ascii_case(&keyword[..short])
+}
+
+fn header(text: &str) -> Option<Header> {
+    let text = text.strip_prefix(':').unwrap_or(text);
+    HEADERS.iter().find_map(|(path, header)| {
+        let mut nodes = text.split(':');
+        let matched = path
+            .iter()
+            .all(|k| nodes.next().is_some_and(|n| keyword(k, n)));
+        (matched && nodes.next().is_none()).then_some(*header)
+    })
+}
+
+fn boolean(text: &str) -> Result<bool, ScpiError> {
+    if text == "1" || text.eq_ignore_ascii_case("ON") {
+        Ok(true)
+    } else if text == "0" || text.eq_ignore_ascii_case("OFF") {
+        Ok(false)
+    } else {
+        Err(ScpiError::IllegalParameterValue)
+    }
+}
+
+fn number<N: TryFrom<u64>>(text: &str) -> Result<N, ScpiError> {
+    let n: u64 = text.parse().map_err(|_| ScpiError::DataType)?;
+    n.try_into().map_err(|_| ScpiError::DataOutOfRange)
+}
+
+/// The command setting what `query` reads to `parameter`.
+fn setting(query: Query, parameter: &str) -> Result<Command, ScpiError> {
+    let command = match query {
+        Query::Led if boolean(parameter)? => Command::SetGpioPin,
+        Query::Led => Command::ClearGpioPin,
+        Query::Output if boolean(parameter)? => Command::PwmOn,
+        Query::Output => Command::PwmOff,
+        Query::Duty => Command::PwmDuty(number(parameter)?),
+        Query::Frequency => Command::PwmSetFreq(number(parameter)?),
+        Query::Identify | Query::Error | Query::Uptime => return Err(ScpiError::UndefinedHeader),
+    };
+    match command.in_range() {
+        true => Ok(command),
+        false => Err(ScpiError::DataOutOfRange),
+    }
+}
+
+impl Message {
+    /// Parses one line, delimiter included or not.
+    pub fn parse(frame: &[u8]) -> Result<Self, ScpiError> {
+        let text = str::from_utf8(trim_frame(frame))
+            .map_err(|_| ScpiError::Syntax)?
+            .trim();
+        let (text, parameter) = match text.split_once(|c: char| c.is_ascii_whitespace()) {
+            Some((text, parameter)) => (text, Some(parameter.trim())),
+            None => (text, None),
+        };
+        let (text, query) = match text.strip_suffix('?') {
+            Some(text) => (text, true),
+            None => (text, false),
+        };
+        match (
+            header(text).ok_or(ScpiError::UndefinedHeader)?,
+            query,
+            parameter,
+        ) {
+            (Header::Reset | Header::Clear, _, Some(_)) | (_, true, Some(_)) => {
+                Err(ScpiError::ParameterNotAllowed)
+            }
+            (Header::Reset, false, None) => Ok(Message::Reset),
+            (Header::Clear, false, None) => Ok(Message::Clear),
+            (Header::Query(q), true, None) => Ok(Message::Query(q)),
+            (Header::Query(q), false, Some(p)) => setting(q, p).map(Message::Apply),
+            (Header::Query(Query::Identify | Query::Error | Query::Uptime), false, None) => {
+                Err(ScpiError::UndefinedHeader)
+            }
+            (Header::Query(_), false, None) => Err(ScpiError::MissingParameter),
+            (Header::Reset | Header::Clear, true, None) => Err(ScpiError::UndefinedHeader),
+        }
+    }
+
+    /// What to apply to the device, in order.
+    pub fn commands(&self) -> &[Command] {
+        match self {
+            Message::Apply(command) => core::slice::from_ref(command),
+            Message::Reset => &RESET,
+            Message::Clear | Message::Query(_) => &[],
+        }
+    }
+
+    /// Writes the line, delimiter included, in short form. Fails for commands
+    /// SCPI has no setting for.
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        match *self {
+            Message::Apply(Command::SetGpioPin) => write_frame(buf, format_args!("SOUR:LED ON")),
+            Message::Apply(Command::ClearGpioPin) => write_frame(buf, format_args!("SOUR:LED OFF")),
+            Message::Apply(Command::PwmOn) => write_frame(buf, format_args!("OUTP ON")),
+            Message::Apply(Command::PwmOff) => write_frame(buf, format_args!("OUTP OFF")),
+            Message::Apply(Command::PwmDuty(duty)) => {
+                write_frame(buf, format_args!("SOUR:PWM:DUTY {}", duty))
+            }
+            Message::Apply(Command::PwmSetFreq(hz)) => {
+                write_frame(buf, format_args!("SOUR:PWM:FREQ {}", hz))
+            }
+            Message::Apply(_) => Err(Error::InvalidArgument),
+            Message::Reset => write_frame(buf, format_args!("*RST")),
+            Message::Clear => write_frame(buf, format_args!("*CLS")),
+            Message::Query(q) => write_frame(buf, format_args!("{}?", q.header())),
+        }
+    }
+}
+
+/// The device side: parses lines and keeps the error queue between them.
+#[derive(Debug, Clone)]
+pub struct Instrument {
+    errors: [ScpiError; QUEUE_SIZE],
+    queued: usize,
+}
+
+impl Instrument {
+    pub const fn new() -> Self {
+        Self {
+            errors: [ScpiError::Syntax; QUEUE_SIZE],
+            queued: 0,
+        }
+    }
+
+    /// Parses a line, queueing the error if it's invalid.
+    pub fn parse(&mut self, frame: &[u8]) -> Option<Message> {
+        Message::parse(frame).map_err(|e| self.push_error(e)).ok()
+    }
+
+    pub fn push_error(&mut self, error: ScpiError) {
+        if self.queued < QUEUE_SIZE {
+            self.errors[self.queued] = error;
+            self.queued += 1;
+        } else {
+            self.errors[QUEUE_SIZE - 1] = ScpiError::QueueOverflow;
+        }
+    }
+
+    /// Takes the oldest error off the queue.
+    pub fn next_error(&mut self) -> Option<ScpiError> {
+        if self.queued == 0 {
+            return None;
+        }
+        let error = self.errors[0];
+        self.errors.copy_within(1..self.queued, 0);
+        self.queued -= 1;
+        Some(error)
+    }
+
+    /// Finishes `message` once its commands ran on a device now in `state`:
+    /// empties the queue for `*CLS` and writes the answer to a query. Gives
+    /// `None` when there's nothing to send.
+    pub fn answer(
+        &mut self,
+        message: &Message,
+        state: &DeviceState,
+        info: &DeviceInfo,
+        buf: &mut [u8],
+    ) -> Option<usize> {
+        let query = match message {
+            Message::Query(q) => *q,
+            Message::Clear => {
+                self.queued = 0;
+                return None;
+            }
+            Message::Apply(_) | Message::Reset => return None,
+        };
+        match query {
+            Query::Identify => write_frame(
+                buf,
+                format_args!(
+                    "{},{},0,{}",
+                    MANUFACTURER, info.board, info.firmware_version
+                ),
+            ),
+            Query::Led => write_frame(buf, format_args!("{}", u8::from(state.led_on))),
+            Query::Output => write_frame(buf, format_args!("{}", u8::from(state.pwm_enabled))),
+            Query::Duty => write_frame(buf, format_args!("{}", state.pwm_duty)),
+            Query::Frequency => write_frame(buf, format_args!("{}", state.pwm_frequency)),
+            Query::Uptime => write_frame(buf, format_args!("{}", state.uptime_ms)),
+            Query::Error => match self.next_error() {
+                Some(e) => write_frame(buf, format_args!("{},\"{}\"", e.code(), e.message())),
+                None => write_frame(buf, format_args!("0,\"No error\"")),
+            },
+        }
+        .ok()
+    }
+}
+
+impl Default for Instrument {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::FRAME_SIZE;
+
+    fn state() -> DeviceState {
+        DeviceState {
+            led_on: true,
+            pwm_enabled: false,
+            pwm_duty: 40,
+            pwm_frequency: 20_000,
+            uptime_ms: 1234,
+        }
+    }
+
+    fn info() -> DeviceInfo {
+        DeviceInfo {
+            protocol_version: PROTOCOL_VERSION,
+            firmware_version: Label::new("0.1.0").unwrap(),
+            board: Label::new("NUCLEO-L476RG").unwrap(),
+            capabilities: Capabilities::all(),
+        }
+    }
+
+    /// Sends `line` to `instrument` and returns its answer, if any.
+    fn ask<'a>(instrument: &mut Instrument, line: &str, buf: &'a mut [u8]) -> Option<&'a str> {
+        let message = instrument.parse(line.as_bytes())?;
+        let n = instrument.answer(&message, &state(), &info(), buf)?;
+        Some(str::from_utf8(&buf[..n]).unwrap())
+    }
+
+    #[test]
+    fn tells_scpi_from_line_commands() {
+        for line in ["*IDN?\n", ":OUTP ON", "sour:pwm:duty?", "OUTP 1\r\n"] {
+            assert!(is_scpi(line.as_bytes()), "{}", line);
+        }
+        for line in ["S\n", "D40\n", "F1000", "12:S\n", "!B\n", ""] {
+            assert!(!is_scpi(line.as_bytes()), "{}", line);
+        }
+    }
+
+    #[test]
+    fn parses_short_and_long_forms() {
+        let duty = Message::Apply(Command::PwmDuty(40));
+        assert_eq!(Message::parse(b"SOUR:PWM:DUTY 40\n"), Ok(duty));
+        assert_eq!(Message::parse(b":source:pwm:duty 40"), Ok(duty));
+        assert_eq!(
+            Message::parse(b"SOURce:PWM:FREQuency 1000\r\n"),
+            Ok(Message::Apply(Command::PwmSetFreq(1000)))
+        );
+        assert_eq!(
+            Message::parse(b"OUTP:STAT OFF"),
+            Ok(Message::Apply(Command::PwmOff))
+        );
+        assert_eq!(
+            Message::parse(b"syst:err:next?"),
+            Ok(Message::Query(Query::Error))
+        );
+        assert_eq!(Message::parse(b"*rst"), Ok(Message::Reset));
+    }
+
+    #[test]
+    fn reports_errors() {
+        let cases: [(&[u8], ScpiError); 8] = [
+            (b"SOUR:PWM:DUTY 101", ScpiError::DataOutOfRange),
+            (b"SOUR:PWM:DUTY 300", ScpiError::DataOutOfRange),
+            (b"SOUR:PWM:DUTY fast", ScpiError::DataType),
+            (b"OUTP MAYBE", ScpiError::IllegalParameterValue),
+            (b"OUTP", ScpiError::MissingParameter),
+            (b"OUTP? 1", ScpiError::ParameterNotAllowed),
+            (b"SOUR:PWM:DUTYCYCLE 40", ScpiError::UndefinedHeader),
+            (b"*IDN", ScpiError::UndefinedHeader),
+        ];
+        for (line, error) in cases {
+            assert_eq!(Message::parse(line), Err(error));
+        }
+        assert_eq!(Message::parse(&[b'O', b'U', 0xff]), Err(ScpiError::Syntax));
+    }
+
+    #[test]
+    fn round_trips_every_message() {
+        let messages = [
+            Message::Apply(Command::SetGpioPin),
+            Message::Apply(Command::ClearGpioPin),
+            Message::Apply(Command::PwmOn),
+            Message::Apply(Command::PwmOff),
+            Message::Apply(Command::PwmDuty(100)),
+            Message::Apply(Command::PwmSetFreq(100_000)),
+            Message::Reset,
+            Message::Clear,
+            Message::Query(Query::Identify),
+            Message::Query(Query::Led),
+            Message::Query(Query::Output),
+            Message::Query(Query::Duty),
+            Message::Query(Query::Frequency),
+            Message::Query(Query::Error),
+            Message::Query(Query::Uptime),
+        ];
+        for message in messages {
+            let mut buf = [0u8; FRAME_SIZE];
+            let n = message.encode(&mut buf).unwrap();
+            assert!(is_scpi(&buf[..n]));
+            assert_eq!(Message::parse(&buf[..n]), Ok(message));
+        }
+        assert_eq!(
+            Message::Apply(Command::GetState).encode(&mut [0u8; FRAME_SIZE]),
+            Err(Error::InvalidArgument)
+        );
+    }
+
+    #[test]
+    fn answers_queries() {
+        let mut instrument = Instrument::new();
+        let mut buf = [0u8; FRAME_SIZE];
+        let idn = ask(&mut instrument, "*IDN?", &mut buf).unwrap();
+        assert_eq!(idn, "iced,NUCLEO-L476RG,0,0.1.0\n");
+        match Query::Identify.decode(idn.as_bytes()) {
+            Ok(Answer::Identity(i)) => {
+                assert_eq!(i.board, info().board);
+                assert_eq!(i.firmware_version, info().firmware_version);
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        let mut buf = [0u8; FRAME_SIZE];
+        assert_eq!(ask(&mut instrument, "SOUR:LED?", &mut buf), Some("1\n"));
+        let mut buf = [0u8; FRAME_SIZE];
+        assert_eq!(ask(&mut instrument, "OUTP?", &mut buf), Some("0\n"));
+        let mut buf = [0u8; FRAME_SIZE];
+        let freq = ask(&mut instrument, "SOUR:PWM:FREQ?", &mut buf).unwrap();
+        assert_eq!(
+            Query::Frequency.decode(freq.as_bytes()),
+            Ok(Answer::Number(20_000))
+        );
+        let mut buf = [0u8; FRAME_SIZE];
+        assert_eq!(ask(&mut instrument, "OUTP ON", &mut buf), None);
+    }
+
+    #[test]
+    fn queues_errors_in_order() {
+        let mut instrument = Instrument::new();
+        let mut buf = [0u8; FRAME_SIZE];
+        assert_eq!(ask(&mut instrument, "BOGUS", &mut buf), None);
+        assert_eq!(ask(&mut instrument, "SOUR:PWM:DUTY 101", &mut buf), None);
+        let mut buf = [0u8; FRAME_SIZE];
+        let first = ask(&mut instrument, "SYST:ERR?", &mut buf).unwrap();
+        assert_eq!(first, "-113,\"Undefined header\"\n");
+        assert_eq!(
+            Query::Error.decode(first.as_bytes()),
+            Ok(Answer::Error(Some(ScpiError::UndefinedHeader)))
+        );
+        let mut buf = [0u8; FRAME_SIZE];
+        let second = ask(&mut instrument, "SYST:ERR?", &mut buf).unwrap();
+        assert_eq!(
+            Query::Error.decode(second.as_bytes()),
+            Ok(Answer::Error(Some(ScpiError::DataOutOfRange)))
+        );
+        let mut buf = [0u8; FRAME_SIZE];
+        let none = ask(&mut instrument, "SYST:ERR?", &mut buf).unwrap();
+        assert_eq!(none, "0,\"No error\"\n");
+        assert_eq!(
+            Query::Error.decode(none.as_bytes()),
+            Ok(Answer::Error(None))
+        );
+    }
+
+    #[test]
+    fn overflows_and_clears_the_queue() {
+        let mut instrument = Instrument::new();
+        for _ in 0..QUEUE_SIZE + 3 {
+            instrument.push_error(ScpiError::UndefinedHeader);
+        }
+        for _ in 0..QUEUE_SIZE - 1 {
+            assert_eq!(instrument.next_error(), Some(ScpiError::UndefinedHeader));
+        }
+        assert_eq!(instrument.next_error(), Some(ScpiError::QueueOverflow));
+        assert_eq!(instrument.next_error(), None);
+
+        instrument.push_error(ScpiError::Syntax);
+        let mut buf = [0u8; FRAME_SIZE];
+        assert_eq!(ask(&mut instrument, "*CLS", &mut buf), None);
+        assert_eq!(instrument.next_error(), None);
+    }
+
+    #[test]
+    fn resets_to_the_boot_settings() {
+        let message = Message::parse(b"*RST\n").unwrap();
+        assert_eq!(message.commands(), &RESET);
+        assert!(message.commands().iter().all(Command::in_range));
+    }
+}
@@ -0,0 +1,112 @@
+//! Optional sequence numbers in front of a frame, `17:D42\n`.
+//!
+//! The host numbers its commands and the device echoes the number on the
+//! reply, so a reply can be matched to its command even after a stale or
+//! unsolicited frame. Frames without a number are still accepted, commands
+//! always start with a letter so the two can't be confused.
+
+use crate::{trim_frame, Command, Error, Response, SliceWriter};
+use core::fmt::Write;
+
+/// Sequence number of a command and of the reply to it.
+pub type Seq = u16;
+
+/// Separates the sequence number from the rest of the frame.
+pub const SEQ_SEPARATOR: u8 = b':';
+
+/// Splits a frame into its sequence number, if any, and the remaining frame.
+pub fn split_seq(frame: &[u8]) -> Result<(Option<Seq>, &[u8]), Error> {
+    match frame.first() {
+        Some(b) if b.is_ascii_digit() => {
+            let sep = frame
+                .iter()
+                .position(|b| *b == SEQ_SEPARATOR)
+                .ok_or(Error::InvalidSequence)?;
+            let seq = core::str::from_utf8(&frame[..sep])
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .ok_or(Error::InvalidSequence)?;
+            Ok((Some(seq), &frame[sep + 1..]))
+        }
+        _ => Ok((None, frame)),
+    }
+}
+
+/// Writes the `seq:` prefix, if any, then lets `body` encode the rest of the frame.
+fn write_seq(
+    seq: Option<Seq>,
+    buf: &mut [u8],
+    body: impl FnOnce(&mut [u8]) -> Result<usize, Error>,
+) -> Result<usize, Error> {
+    let n = match seq {
+        Some(seq) => {
+            let mut w = SliceWriter::new(buf);
+            write!(w, "{}:", seq).map_err(|_| Error::BufferTooSmall)?;
+            w.written()
+        }
+        None => 0,
+    };
+    Ok(n + body(&mut buf[n..])?)
+}
+
+impl Command {
+    /// Like [`Command::encode`], with `seq` in front.
+    pub fn encode_seq(&self, seq: Option<Seq>, buf: &mut [u8]) -> Result<usize, Error> {
+        write_seq(seq, buf, |buf| self.encode(buf))
+    }
+
+    /// Like [`Command::decode`], also returning the frame's sequence number.
+    pub fn decode_seq(frame: &[u8]) -> Result<(Option<Seq>, Self), Error> {
+        let (seq, rest) = split_seq(trim_frame(frame))?;
+        Ok((seq, Command::decode(rest)?))
+    }
+}
+
+impl Response {
+    /// Like [`Response::encode`], with `seq` in front.
+    pub fn encode_seq(&self, seq: Option<Seq>, buf: &mut [u8]) -> Result<usize, Error> {
+        write_seq(seq, buf, |buf| self.encode(buf))
+    }
+
+    /// Like [`Response::decode`], also returning the frame's sequence number.
+    pub fn decode_seq(frame: &[u8]) -> Result<(Option<Seq>, Self), Error> {
+        let (seq, rest) = split_seq(trim_frame(frame))?;
+        Ok((seq, Response::decode(rest)?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ErrorCode, FRAME_SIZE};
+
+    #[test]
+    fn round_trip() {
+        let mut buf = [0u8; FRAME_SIZE];
+        for seq in [None, Some(0), Some(Seq::MAX)] {
+            let n = Command::PwmDuty(42).encode_seq(seq, &mut buf).unwrap();
+            assert_eq!(
+                Command::decode_seq(&buf[..n]),
+                Ok((seq, Command::PwmDuty(42)))
+            );
+            let n = Response::Time(7).encode_seq(seq, &mut buf).unwrap();
+            assert_eq!(
+                Response::decode_seq(&buf[..n]),
+                Ok((seq, Response::Time(7)))
+            );
+        }
+    }
+
+    #[test]
+    fn parses_prefix() {
+        let mut buf = [0u8; FRAME_SIZE];
+        let n = Response::Error(ErrorCode::Malformed)
+            .encode_seq(Some(17), &mut buf)
+            .unwrap();
+        assert_eq!(&buf[..n], b"17:X1\n");
+        assert_eq!(split_seq(b"P"), Ok((None, &b"P"[..])));
+        assert_eq!(split_seq(b"17"), Err(Error::InvalidSequence));
+        assert_eq!(split_seq(b"70000:P"), Err(Error::InvalidSequence));
+        assert_eq!(Command::decode_seq(b"3:Z\n"), Err(Error::UnknownTag(b'Z')));
+    }
+}
@@ -0,0 +1,476 @@
+use iced_protocol::modbus::{self, Transaction};
+use iced_protocol::scpi::{self, Instrument};
+use iced_protocol::{
+    sequence::split_seq, Capabilities, Command, DeviceInfo, DeviceState, Error as ProtocolError,
+    ErrorCode, Event, Framing, Label, Response, FRAME_SIZE, PROTOCOL_VERSION,
+};
+use std::io;
+use std::time::Duration;
+use tokio::io::{
+    AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, DuplexStream,
+};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant};
+
+/// Mirror of the firmware `AppState`, with the duty cycle kept in percent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SimState {
+    pub led_state: bool,
+    pub pwm_state: bool,
+    pub pwm_duty_cycle: u16,
+    pub pwm_frequency: u32,
+    /// Milliseconds between telemetry events, 0 when they're off.
+    pub telemetry_period: u16,
+}
+
+impl SimState {
+    /// The state the firmware is in right after boot.
+    pub fn new() -> Self {
+        Self {
+            led_state: false,
+            pwm_state: true,
+            pwm_duty_cycle: 25,
+            pwm_frequency: 1000,
+            telemetry_period: 0,
+        }
+    }
+}
+
+impl Default for SimState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct Simulator {
+    state: SimState,
+    framing: Framing,
+    started: Instant,
+    last_telemetry: Instant,
+    events: Option<mpsc::UnboundedReceiver<Event>>,
+    /// Slave address when speaking Modbus RTU instead of the line protocol.
+    modbus: Option<u8>,
+    /// Error queue of the SCPI lines received so far.
+    instrument: Instrument,
+}
+
+/// Silence after which a partial Modbus frame is dropped. The standard's 3.5
+/// characters are far shorter than a pty or pipe's scheduling jitter.
+const MODBUS_GAP: Duration = Duration::from_millis(50);
+
+impl Simulator {
+    pub fn new() -> Self {
+        Self {
+            state: SimState::new(),
+            framing: Framing::Line,
+            started: Instant::now(),
+            last_telemetry: Instant::now(),
+            events: None,
+            modbus: None,
+            instrument: Instrument::new(),
+        }
+    }
+
+    /// Speaks Modbus RTU as slave `unit` instead of the line protocol, like
+    /// firmware built with the `modbus` feature.
+    pub fn with_modbus(mut self, unit: u8) -> Self {
+        self.modbus = Some(unit);
+        self
+    }
+
+    pub fn state(&self) -> &SimState {
+        &self.state
+    }
+
+    pub fn framing(&self) -> Framing {
+        self.framing
+    }
+
+    /// Returns a sender for events to push to the host while running, such as
+    /// the button presses the board would report.
+    pub fn event_sender(&mut self) -> mpsc::UnboundedSender<Event> {
+        let (tx, rx) = mpsc::unbounded_channel();
+        self.events = Some(rx);
+        tx
+    }
+
+    /// Milliseconds since the simulator was created, wrapping like the SysTick counter.
+    pub fn millis(&self) -> u32 {
+        self.started.elapsed().as_millis() as u32
+    }
+
+    /// The report the firmware sends in reply to `GetState`.
+    pub fn device_state(&self) -> DeviceState {
+        DeviceState {
+            led_on: self.state.led_state,
+            pwm_enabled: self.state.pwm_state,
+            pwm_duty: self.state.pwm_duty_cycle as u8,
+            pwm_frequency: self.state.pwm_frequency,
+            uptime_ms: self.millis(),
+        }
+    }
+
+    /// The identity report, the simulator supports every command.
+    pub fn device_info(&self) -> DeviceInfo {
+        DeviceInfo {
+            protocol_version: PROTOCOL_VERSION,
+            firmware_version: Label::new(env!("CARGO_PKG_VERSION")).unwrap(),
+            board: Label::new("iced-sim").unwrap(),
+            capabilities: Capabilities::all(),
+        }
+    }
+
+    /// Applies an in-range `command` to the state, the way the firmware main loop does.
+    pub fn apply(&mut self, command: Command) {
+        match command {
+            Command::PwmOn => self.state.pwm_state = true,
+            Command::PwmOff => self.state.pwm_state = false,
+            Command::PwmDuty(duty) => self.state.pwm_duty_cycle = u16::from(duty),
+            Command::PwmSetFreq(hz) => self.state.pwm_frequency = hz,
+            Command::SetGpioPin => self.state.led_state = true,
+            Command::ClearGpioPin => self.state.led_state = false,
+            Command::SetTelemetry(ms) => {
+                self.state.telemetry_period = ms;
+                self.last_telemetry = Instant::now();
+            }
+            // Framing changes take effect after the reply, see `handle_frame`.
+            Command::GetTime | Command::GetState | Command::SetFraming(_) | Command::Identify => (),
+        }
+    }
+
+    /// Answers a single command frame, without framing or sequence number.
+    fn respond(&mut self, message: &[u8]) -> Response {
+        match Command::decode(message) {
+            Ok(command) if !command.in_range() => Response::Error(ErrorCode::OutOfRange),
+            Ok(Command::GetTime) => Response::Time(self.millis()),
+            Ok(Command::GetState) => Response::State(self.device_state()),
+            Ok(Command::Identify) => Response::Identity(self.device_info()),
+            Ok(command) => {
+                self.apply(command);
+                Response::Ack(command)
+            }
+            Err(_) => Response::Error(ErrorCode::Malformed),
+        }
+    }
+
+    /// Handles one frame, delimiter included, and returns the bytes the firmware would send back.
+    pub fn handle_frame(&mut self, frame: &[u8]) -> Vec<u8> {
+        if self.framing == Framing::Line && scpi::is_scpi(frame) {
+            return self.handle_scpi(frame);
+        }
+        let mut message = [0u8; FRAME_SIZE];
+        let (seq, response) = match self.framing.unwrap(frame, &mut message) {
+            _ if frame.len() > FRAME_SIZE => (None, Response::Error(ErrorCode::Malformed)),
+            Err(ProtocolError::Checksum) => (None, Response::Error(ErrorCode::Checksum)),
+            Err(_) => (None, Response::Error(ErrorCode::Malformed)),
+            Ok(message) => match split_seq(message) {
+                Ok((seq, message)) => (seq, self.respond(message)),
+                Err(_) => (None, Response::Error(ErrorCode::Malformed)),
+            },
+        };
+        let mut line = [0u8; FRAME_SIZE];
+        let reply = response
+            .encode_seq(seq, &mut line)
+            .map(|n| self.wrap(&line[..n]))
+            .unwrap_or_default();
+        if let Response::Ack(Command::SetFraming(framing)) = response {
+            self.framing = framing;
+        }
+        reply
+    }
+
+    /// Handles one SCPI line and returns the answer, empty for anything but a query.
+    pub fn handle_scpi(&mut self, line: &[u8]) -> Vec<u8> {
+        let Some(message) = self.instrument.parse(line) else {
+            return Vec::new();
+        };
+        for command in message.commands() {
+            self.apply(*command);
+        }
+        let mut out = [0u8; FRAME_SIZE];
+        let (state, info) = (self.device_state(), self.device_info());
+        match self.instrument.answer(&message, &state, &info, &mut out) {
+            Some(n) => out[..n].to_vec(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Handles one Modbus request frame and returns the reply, empty when a
+    /// slave `unit` stays silent.
+    pub fn handle_modbus(&mut self, unit: u8, adu: &[u8]) -> Vec<u8> {
+        let Some(transaction) = Transaction::parse(adu, unit, &self.device_state()) else {
+            return Vec::new();
+        };
+        for command in transaction.commands() {
+            self.apply(command);
+        }
+        let mut out = [0u8; modbus::MAX_ADU];
+        match transaction.reply(&self.device_state(), &self.device_info(), &mut out) {
+            Some(n) => out[..n].to_vec(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Returns the bytes the firmware sends for `event`.
+    pub fn event_frame(&self, event: &Event) -> Vec<u8> {
+        let mut line = [0u8; FRAME_SIZE];
+        event
+            .encode(&mut line)
+            .map(|n| self.wrap(&line[..n]))
+            .unwrap_or_default()
+    }
+
+    /// Puts a line frame into the current framing.
+    fn wrap(&self, line: &[u8]) -> Vec<u8> {
+        let mut out = [0u8; FRAME_SIZE];
+        let n = self.framing.wrap(line, &mut out).unwrap_or(0);
+        out[..n].to_vec()
+    }
+
+    /// When the next telemetry event is due, if they're on.
+    fn next_telemetry(&self) -> Option<Instant> {
+        match self.state.telemetry_period {
+            0 => None,
+            ms => Some(self.last_telemetry + Duration::from_millis(ms.into())),
+        }
+    }
+
+    /// Serves the protocol on `io` until the other side closes it.
+    pub async fn run<T>(&mut self, io: T) -> io::Result<()>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        if let Some(unit) = self.modbus {
+            return self.run_modbus(unit, io).await;
+        }
+        let mut io = BufReader::new(io);
+        let mut frame = Vec::with_capacity(FRAME_SIZE);
+        loop {
+            let delimiter = self.framing.delimiter();
+            let telemetry = self.next_telemetry();
+            let out = tokio::select! {
+                // Partial reads stay in `frame` if another branch wins.
+                n = io.read_until(delimiter, &mut frame) => {
+                    if n? == 0 || frame.last() != Some(&delimiter) {
+                        return Ok(());
+                    }
+                    let reply = self.handle_frame(&frame);
+                    frame.clear();
+                    reply
+                }
+                Some(event) = next_event(&mut self.events) => self.event_frame(&event),
+                _ = time::sleep_until(telemetry.unwrap_or_else(Instant::now)), if telemetry.is_some() => {
+                    self.last_telemetry = Instant::now();
+                    self.event_frame(&Event::Telemetry(self.device_state()))
+                }
+            };
+            io.write_all(&out).await?;
+            io.flush().await?;
+        }
+    }
+
+    /// Serves Modbus RTU, a slave sends nothing on its own so there are no events.
+    async fn run_modbus<T>(&mut self, unit: u8, mut io: T) -> io::Result<()>
+    where
+        T: AsyncRead + AsyncWrite + Unpin,
+    {
+        let mut pending = Vec::with_capacity(modbus::MAX_ADU);
+        let mut buf = [0u8; modbus::MAX_ADU];
+        loop {
+            let n = match pending.is_empty() {
+                true => io.read(&mut buf).await?,
+                false => match time::timeout(MODBUS_GAP, io.read(&mut buf)).await {
+                    Ok(n) => n?,
+                    Err(_) => {
+                        pending.clear();
+                        continue;
+                    }
+                },
+            };
+            if n == 0 {
+                return Ok(());
+            }
+            pending.extend_from_slice(&buf[..n]);
+            while let Some(len) = modbus::request_len(&pending) {
+                if len > pending.len() {
+                    break;
+                }
+                let frame: Vec<u8> = pending.drain(..len).collect();
+                let reply = self.handle_modbus(unit, &frame);
+                io.write_all(&reply).await?;
+                io.flush().await?;
+            }
+            // Too long to be a frame, drop it like the gap would.
+            if pending.len() > modbus::MAX_ADU {
+                pending.clear();
+            }
+        }
+    }
+}
+
+/// The next pushed event, never resolves without an event sender.
+async fn next_event(events: &mut Option<mpsc::UnboundedReceiver<Event>>) -> Option<Event> {
+    match events {
+        Some(rx) => rx.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+impl Default for Simulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Spawns a simulator on one end of an in-memory pipe and returns the other end.
+///
+/// The join handle resolves to the simulator once the returned stream is dropped,
+/// so its final state can be inspected.
+pub fn spawn(max_buf_size: usize) -> (DuplexStream, JoinHandle<io::Result<Simulator>>) {
+    let (client, device) = tokio::io::duplex(max_buf_size);
+    let handle = tokio::spawn(async move {
+        let mut sim = Simulator::new();
+        sim.run(device).await?;
+        Ok(sim)
+    });
+    (client, handle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iced_protocol::framing::{decode_binary, encode_binary};
+    use iced_protocol::modbus::{Exception, Reply, Request};
+
+    fn binary(message: &[u8]) -> Vec<u8> {
+        let mut out = [0u8; FRAME_SIZE];
+        let n = encode_binary(message, &mut out).unwrap();
+        out[..n].to_vec()
+    }
+
+    fn unbinary(frame: &[u8]) -> Vec<u8> {
+        let mut out = [0u8; FRAME_SIZE];
+        let n = decode_binary(frame, &mut out).unwrap();
+        out[..n].to_vec()
+    }
+
+    /// Sends `request` to slave 1 and decodes the answer.
+    fn modbus(sim: &mut Simulator, request: Request) -> Reply {
+        let mut adu = [0u8; modbus::MAX_ADU];
+        let n = request.encode(1, &mut adu).unwrap();
+        let reply = sim.handle_modbus(1, &adu[..n]);
+        Reply::decode(&reply, &request).unwrap().1
+    }
+
+    #[test]
+    fn answers_line_frames() {
+        let mut sim = Simulator::new();
+        assert_eq!(sim.handle_frame(b"D40\n"), b"D40\n");
+        assert_eq!(sim.handle_frame(b"P\n"), b"P\n");
+        assert_eq!(sim.state().pwm_duty_cycle, 40);
+        assert!(sim.state().led_state);
+        assert!(sim.handle_frame(b"S\n").starts_with(b"S1,1,40,1000,"));
+        assert!(sim.handle_frame(b"I\n").starts_with(b"I"));
+        assert_eq!(sim.handle_frame(b"Q\n"), b"X1\n");
+        assert_eq!(sim.handle_frame(b"E1\n"), b"X1\n");
+    }
+
+    #[test]
+    fn echoes_the_sequence_number() {
+        let mut sim = Simulator::new();
+        assert_eq!(sim.handle_frame(b"7:O\n"), b"7:O\n");
+        assert_eq!(sim.handle_frame(b"12:D101\n"), b"12:X2\n");
+        assert!(sim.handle_frame(b"3:T\n").starts_with(b"3:T"));
+        assert!(sim.handle_frame(b"T\n").starts_with(b"T"));
+        assert!(!sim.state().pwm_state);
+    }
+
+    #[test]
+    fn refuses_out_of_range_arguments() {
+        let mut sim = Simulator::new();
+        assert_eq!(sim.handle_frame(b"D101\n"), b"X2\n");
+        assert_eq!(sim.handle_frame(b"F0\n"), b"X2\n");
+        // Too big for the argument's type at all.
+        assert_eq!(sim.handle_frame(b"D300\n"), b"X1\n");
+        assert_eq!(sim.state(), &SimState::new());
+    }
+
+    #[test]
+    fn switches_to_binary_framing() {
+        let mut sim = Simulator::new();
+        // The ack still goes out in the old framing.
+        assert_eq!(sim.handle_frame(b"1:B1\n"), b"1:B1\n");
+        assert_eq!(sim.framing(), Framing::Binary);
+
+        let reply = sim.handle_frame(&binary(b"2:D40"));
+        assert_eq!(reply.last(), Some(&0));
+        assert_eq!(unbinary(&reply), b"2:D40");
+        assert_eq!(sim.state().pwm_duty_cycle, 40);
+
+        let mut corrupt = binary(b"3:T");
+        corrupt[1] ^= 0x20;
+        assert_eq!(unbinary(&sim.handle_frame(&corrupt)), b"X3");
+        // SCPI is only understood on lines.
+        assert_eq!(unbinary(&sim.handle_frame(&binary(b"*IDN?"))), b"X1");
+
+        assert_eq!(unbinary(&sim.handle_frame(&binary(b"B0"))), b"B0");
+        assert_eq!(sim.framing(), Framing::Line);
+        assert_eq!(sim.handle_frame(b"E\n"), b"E\n");
+    }
+
+    #[test]
+    fn speaks_scpi() {
+        let mut sim = Simulator::new();
+        assert!(sim
+            .handle_frame(b"*IDN?\n")
+            .starts_with(b"iced,iced-sim,0,"));
+        assert_eq!(sim.handle_frame(b"SOUR:PWM:DUTY 40\n"), b"");
+        assert_eq!(sim.handle_frame(b"sour:pwm:duty?\n"), b"40\n");
+        assert_eq!(sim.handle_frame(b"SOURce:LED ON\n"), b"");
+        assert!(sim.state().led_state);
+
+        assert_eq!(sim.handle_frame(b"SOUR:PWM:DUTY 101\n"), b"");
+        assert_eq!(sim.state().pwm_duty_cycle, 40);
+        assert!(sim.handle_frame(b"SYST:ERR?\n").starts_with(b"-222,"));
+        assert_eq!(sim.handle_frame(b"SYST:ERR?\n"), b"0,\"No error\"\n");
+    }
+
+    #[test]
+    fn speaks_modbus() {
+        let mut sim = Simulator::new().with_modbus(1);
+        let write = Request::WriteRegister {
+            address: modbus::HOLDING_DUTY,
+            value: 40,
+        };
+        assert_eq!(modbus(&mut sim, write), Reply::Written);
+        let coil = Request::WriteCoil {
+            address: modbus::COIL_LED,
+            on: true,
+        };
+        assert_eq!(modbus(&mut sim, coil), Reply::Written);
+        assert_eq!(sim.state().pwm_duty_cycle, 40);
+        assert!(sim.state().led_state);
+
+        let read = Request::ReadCoils {
+            start: 0,
+            count: modbus::COILS,
+        };
+        assert_eq!(modbus(&mut sim, read), Reply::Coils(0b11));
+
+        let too_high = Request::WriteRegister {
+            address: modbus::HOLDING_DUTY,
+            value: 101,
+        };
+        assert_eq!(
+            modbus(&mut sim, too_high),
+            Reply::Exception(Exception::IllegalDataValue)
+        );
+        assert_eq!(sim.state().pwm_duty_cycle, 40);
+
+        // Other slaves' requests go unanswered.
+        let mut adu = [0u8; modbus::MAX_ADU];
+        let n = read.encode(2, &mut adu).unwrap();
+        assert!(sim.handle_modbus(1, &adu[..n]).is_empty());
+    }
+}
@@ -0,0 +1,34 @@
+use iced_protocol::{modbus, Event};
+use iced_sim::Simulator;
+use std::io;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio_serial::{SerialPort, SerialStream};
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    let (master, slave) = SerialStream::pair()?;
+    // The slave end stays open for the lifetime of the simulator so the pty
+    // survives clients opening and closing it.
+    let name = slave.name().unwrap_or_default();
+    println!("Simulated device listening on {}", name);
+    // `--modbus` makes it a Modbus RTU slave, like firmware built with the feature.
+    if std::env::args().any(|a| a == "--modbus") {
+        println!("Speaking Modbus RTU as unit {}", modbus::DEFAULT_UNIT);
+        return Simulator::new()
+            .with_modbus(modbus::DEFAULT_UNIT)
+            .run(master)
+            .await;
+    }
+    println!("Press Enter to press the user button");
+    let mut sim = Simulator::new();
+    let events = sim.event_sender();
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(tokio::io::stdin()).lines();
+        while let Ok(Some(_)) = lines.next_line().await {
+            if events.send(Event::Button).is_err() {
+                break;
+            }
+        }
+    });
+    sim.run(master).await
+}